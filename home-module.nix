@@ -35,6 +35,8 @@ in
       };
       Service = {
         ExecStart = "${lib.getExe cfg.package}";
+        # SIGHUP reloads the configuration
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
         Restart = "always";
         RestartSec = 5;
       };
@@ -1,9 +1,11 @@
 use std::time::Instant;
 
 use enigo::agent;
+use indexmap::IndexMap;
 
 use serde::Serialize;
 use serde::Deserialize;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
 
 use crate::events;
 
@@ -12,8 +14,9 @@ pub type WheelId = String;
 pub type ButtonSetId = String;
 pub type ProfileId = String;
 pub type MacroId = String;
+pub type VariableId = String;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum WhichButton {
     ThisButton,
     Button0,
@@ -38,7 +41,7 @@ pub enum ChangeRef {
     This,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum GoTo {
     Swap,
     Switch(ChangeRef, ChangeRef, ChangeRef),
@@ -65,18 +68,209 @@ pub enum NonEnigoAction {
     ChangeWheel(ChangeRef),
     ChangeButtonSet(ChangeRef),
     Swap,
+    SetVariable(VariableId, toml::Value),
+    /// Add to a number, an unset variable counting as 0
+    IncrementVariable(VariableId, i64),
+    /// Flip a boolean, an unset variable counting as false
+    ToggleVariable(VariableId),
 
     // QKeyPie config
-    Macro(MacroId),
+    Macro(MacroCall),
+
+    // Control flow
+    If(Conditional),
+}
+
+/// `{ If = { when = { Profile = "shell" }, then = [...], else = [...] } }`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Conditional {
+    pub when: Condition,
+    pub then: Vec<Action>,
+    #[serde(default, rename = "else")]
+    pub otherwise: Vec<Action>,
 }
 
+/// What an `If` asks, looked at when the action list gets to it.
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Condition {
+    Profile(ProfileId),
+    ButtonSet(ButtonSetId),
+    Wheel(WheelId),
+    /// The command exits with status 0 within a second.  Its action list
+    /// waits for it, the rest of the device does not.
+    Command(Vec<String>),
+    /// The environment variable is set and not empty
+    Env(String),
+    EnvEquals(String, String),
+    /// The variable is true, a number other than 0 or a string that is not empty
+    Variable(VariableId),
+    VariableEquals(VariableId, toml::Value),
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+/// What to do when a callback fires again before its previous run is over.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum Concurrency {
+    /// Run after the previous run
+    #[default]
+    Queue,
+    /// Stop the previous run and start over
+    Cancel,
+    /// Let the previous run finish and skip this one
+    Ignore,
+}
+
+/// The actions run for one event.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Callback {
+    pub concurrency: Concurrency,
+    pub actions: Vec<Action>,
+}
+
+/// `{ Macro = "name" }`, or `{ Macro = { name = "name", args = { ... } } }` for macros with parameters.
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum MacroCall {
+    Name(MacroId),
+    WithArgs {
+        name: MacroId,
+        #[serde(default)]
+        args: IndexMap<String, toml::Value>,
+    },
+}
+
+// By hand rather than untagged, so the errors of the table form come through
+impl<'de> Deserialize<'de> for MacroCall {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct WithArgs {
+            name: MacroId,
+            #[serde(default)]
+            args: IndexMap<String, toml::Value>,
+        }
+
+        struct MacroCallVisitor;
+
+        impl<'de> Visitor<'de> for MacroCallVisitor {
+            type Value = MacroCall;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a macro name, or a table with its name and args")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<MacroCall, E> {
+                Ok(MacroCall::Name(name.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<MacroCall, A::Error> {
+                let WithArgs { name, args } = WithArgs::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(MacroCall::WithArgs { name, args })
+            }
+        }
+
+        deserializer.deserialize_any(MacroCallVisitor)
+    }
+}
+
+impl MacroCall {
+    pub fn name(&self) -> &MacroId {
+        match self {
+            MacroCall::Name(name) => name,
+            MacroCall::WithArgs { name, .. } => name,
+        }
+    }
+}
+
+/// A `[macros]` entry.  The actions of a macro with parameters are kept as
+/// TOML until it is called, since `${param}` may stand where the actions
+/// expect a key or a number.
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Macro {
+    Actions(Vec<Action>),
+    Template {
+        params: Vec<String>,
+        actions: Vec<toml::Value>,
+    },
+}
+
+// A list is a plain macro and a table one with `params`, each deserialized as
+// such so its errors are not lost to an untagged enum
+impl<'de> Deserialize<'de> for Macro {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Template {
+            params: Vec<String>,
+            actions: Vec<toml::Value>,
+        }
+
+        struct MacroVisitor;
+
+        impl<'de> Visitor<'de> for MacroVisitor {
+            type Value = Macro;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of actions, or a table with params and actions")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Macro, A::Error> {
+                Ok(Macro::Actions(Vec::deserialize(de::value::SeqAccessDeserializer::new(seq))?))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Macro, A::Error> {
+                let Template { params, actions } = Template::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(Macro::Template { params, actions })
+            }
+        }
+
+        deserializer.deserialize_any(MacroVisitor)
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
 #[serde(untagged)]
 pub enum Action {
     Input(agent::Token),
     NonEnigo(NonEnigoAction),
 }
 
+/// The variants of `agent::Token`, which come from enigo
+const INPUT_ACTIONS: &[&str] = &["Text", "Key", "Raw", "Button", "MoveMouse", "Scroll"];
+
+/// The variants of `NonEnigoAction`
+const NON_ENIGO_ACTIONS: &[&str] = &[
+    "Debug", "Run", "Sleep",
+    "SetScreenOrientation", "SetScreenBrightness", "SetWheelSpeed", "SetSleepTimeout", "SetButtonText", "SetWheelColor", "ShowBanner",
+    "ChangeProfile", "ChangeWheel", "ChangeButtonSet", "Swap", "SetVariable", "IncrementVariable", "ToggleVariable",
+    "Macro",
+    "If",
+];
+
+// Picked by the name of the action, so a mistake inside one is reported
+// instead of neither kind matching
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = toml::Value::deserialize(deserializer)?;
+        let name = match &value {
+            toml::Value::String(name) => name.clone(),
+            toml::Value::Table(table) if table.len() == 1 => table.keys().next().cloned().unwrap_or_default(),
+            _ => return Err(de::Error::custom("an action is a table with one key, like { Debug = \"...\" }")),
+        };
+        if NON_ENIGO_ACTIONS.contains(&name.as_str()) {
+            value.try_into::<NonEnigoAction>().map(Action::NonEnigo).map_err(|err| de::Error::custom(err.message()))
+        } else if INPUT_ACTIONS.contains(&name.as_str()) {
+            value.try_into::<agent::Token>().map(Action::Input).map_err(|err| de::Error::custom(err.message()))
+        } else {
+            Err(de::Error::custom(format!("unknown action `{}`", name)))
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(deny_unknown_fields)]
 pub struct ButtonCallback<T> {
@@ -95,12 +289,43 @@ pub struct ButtonCallback<T> {
     pub on_triple_click: T,
     pub on_triple_click_release: T,
 
+    /// `on_click_n = { 4 = [...], 5 = [...] }` for more clicks than three,
+    /// by the click count as TOML keys are strings
+    #[serde(default = "IndexMap::new")]
+    pub on_click_n: IndexMap<String, T>,
+
     pub on_long_press: T,
+    /// Again and again while the button is held, see `events::Timings`
+    pub on_hold_repeat: T,
+
+    pub on_toggle_on: T,
+    pub on_toggle_off: T,
+    pub toggle: Option<Toggle>,
+
+    /// For a wheel, these are the timings of both the wheel and its button
+    #[serde(default)]
+    pub timings: events::Timings,
 
     #[serde(flatten)]
     pub active: ActiveCallback<T>,
 }
 
+/// `toggle = { on = "Unmute", off = "Mute" }` makes every press of a button
+/// flip it on or off, running `on_toggle_on` or `on_toggle_off` and showing
+/// the label for the new state.  Giving either callback is enough to make a
+/// toggle.  Whether it is on is kept in a variable, so it outlives buttonset
+/// switches.  Unless `variable` names one it is named after where the button
+/// is bound, like `toggle.buttonset.BUTTONSET.button0`,
+/// `toggle.buttonset.BUTTONSET.shift.button0`, `toggle.profile.PROFILE.button`
+/// or `toggle.profile.PROFILE.wheel.WHEEL`, so every place has its own.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Toggle {
+    pub variable: Option<VariableId>,
+    pub on: Option<String>,
+    pub off: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WheelCallback<T> {
     pub on_clockwise: T,
@@ -140,13 +365,35 @@ pub struct ButtonSet<T> {
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct ButtonSetCallback<T1, T2> {
+pub struct ButtonSetCallback<T1, T2, T3> {
     #[serde(flatten)]
     pub buttonset: ButtonSet<T1>,
+    /// See `chords`
+    #[serde(default = "IndexMap::new")]
+    pub chords: IndexMap<String, T2>,
+    pub shift: Option<T3>,
     #[serde(flatten)]
     pub active: ActiveCallback<T2>,
 }
 
+/// A layer over a buttonset while the `hold` button is held: the buttons it
+/// has and its wheel stand in for the ones of the buttonset, showing their
+/// labels until `hold` is let go.
+///
+/// ```toml
+/// [buttonsets.editing]
+/// button0 = "copy"
+/// button7 = "shift"
+/// shift = { hold = "Button7", button0 = "paste", wheel = "zoom" }
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Shift<T1, T2> {
+    pub hold: WhichButton,
+    #[serde(flatten)]
+    pub buttonset: ButtonSet<T1>,
+    pub wheel: T2,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(deny_unknown_fields)]
 pub struct WheelSet<T1, T2> {
@@ -160,6 +407,18 @@ pub struct ProfileButton<T> {
     pub button: T,
 }
 
+impl<T> From<ButtonSet<T>> for [T; 8] {
+    fn from(b: ButtonSet<T>) -> Self {
+        [b.button0, b.button1, b.button2, b.button3, b.button4, b.button5, b.button6, b.button7]
+    }
+}
+
+impl<T> From<[T; 8]> for ButtonSet<T> {
+    fn from([button0, button1, button2, button3, button4, button5, button6, button7]: [T; 8]) -> Self {
+        ButtonSet { button0, button1, button2, button3, button4, button5, button6, button7 }
+    }
+}
+
 impl From<xencelabs_quick_keys::ButtonState> for ButtonSet<events::ButtonState> {
     fn from(b: xencelabs_quick_keys::ButtonState) -> Self {
         ButtonSet {
@@ -216,15 +475,15 @@ impl From<xencelabs_quick_keys::Event> for WheelSet<events::WheelState, events::
 }
 
 impl ButtonSet<events::ButtonStateMachine> {
-    pub fn transition(&self, event: ButtonSet<events::ButtonState>, when: Instant) -> (Self, ButtonSet<Vec<events::ButtonEvent>>) {
-        let (button0_new_state, button0_events) = self.button0.transition(event.button0, when);
-        let (button1_new_state, button1_events) = self.button1.transition(event.button1, when);
-        let (button2_new_state, button2_events) = self.button2.transition(event.button2, when);
-        let (button3_new_state, button3_events) = self.button3.transition(event.button3, when);
-        let (button4_new_state, button4_events) = self.button4.transition(event.button4, when);
-        let (button5_new_state, button5_events) = self.button5.transition(event.button5, when);
-        let (button6_new_state, button6_events) = self.button6.transition(event.button6, when);
-        let (button7_new_state, button7_events) = self.button7.transition(event.button7, when);
+    pub fn transition(&self, event: ButtonSet<events::ButtonState>, when: Instant, timings: &ButtonSet<events::Timings>) -> (Self, ButtonSet<Vec<events::ButtonEvent>>) {
+        let (button0_new_state, button0_events) = self.button0.transition(event.button0, when, &timings.button0);
+        let (button1_new_state, button1_events) = self.button1.transition(event.button1, when, &timings.button1);
+        let (button2_new_state, button2_events) = self.button2.transition(event.button2, when, &timings.button2);
+        let (button3_new_state, button3_events) = self.button3.transition(event.button3, when, &timings.button3);
+        let (button4_new_state, button4_events) = self.button4.transition(event.button4, when, &timings.button4);
+        let (button5_new_state, button5_events) = self.button5.transition(event.button5, when, &timings.button5);
+        let (button6_new_state, button6_events) = self.button6.transition(event.button6, when, &timings.button6);
+        let (button7_new_state, button7_events) = self.button7.transition(event.button7, when, &timings.button7);
         (ButtonSet {
             button0: button0_new_state,
             button1: button1_new_state,
@@ -247,10 +506,32 @@ impl ButtonSet<events::ButtonStateMachine> {
     }
 }
 
+impl ButtonSet<events::ButtonStateMachine> {
+    /// When the first of the buttons has something happen without moving.
+    pub fn deadline(&self, timings: &ButtonSet<events::Timings>) -> Option<Instant> {
+        [
+            self.button0.deadline(&timings.button0),
+            self.button1.deadline(&timings.button1),
+            self.button2.deadline(&timings.button2),
+            self.button3.deadline(&timings.button3),
+            self.button4.deadline(&timings.button4),
+            self.button5.deadline(&timings.button5),
+            self.button6.deadline(&timings.button6),
+            self.button7.deadline(&timings.button7),
+        ].into_iter().flatten().min()
+    }
+}
+
+impl WheelSet<events::WheelStateMachine, events::ButtonStateMachine> {
+    pub fn deadline(&self, timings: &events::Timings) -> Option<Instant> {
+        self.wheel.deadline(timings).into_iter().chain(self.wheel_button.deadline(timings)).min()
+    }
+}
+
 impl WheelSet<events::WheelStateMachine, events::ButtonStateMachine> {
-    pub fn transition(&self, event: WheelSet<events::WheelState, events::ButtonState>, when: Instant) -> (Self, WheelSet<Vec<events::WheelEvent>, Vec<events::ButtonEvent>>) {
-        let (wheel_new_state, wheel_events) = self.wheel.transition(event.wheel, when);
-        let (wheel_button_new_state, wheel_button_events) = self.wheel_button.transition(event.wheel_button, when);
+    pub fn transition(&self, event: WheelSet<events::WheelState, events::ButtonState>, when: Instant, timings: &events::Timings) -> (Self, WheelSet<Vec<events::WheelEvent>, Vec<events::ButtonEvent>>) {
+        let (wheel_new_state, wheel_events) = self.wheel.transition(event.wheel, when, timings);
+        let (wheel_button_new_state, wheel_button_events) = self.wheel_button.transition(event.wheel_button, when, timings);
         (WheelSet {
             wheel: wheel_new_state,
             wheel_button: wheel_button_new_state,
@@ -273,11 +554,11 @@ pub struct ProfileCallback<T1, T2, T3, T4> {
 
 
 impl ProfileButton<events::ButtonStateMachine> {
-   pub fn transition(&self, event: ProfileButton<events::ButtonState>, when: Instant) -> (Self, ProfileButton<Vec<events::ButtonEvent>>) {
+   pub fn transition(&self, event: ProfileButton<events::ButtonState>, when: Instant, timings: &events::Timings) -> (Self, ProfileButton<Vec<events::ButtonEvent>>) {
        (ProfileButton {
-           button: self.button.transition(event.button, when).0,
+           button: self.button.transition(event.button, when, timings).0,
        }, ProfileButton {
-           button: self.button.transition(event.button, when).1,
+           button: self.button.transition(event.button, when, timings).1,
        })
    }
 }
@@ -294,3 +575,56 @@ impl From<xencelabs_quick_keys::Event> for ProfileButton<events::ButtonState> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(toml: &str) -> Result<Action, String> {
+        #[derive(Deserialize)]
+        struct One {
+            action: Action,
+        }
+        toml::from_str::<One>(&format!("action = {toml}")).map(|one| one.action).map_err(|err| err.message().trim().to_string())
+    }
+
+    #[test]
+    fn parses_input_actions() {
+        assert!(matches!(action(r#"{ Text = "hello" }"#), Ok(Action::Input(agent::Token::Text(text))) if text == "hello"));
+        assert!(matches!(action(r#"{ Key = [{ Unicode = "b" }, "Click"] }"#), Ok(Action::Input(agent::Token::Key(enigo::Key::Unicode('b'), enigo::Direction::Click)))));
+        assert!(matches!(action(r#"{ Scroll = [3, "Vertical"] }"#), Ok(Action::Input(agent::Token::Scroll(3, enigo::Axis::Vertical)))));
+    }
+
+    #[test]
+    fn parses_non_enigo_actions() {
+        assert!(matches!(action(r#"{ Debug = "hello" }"#), Ok(Action::NonEnigo(NonEnigoAction::Debug(text))) if text == "hello"));
+        assert!(matches!(action(r#""Swap""#), Ok(Action::NonEnigo(NonEnigoAction::Swap))));
+        assert!(matches!(action(r#"{ Macro = "tmux" }"#), Ok(Action::NonEnigo(NonEnigoAction::Macro(MacroCall::Name(name)))) if name == "tmux"));
+        assert!(matches!(
+            action(r#"{ If = { when = { Variable = "muted" }, then = [{ Sleep = 10 }] } }"#),
+            Ok(Action::NonEnigo(NonEnigoAction::If(Conditional { when: Condition::Variable(_), then, otherwise }))) if then.len() == 1 && otherwise.is_empty()
+        ));
+    }
+
+    #[test]
+    fn every_listed_action_is_one() {
+        // A name listed for the wrong kind would never parse
+        for name in NON_ENIGO_ACTIONS {
+            let err = action(&format!("{{ {name} = {{ nonsense = [] }} }}")).unwrap_err();
+            assert!(!err.contains(&format!("unknown variant `{name}`")), "{name}: {err}");
+        }
+        for name in INPUT_ACTIONS {
+            let err = action(&format!("{{ {name} = {{ nonsense = [] }} }}")).unwrap_err();
+            assert!(!err.contains(&format!("unknown variant `{name}`")), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn reports_unknown_actions_and_mistakes_inside_known_ones() {
+        assert_eq!(action(r#"{ Dbug = "hello" }"#).unwrap_err(), "unknown action `Dbug`");
+        assert_eq!(action(r#""Swop""#).unwrap_err(), "unknown action `Swop`");
+        assert_eq!(action(r#"{ Debug = "a", Sleep = 1 }"#).unwrap_err(), "an action is a table with one key, like { Debug = \"...\" }");
+        assert!(action(r#"{ Sleep = "soon" }"#).unwrap_err().contains("invalid type: string \"soon\""));
+        assert!(action(r#"{ Key = ["Nope", "Click"] }"#).unwrap_err().contains("unknown variant `Nope`"));
+    }
+}
@@ -0,0 +1,225 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Command, Arg, ArgAction};
+use crossterm::{
+    cursor, execute, queue, terminal,
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    style::{Color, Print, ResetColor, SetForegroundColor},
+};
+use enigo::{Enigo, Settings};
+use xencelabs_quick_keys::WheelDirection;
+
+use qkeypie::{controller, reload};
+use qkeypie::actions::WhichButton;
+use qkeypie::device::FakeDevice;
+use qkeypie::input::RecordingInput;
+
+const BUTTONS: [WhichButton; 8] = [
+    WhichButton::Button0,
+    WhichButton::Button1,
+    WhichButton::Button2,
+    WhichButton::Button3,
+    WhichButton::Button4,
+    WhichButton::Button5,
+    WhichButton::Button6,
+    WhichButton::Button7,
+];
+
+// How long a tapped button stays pressed
+const TAP_MILLIS: u64 = 80;
+
+fn cli() -> Command {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("qkeypie").unwrap();
+    let config_file = xdg_dirs.get_config_file("config.toml");
+
+    Command::new("qkeypie-sim")
+        .about("Virtual Xencelabs Quick Keys driven by the QKeyPie controller")
+        .arg(Arg::new("CONFIG")
+            .help("The configuration file")
+            .short('c')
+            .default_value(config_file.to_str().unwrap().to_string()))
+        .arg(Arg::new("INPUT")
+            .help("Really type the keyboard and mouse actions instead of only listing them")
+            .long("input")
+            .action(ArgAction::SetTrue))
+}
+
+struct Sim {
+    dev: FakeDevice,
+    recorder: RecordingInput,
+    hold_mode: bool,
+    pending_releases: Vec<(WhichButton, Instant)>,
+}
+
+impl Sim {
+    fn press(&mut self, button: WhichButton) {
+        if self.hold_mode {
+            let pressed = self.dev.is_pressed(&button);
+            self.dev.set_button(&button, !pressed);
+            return;
+        }
+        // A second tap while the first one is still down releases it first
+        if let Some(pos) = self.pending_releases.iter().position(|(b, _)| *b == button) {
+            self.pending_releases.remove(pos);
+            self.dev.set_button(&button, false);
+        }
+        self.dev.set_button(&button, true);
+        self.pending_releases.push((button, Instant::now() + Duration::from_millis(TAP_MILLIS)));
+    }
+
+    fn release_due(&mut self, now: Instant) {
+        let (due, pending) = self.pending_releases.drain(..).partition(|(_, at)| *at <= now);
+        self.pending_releases = pending;
+        for (button, _) in due {
+            self.dev.set_button(&button, false);
+        }
+    }
+
+    fn label(&self, button: &WhichButton, text: &str) -> String {
+        let marker = if self.dev.is_pressed(button) { '*' } else { ' ' };
+        format!("{}{:^10}{}", marker, text, marker)
+    }
+
+    fn render(&self, out: &mut impl Write) -> io::Result<()> {
+        let display = self.dev.display();
+        let (r, g, b) = display.ring_color;
+        let banner = match &display.overlay {
+            Some((text, until)) if *until > Instant::now() => text.clone(),
+            _ => String::new(),
+        };
+
+        let mut lines = vec![
+            format!("QKeyPie simulator{}{}",
+                if self.hold_mode { "  [hold mode]" } else { "" },
+                if self.dev.is_connected() { "" } else { "  [unplugged]" }),
+            String::new(),
+            format!("  +{}+", "-".repeat(12 * 4 + 3)),
+        ];
+        for row in BUTTONS.chunks(4) {
+            let cells: Vec<String> = row.iter().map(|button| {
+                let index = BUTTONS.iter().position(|b| b == button).unwrap();
+                self.label(button, &display.key_text[index])
+            }).collect();
+            lines.push(format!("  |{}|", cells.join("|")));
+            lines.push(format!("  +{}+", "-".repeat(12 * 4 + 3)));
+        }
+        lines.push(format!("  Extra button:{}   Wheel button:{}",
+            self.label(&WhichButton::ButtonExtra, ""),
+            self.label(&WhichButton::WheelButton, "")));
+        lines.push(format!("  Banner: {}", banner));
+        lines.push(String::new());
+        for (row, line) in lines.iter().enumerate() {
+            queue!(out, cursor::MoveTo(0, row as u16), Print(line), terminal::Clear(terminal::ClearType::UntilNewLine))?;
+        }
+        let row = lines.len() as u16;
+        queue!(out,
+            cursor::MoveTo(0, row),
+            Print("  Wheel ring: "),
+            SetForegroundColor(Color::Rgb { r, g, b }),
+            Print("(######)"),
+            ResetColor,
+            Print(format!(" {:?} {:?} {:?}", display.ring_color, display.wheel_speed, display.orientation)),
+            terminal::Clear(terminal::ClearType::UntilNewLine),
+        )?;
+
+        let mut row = row + 2;
+        queue!(out, cursor::MoveTo(0, row), Print("  Last input actions:"))?;
+        for token in self.recorder.tokens().iter().rev().take(8) {
+            row += 1;
+            queue!(out, cursor::MoveTo(0, row), Print(format!("    {:?}", token)), terminal::Clear(terminal::ClearType::UntilNewLine))?;
+        }
+        queue!(out,
+            cursor::MoveTo(0, row + 2),
+            Print("  1-8: buttons 0-7   0: extra button   Enter: wheel button   Left/Right: turn wheel"),
+            cursor::MoveTo(0, row + 3),
+            Print("  Tab: toggle hold mode (keys latch instead of tap)   u: unplug/plug   Esc: quit"),
+            terminal::Clear(terminal::ClearType::FromCursorDown),
+        )?;
+        out.flush()
+    }
+}
+
+fn key_button(code: KeyCode) -> Option<WhichButton> {
+    match code {
+        KeyCode::Char(c @ '1'..='8') => Some(BUTTONS[c as usize - '1' as usize].clone()),
+        KeyCode::Char('0') => Some(WhichButton::ButtonExtra),
+        KeyCode::Enter => Some(WhichButton::WheelButton),
+        _ => None,
+    }
+}
+
+fn ui(sim: &mut Sim, controller: &thread::JoinHandle<anyhow::Result<()>>) -> anyhow::Result<()> {
+    let mut out = io::stdout();
+    while !controller.is_finished() {
+        if event::poll(Duration::from_millis(20))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                    KeyCode::Tab => sim.hold_mode = !sim.hold_mode,
+                    KeyCode::Char('u') => sim.dev.set_connected(!sim.dev.is_connected()),
+                    KeyCode::Left => sim.dev.rotate(WheelDirection::Left),
+                    KeyCode::Right => sim.dev.rotate(WheelDirection::Right),
+                    code => if let Some(button) = key_button(code) {
+                        sim.press(button);
+                    },
+                }
+            }
+        }
+        sim.release_due(Instant::now());
+        sim.render(&mut out)?;
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let matches = cli().get_matches();
+
+    let model = reload::load(Path::new(matches.get_one::<String>("CONFIG").unwrap()))?;
+
+    let dev = FakeDevice::new();
+    let recorder = RecordingInput::new();
+    let controller = {
+        let dev = dev.clone();
+        let recorder = recorder.clone();
+        let real_input = matches.get_flag("INPUT");
+        // Only the first device is simulated
+        let device = model.devices.keys().next().unwrap().clone();
+        let model = model.for_device(&device)?;
+        let (_, mut commands) = controller::channels(&[device]);
+        let commands = commands.remove(0);
+        thread::spawn(move || {
+            if real_input {
+                let enigo = Enigo::new(&Settings::default()).map_err(|e| anyhow::anyhow!("Failed to create enigo: {:?}", e))?;
+                controller::run(model, || Ok(dev.connect()?), enigo, commands)
+            } else {
+                controller::run(model, || Ok(dev.connect()?), recorder, commands)
+            }
+        })
+    };
+
+    let mut sim = Sim {
+        dev,
+        recorder,
+        hold_mode: false,
+        pending_releases: Vec::new(),
+    };
+
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+    let res = ui(&mut sim, &controller);
+    execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    res?;
+
+    if controller.is_finished() {
+        controller.join().map_err(|_| anyhow::anyhow!("controller panicked"))??;
+    }
+    Ok(())
+}
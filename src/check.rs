@@ -0,0 +1,646 @@
+//! `qkeypie check`: run the loader over a configuration file and report what
+//! it finds with the line and column where it is.  Each definition is loaded
+//! on its own, so a mistake in one does not hide those in the others.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+
+use indexmap::IndexMap;
+use serde::de::{Deserialize, IntoDeserializer};
+use toml_edit::{ImDocument, InlineTable, Item, Table, Value};
+
+use crate::config::{Config, ProfileConfig};
+use crate::model;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}:{}: {}: {}", self.line, self.column, severity, self.message)
+    }
+}
+
+/// The TOML document with the position of every key and value, which the
+/// typed `Config` does not keep.
+#[derive(Debug)]
+enum Node {
+    Str(String),
+    Array(Vec<Located>),
+    Table(Vec<(String, Located)>),
+    Other,
+}
+
+/// A value and where it is.  Tables implied by a longer header like
+/// `[a.b.c]` have no place of their own.
+#[derive(Debug)]
+struct Located {
+    span: Option<Range<usize>>,
+    node: Node,
+}
+
+impl Located {
+    fn span(&self) -> Range<usize> {
+        self.span.clone().unwrap_or(0..0)
+    }
+}
+
+impl From<&Item> for Located {
+    fn from(item: &Item) -> Located {
+        match item {
+            Item::Value(value) => value.into(),
+            Item::Table(table) => table.into(),
+            Item::ArrayOfTables(tables) => Located {
+                span: tables.span(),
+                node: Node::Array(tables.iter().map(Located::from).collect()),
+            },
+            Item::None => Located { span: None, node: Node::Other },
+        }
+    }
+}
+
+impl From<&Table> for Located {
+    fn from(table: &Table) -> Located {
+        Located {
+            span: table.span(),
+            node: Node::Table(table.iter().map(|(key, item)| (key.to_string(), item.into())).collect()),
+        }
+    }
+}
+
+impl From<&Value> for Located {
+    fn from(value: &Value) -> Located {
+        let node = match value {
+            Value::String(text) => Node::Str(text.value().clone()),
+            Value::Array(items) => Node::Array(items.iter().map(Located::from).collect()),
+            Value::InlineTable(table) => Node::Table(table.iter().map(|(key, value)| (key.to_string(), value.into())).collect()),
+            _ => Node::Other,
+        };
+        Located { span: value.span(), node }
+    }
+}
+
+fn entries(node: &Located) -> &[(String, Located)] {
+    match &node.node {
+        Node::Table(entries) => entries,
+        _ => &[],
+    }
+}
+
+fn get<'a>(node: &'a Located, key: &str) -> Option<&'a Located> {
+    entries(node).iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+fn string(node: &Located) -> Option<&str> {
+    match &node.node {
+        Node::Str(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// Every value in `node` in file order, with the keys leading to it.
+/// Array elements have `#` for a key.
+fn walk<'a>(node: &'a Located, path: &mut Vec<&'a str>, nodes: &mut Nodes<'a>) {
+    nodes.push((path.clone(), node));
+    match &node.node {
+        Node::Table(entries) => {
+            for (key, value) in entries {
+                path.push(key);
+                walk(value, path, nodes);
+                path.pop();
+            }
+        },
+        Node::Array(items) => {
+            for item in items {
+                path.push("#");
+                walk(item, path, nodes);
+                path.pop();
+            }
+        },
+        _ => {},
+    }
+}
+
+type Nodes<'a> = Vec<(Vec<&'a str>, &'a Located)>;
+
+/// Whether the keys of `path` are `keys`, where `*` stands for any key and
+/// `button*` for any key starting with `button`.
+fn is(path: &[&str], keys: &[&str]) -> bool {
+    path.len() == keys.len() && path.iter().zip(keys).all(|(key, pattern)| match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == pattern,
+    })
+}
+
+/// The first value with the keys `keys`, which is the string `value` when given.
+fn find<'a>(nodes: &Nodes<'a>, keys: &[&str], value: Option<&str>) -> Option<&'a Located> {
+    nodes.iter()
+        .find(|(path, node)| is(path, keys) && value.map_or(true, |value| string(node) == Some(value)))
+        .map(|(_, node)| *node)
+}
+
+/// The first value whose last keys are `keys` and which is the string `value`,
+/// like the `Name` of a target at any depth in an action list.
+fn find_in_actions<'a>(nodes: &Nodes<'a>, keys: &[&str], value: &str) -> Option<&'a Located> {
+    nodes.iter()
+        .find(|(path, node)| path.len() >= keys.len() && is(&path[path.len() - keys.len()..], keys) && string(node) == Some(value))
+        .map(|(_, node)| *node)
+}
+
+/// The macro a `Macro` action calls.
+fn call_name(call: &Located) -> Option<&str> {
+    string(call).or_else(|| get(call, "name").and_then(string))
+}
+
+/// Every `Macro` action, with the keys leading to it.
+fn calls<'a, 'b>(nodes: &'b Nodes<'a>) -> impl Iterator<Item = (&'b [&'a str], &'a Located, &'a str)> {
+    nodes.iter().filter_map(|(path, node)| match path.last() {
+        Some(&"Macro") => call_name(node).map(|name| (&path[..], *node, name)),
+        _ => None,
+    })
+}
+
+/// Where the problem the loader reports in `message` is.  Its messages name
+/// what is wrong, which is looked up where it can be in the config.
+fn locate<'a>(nodes: &Nodes<'a>, message: &str) -> Option<&'a Located> {
+    let call = |name: &str, within: Option<&str>| calls(nodes)
+        .find(|(path, _, called)| *called == name && within.map_or(true, |caller| path.starts_with(&["macros", caller])))
+        .map(|(_, call, _)| call);
+    if let Some(cycle) = message.strip_prefix("Macro cycle: ") {
+        // The call that closes the cycle, in the last macro before it
+        let names: Vec<&str> = cycle.split(" -> ").collect();
+        let [.., caller, callee] = names[..] else { return None };
+        return call(callee, Some(caller));
+    }
+    let words: Vec<&str> = message.split(' ').collect();
+    match words[..] {
+        ["Macro", name, "has", "no", "parameter", param] => {
+            let call = call(name, None)?;
+            get(call, "args").and_then(|args| get(args, param)).or(Some(call))
+        },
+        ["Macro", name, ..] => call(name.trim_end_matches(':'), None),
+        ["Bad", "click", "count", count, ..] => nodes.iter()
+            .find(|(path, _)| path.ends_with(&["on_click_n", count]))
+            .map(|(_, node)| *node),
+        ["Bad", "chord", chord, "in", "buttonset", id, ..] => find(nodes, &["buttonsets", id.trim_end_matches(','), "chords", chord], None),
+        ["Button", id, "not", "found"] => find(nodes, &["buttonsets", "*", "button*"], Some(id))
+            .or_else(|| find(nodes, &["buttonsets", "*", "shift", "button*"], Some(id)))
+            .or_else(|| find(nodes, &["profiles", "*", "button"], Some(id))),
+        ["Wheel", id, "not", "found"] => find(nodes, &["profiles", "*", "wheels", "*"], Some(id))
+            .or_else(|| find(nodes, &["buttonsets", "*", "shift", "wheel"], Some(id))),
+        ["Buttonset", id, "not", "found"] => find(nodes, &["profiles", "*", "buttonsets", "*"], Some(id)),
+        ["Button", id, "has", "a", "chord", ..] => find(nodes, &["buttons", id, "timings", "chord"], None),
+        ["Wheel", id, "has", "a", "chord", ..] => find(nodes, &["wheels", id, "timings", "chord"], None),
+        ["The", "shift", "of", "buttonset", id, ..] => find(nodes, &["buttonsets", id, "shift", "hold"], None),
+        ["Device", id, "has", "an", "unknown", "profile", profile] => find(nodes, &["devices", id, "profiles", "#"], Some(profile))
+            .or_else(|| find(nodes, &["device", "profiles", "#"], Some(profile))),
+        ["Device", id, "has", "no", "profiles"] => find(nodes, &["devices", id, "profiles"], None)
+            .or_else(|| find(nodes, &["device", "profiles"], None)),
+        ["Focus", "rule", "for", "unknown", kind, name, ..] => find(nodes, &["focus", "#", kind], Some(name)),
+        ["Profile", id, "has", ..] => find(nodes, &["profiles", id], None),
+        [action @ ("ChangeProfile" | "ChangeButtonSet" | "ChangeWheel"), "target", name, ..] => find_in_actions(nodes, &[action, "Name"], name)
+            .or_else(|| find_in_actions(nodes, &[action, "#", "Name"], name))
+            // Or handed to a macro, to be put in its place
+            .or_else(|| find_in_actions(nodes, &["args", "*"], name)),
+        ["If", "condition", kind, name, ..] => {
+            let key = match kind {
+                "profile" => "Profile",
+                "buttonset" => "ButtonSet",
+                _ => "Wheel",
+            };
+            find_in_actions(nodes, &[key], name).or_else(|| find_in_actions(nodes, &["args", "*"], name))
+        },
+        _ => None,
+    }
+}
+
+/// A definition with nothing in it, standing in for the real one.
+fn empty<T: serde::de::DeserializeOwned>() -> T {
+    toml::Value::Table(toml::Table::new()).try_into().expect("every field is optional")
+}
+
+/// What the loader finds wrong with `cfg`.  It stops at the first problem, so
+/// each definition is loaded on its own, with those it refers to left empty,
+/// and the config as a whole is only validated once they all load.
+fn loader_problems(cfg: &Config) -> Vec<String> {
+    fn hollow<T: serde::de::DeserializeOwned>(definitions: &Option<IndexMap<String, T>>) -> Option<IndexMap<String, T>> {
+        definitions.as_ref().map(|definitions| definitions.keys().map(|id| (id.clone(), empty())).collect())
+    }
+    let hollow = Config {
+        server: None,
+        buttons: hollow(&cfg.buttons),
+        wheels: hollow(&cfg.wheels),
+        buttonsets: hollow(&cfg.buttonsets),
+        profiles: None,
+        ..cfg.clone()
+    };
+    // A profile with only `definition` in it, so the loader gets to it
+    let using = |profile: ProfileConfig| Config { profiles: Some(IndexMap::from([(String::new(), profile)])), ..hollow.clone() };
+
+    let mut units = vec![Config { server: cfg.server.clone(), ..hollow.clone() }];
+    for (id, button) in cfg.buttons.iter().flatten() {
+        let mut unit = using(ProfileConfig { button: Some(id.clone()), ..empty() });
+        unit.buttons.get_or_insert_with(IndexMap::new).insert(id.clone(), button.clone());
+        units.push(unit);
+    }
+    for (id, wheel) in cfg.wheels.iter().flatten() {
+        let mut unit = using(ProfileConfig { wheels: Some(IndexMap::from([(id.clone(), id.clone())])), ..empty() });
+        unit.wheels.get_or_insert_with(IndexMap::new).insert(id.clone(), wheel.clone());
+        units.push(unit);
+    }
+    for (id, buttonset) in cfg.buttonsets.iter().flatten() {
+        let mut unit = using(ProfileConfig { buttonsets: Some(IndexMap::from([(id.clone(), id.clone())])), ..empty() });
+        unit.buttonsets.get_or_insert_with(IndexMap::new).insert(id.clone(), buttonset.clone());
+        units.push(unit);
+    }
+    for (id, profile) in cfg.profiles.iter().flatten() {
+        units.push(Config { profiles: Some(IndexMap::from([(id.clone(), profile.clone())])), ..hollow.clone() });
+    }
+
+    let mut problems: Vec<String> = Vec::new();
+    for unit in units {
+        if let Err(err) = model::from_config(unit) {
+            let problem = format!("{:#}", err);
+            if !problems.contains(&problem) {
+                problems.push(problem);
+            }
+        }
+    }
+    if problems.is_empty() {
+        if let Err(err) = model::from_config(cfg.clone()).and_then(|model| model::validate(&model)) {
+            problems.push(format!("{:#}", err));
+        }
+    }
+    problems
+}
+
+struct Checker<'a> {
+    source: &'a str,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, severity: Severity, span: Range<usize>, message: String) {
+        let before = &self.source[..span.start.min(self.source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        self.diagnostics.push(Diagnostic { line, column, severity, message });
+    }
+
+    fn error(&mut self, span: Range<usize>, message: String) {
+        self.report(Severity::Error, span, message);
+    }
+
+    fn warning(&mut self, span: Range<usize>, message: String) {
+        self.report(Severity::Warning, span, message);
+    }
+
+    /// Check a document with only `key = value` in it, where `value` is `item`
+    /// or a part of it, against `Config`.
+    fn check_schema(&mut self, key: &str, value: Value, item: &Item) {
+        let document = Value::InlineTable(InlineTable::from_iter([(key, value)]));
+        if let Err(err) = Config::deserialize(document.into_deserializer()) {
+            let field = err.message().strip_prefix("unknown field `").and_then(|rest| rest.split('`').next());
+            let span = field.and_then(|field| key_span(item, field))
+                .or_else(|| err.span())
+                .or_else(|| item.span())
+                .unwrap_or(0..0);
+            self.error(span, err.message().to_string());
+        }
+    }
+
+    /// Warn about what the loader accepts but never runs.
+    fn check_unused(&mut self, root: &Located, nodes: &Nodes) {
+        let references = |patterns: &[&[&str]]| -> HashSet<&str> {
+            nodes.iter()
+                .filter(|(path, _)| patterns.iter().any(|keys| is(path, keys)))
+                .filter_map(|(_, node)| string(node))
+                .collect()
+        };
+        let buttons = references(&[&["buttonsets", "*", "button*"], &["buttonsets", "*", "shift", "button*"], &["profiles", "*", "button"]]);
+        let wheels = references(&[&["profiles", "*", "wheels", "*"], &["buttonsets", "*", "shift", "wheel"]]);
+        let buttonsets = references(&[&["profiles", "*", "buttonsets", "*"]]);
+        // A macro calling itself is a cycle, which the loader reports
+        let macros: HashSet<&str> = calls(nodes)
+            .filter(|(path, _, name)| !path.starts_with(&["macros", name]))
+            .map(|(_, _, name)| name)
+            .collect();
+        for (section, kind, used) in [("buttons", "Button", buttons), ("wheels", "Wheel", wheels), ("buttonsets", "Buttonset", buttonsets), ("macros", "Macro", macros)] {
+            for (id, definition) in get(root, section).map(entries).unwrap_or_default() {
+                if !used.contains(id.as_str()) {
+                    self.warning(definition.span(), format!("{} {} is never used", kind, id));
+                }
+            }
+        }
+
+        for (path, callback) in nodes {
+            let [.., "on_click_n", count] = path[..] else { continue };
+            if let Some(name) = match count { "1" => Some("on_click"), "2" => Some("on_double_click"), "3" => Some("on_triple_click"), _ => None } {
+                self.warning(callback.span(), format!("on_click_n for {} clicks is never used, {} is", count, name));
+            }
+        }
+        // `Button7` holds the shift of `button7`
+        for (path, shift) in nodes {
+            let ["buttonsets", _, "shift"] = path[..] else { continue };
+            let Some(hold) = get(shift, "hold").and_then(string) else { continue };
+            for (key, value) in entries(shift) {
+                if key.starts_with("button") && hold.eq_ignore_ascii_case(key) {
+                    self.warning(value.span(), format!("Shift {} is never used, it holds the shift", key));
+                }
+            }
+        }
+    }
+}
+
+/// The sections checked entry by entry, so a mistake in one entry does not
+/// hide those in the others.
+const MAPS: &[&str] = &["devices", "macros", "variables", "buttons", "wheels", "buttonsets", "profiles", "focus"];
+
+/// `item` as an inline value for the deserializer, keeping where every value
+/// is.  Tables from headers lose their own place on the way.
+fn inline(item: &Item) -> Option<Value> {
+    match item {
+        Item::Value(value) => Some(value.clone()),
+        Item::Table(table) => Some(Value::InlineTable(inline_table(table))),
+        Item::ArrayOfTables(tables) => Some(Value::Array(tables.iter().map(inline_table).collect())),
+        Item::None => None,
+    }
+}
+
+fn inline_table(table: &Table) -> InlineTable {
+    let mut inline_table = InlineTable::new();
+    for (key, item) in table.iter() {
+        if let Some(value) = inline(item) {
+            inline_table.insert(key, value);
+        }
+    }
+    inline_table
+}
+
+/// The entries of a table or the elements of an array, with their keys.
+fn parts(item: &Item) -> Option<Vec<(Option<&str>, Item)>> {
+    match item {
+        Item::Table(table) => Some(table.iter().map(|(key, item)| (Some(key), item.clone())).collect()),
+        Item::ArrayOfTables(tables) => Some(tables.iter().map(|table| (None, Item::Table(table.clone()))).collect()),
+        Item::Value(Value::InlineTable(table)) => Some(table.iter().map(|(key, value)| (Some(key), Item::Value(value.clone()))).collect()),
+        Item::Value(Value::Array(values)) => Some(values.iter().map(|value| (None, Item::Value(value.clone()))).collect()),
+        _ => None,
+    }
+}
+
+/// Where the key `field` is in `item`, at any depth.
+fn key_span(item: &Item, field: &str) -> Option<Range<usize>> {
+    match item {
+        Item::Table(table) => table.key(field).and_then(|key| key.span())
+            .or_else(|| table.iter().find_map(|(_, item)| key_span(item, field))),
+        Item::ArrayOfTables(tables) => tables.iter().find_map(|table| key_span(&Item::Table(table.clone()), field)),
+        Item::Value(Value::InlineTable(table)) => table.key(field).and_then(|key| key.span())
+            .or_else(|| table.iter().find_map(|(_, value)| key_span(&Item::Value(value.clone()), field))),
+        Item::Value(Value::Array(values)) => values.iter().find_map(|value| key_span(&Item::Value(value.clone()), field)),
+        _ => None,
+    }
+}
+
+/// Every problem found in the configuration `source`, in file order.
+pub fn check(source: &str) -> Vec<Diagnostic> {
+    let mut checker = Checker { source, diagnostics: Vec::new() };
+
+    let document = match ImDocument::parse(source) {
+        Ok(document) => document,
+        Err(err) => {
+            checker.error(err.span().unwrap_or(0..0), err.message().to_string());
+            return checker.diagnostics;
+        },
+    };
+    // Wrong field names and malformed actions
+    for (key, item) in document.iter() {
+        match parts(item).filter(|_| MAPS.contains(&key)) {
+            Some(parts) => {
+                for (id, part) in parts {
+                    let value = match (id, inline(&part)) {
+                        (Some(id), Some(value)) => Value::InlineTable(InlineTable::from_iter([(id, value)])),
+                        (None, Some(value)) => Value::Array([value].into_iter().collect()),
+                        (_, None) => continue,
+                    };
+                    checker.check_schema(key, value, &part);
+                }
+            },
+            None => {
+                if let Some(value) = inline(item) {
+                    checker.check_schema(key, value, item);
+                }
+            },
+        }
+    }
+    let root = Located::from(document.as_table());
+    let mut nodes = Vec::new();
+    walk(&root, &mut Vec::new(), &mut nodes);
+
+    // The rest is up to the loader, which needs every part of the config to be well formed
+    if checker.diagnostics.is_empty() {
+        match toml::from_str::<Config>(source) {
+            Ok(cfg) => {
+                for problem in loader_problems(&cfg) {
+                    let span = locate(&nodes, &problem).map(Located::span).unwrap_or(0..0);
+                    checker.error(span, problem);
+                }
+            },
+            Err(err) => checker.error(err.span().unwrap_or(0..0), err.message().to_string()),
+        }
+    }
+    checker.check_unused(&root, &nodes);
+
+    checker.diagnostics.sort();
+    checker.diagnostics.dedup();
+    checker.diagnostics
+}
+
+pub fn check_file(path: &Path) -> anyhow::Result<Vec<Diagnostic>> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path.display(), e))?;
+    Ok(check(&source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The diagnostics for the config with these lines, as `check` prints them.
+    fn diagnostics(lines: &[&str]) -> Vec<String> {
+        check(&lines.join("\n")).iter().map(Diagnostic::to_string).collect()
+    }
+
+    /// A config with nothing wrong, to add mistakes to
+    const BASE: &[&str] = &[
+        "[buttons.hello]",
+        "on_press = [{ Debug = \"hello\" }]",
+        "[wheels.volume]",
+        "[buttonsets.main]",
+        "button0 = \"hello\"",
+        "[profiles.main.buttonsets]",
+        "main = \"main\"",
+        "[profiles.main.wheels]",
+        "volume = \"volume\"",
+    ];
+
+    fn with(lines: &[&'static str]) -> Vec<&'static str> {
+        BASE.iter().chain(lines).copied().collect()
+    }
+
+    #[test]
+    fn the_example_config_is_fine() {
+        assert_eq!(check(include_str!("../config.toml")), []);
+        assert_eq!(diagnostics(BASE), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_references() {
+        assert_eq!(diagnostics(&with(&[
+            "[buttonsets.other]",
+            "button0 = \"hello\"",
+            "button1 = \"nope\"",
+            "[profiles.second]",
+            "buttonsets = { other = \"other\", third = \"third\" }",
+            "wheels = { volume = \"volume\" }",
+        ])), [
+            "12:11: error: Button nope not found",
+            "14:41: error: Buttonset third not found",
+        ]);
+    }
+
+    #[test]
+    fn macro_cycles() {
+        assert_eq!(diagnostics(&with(&[
+            "[macros]",
+            "ping = [{ Macro = \"pong\" }]",
+            "pong = [{ Debug = \"pong\" }, { Macro = \"ping\" }]",
+        ])), [
+            "12:39: error: Macro cycle: ping -> pong -> ping",
+        ]);
+    }
+
+    #[test]
+    fn unknown_macros_and_wrong_args() {
+        assert_eq!(diagnostics(&with(&[
+            "[macros]",
+            "greet = { params = [\"name\"], actions = [{ Debug = \"hello ${name}\" }] }",
+            "[buttons.typo]",
+            "on_press = [{ Macro = { name = \"greet\", args = { name = \"world\", nmae = \"planet\" } } }]",
+            "[buttons.missing]",
+            "on_press = [{ Macro = \"gret\" }]",
+            "[buttonsets.main.shift]",
+            "hold = \"Button7\"",
+            "button1 = \"typo\"",
+            "button2 = \"missing\"",
+        ])), [
+            "13:73: error: Macro greet has no parameter nmae",
+            "15:23: error: Macro gret not found",
+        ]);
+    }
+
+    #[test]
+    fn change_targets_of_other_profiles() {
+        assert_eq!(diagnostics(&with(&[
+            "[buttonsets.main.chords]",
+            "\"button0+button1\" = [{ ChangeButtonSet = { Name = \"games\" } }]",
+        ])), [
+            "11:51: error: ChangeButtonSet target games is not a buttonset of profile main",
+        ]);
+        assert_eq!(diagnostics(&with(&[
+            "[server]",
+            "on_enter = [{ ChangeProfile = [{ Name = \"main\" }, \"This\", { Name = \"scroll\" }] }]",
+        ])), [
+            "11:68: error: ChangeProfile target scroll is not a wheel of profile main",
+        ]);
+    }
+
+    #[test]
+    fn bad_click_counts() {
+        assert_eq!(diagnostics(&with(&[
+            "[buttons.hello.on_click_n]",
+            "four = [{ Debug = \"four\" }]",
+            "2 = [{ Debug = \"two\" }]",
+        ])), [
+            "11:8: error: Bad click count four in on_click_n",
+            "12:5: warning: on_click_n for 2 clicks is never used, on_double_click is",
+        ]);
+    }
+
+    #[test]
+    fn bad_chords() {
+        assert_eq!(diagnostics(&with(&[
+            "[buttonsets.main.chords]",
+            "\"button0-button4\" = [{ Debug = \"chord\" }]",
+        ])), [
+            "11:21: error: Bad chord button0-button4 in buttonset main, it should be like button0+button4",
+        ]);
+    }
+
+    #[test]
+    fn unused_definitions() {
+        assert_eq!(diagnostics(&with(&[
+            "[buttons.spare]",
+            "[wheels.scroll]",
+            "[buttonsets.games]",
+            "[macros]",
+            "unused = [{ Debug = \"never\" }]",
+        ])), [
+            "10:1: warning: Button spare is never used",
+            "11:1: warning: Wheel scroll is never used",
+            "12:1: warning: Buttonset games is never used",
+            "14:10: warning: Macro unused is never used",
+        ]);
+    }
+
+    #[test]
+    fn every_definition_reports_its_own_problem() {
+        assert_eq!(diagnostics(&[
+            "[buttons.hello]",
+            "on_press = [{ Macro = \"nope\" }]",
+            "[buttons.other]",
+            "on_click_n = { x = [] }",
+            "[wheels.volume]",
+            "[buttonsets.main]",
+            "button0 = \"hello\"",
+            "button1 = \"other\"",
+            "[profiles.main.buttonsets]",
+            "main = \"main\"",
+            "[profiles.main.wheels]",
+            "volume = \"volume\"",
+            "scroll = \"scroll\"",
+        ]), [
+            "2:23: error: Macro nope not found",
+            "4:20: error: Bad click count x in on_click_n",
+            "13:10: error: Wheel scroll not found",
+        ]);
+    }
+
+    #[test]
+    fn syntax_and_schema_errors() {
+        assert_eq!(diagnostics(&["[buttons.hello", "on_press = []"]), ["1:15: error: invalid table header\nexpected `.`, `]`"]);
+        assert_eq!(diagnostics(&with(&["[buttons.typo]", "on_pres = []"])), ["10:1: warning: Button typo is never used", "11:1: error: unknown field `on_pres`"]);
+    }
+
+    #[test]
+    fn what_the_config_as_a_whole_lacks() {
+        assert_eq!(diagnostics(&with(&["[profiles.empty]", "button = \"hello\""])), ["10:1: error: Profile empty has no buttonsets"]);
+        assert_eq!(diagnostics(&with(&["[devices.left]", "profiles = [\"main\", \"games\"]"])), ["11:21: error: Device left has an unknown profile games"]);
+    }
+}
@@ -0,0 +1,258 @@
+//! Chords: buttons of a buttonset pressed together, bound in its `chords`
+//! table by their names joined with `+`:
+//!
+//! ```toml
+//! [buttonsets.editing]
+//! chords = { "button0+button4" = [ { Text = "chord!" } ] }
+//! ```
+//!
+//! A press of a button that is part of some chord is held back for the
+//! `chord` window of the `[timings]`.  When the buttons down by then make a
+//! chord, its callback runs and the buttons keep quiet until they are let go
+//! and done clicking.  Otherwise whatever was held back goes on as usual.
+
+use std::time::{Duration, Instant};
+
+use indexmap::IndexMap;
+
+use crate::actions::ButtonSet;
+use crate::events::{ButtonEvent, ButtonStateMachine};
+
+/// The buttons of a chord name like `button0+button4`, one bit each, or
+/// `None` when it is not one.
+pub fn parse(name: &str) -> Option<u8> {
+    let mut mask = 0u8;
+    for button in name.split('+') {
+        let index: u8 = button.trim().strip_prefix("button")?.parse().ok().filter(|index| *index < 8)?;
+        if mask & (1 << index) != 0 {
+            return None;
+        }
+        mask |= 1 << index;
+    }
+    (mask.count_ones() >= 2).then_some(mask)
+}
+
+/// The chords of a buttonset by their buttons.  Names that are not chords
+/// were turned down with the config already.
+pub fn masks<T>(chords: &IndexMap<String, T>) -> Vec<(String, u8)> {
+    chords.keys().filter_map(|name| parse(name).map(|mask| (name.clone(), mask))).collect()
+}
+
+/// Presses waiting to find out if they make a chord.
+#[derive(Debug, Clone)]
+struct Pending {
+    until: Instant,
+    /// The buttons pressed since it started
+    pressed: u8,
+    events: Vec<(usize, ButtonEvent)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Chords {
+    pending: Option<Pending>,
+    /// The buttons of the last chord, quiet until they are idle again
+    quiet: u8,
+}
+
+impl Chords {
+    /// When the presses held back are due, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.until)
+    }
+
+    /// Take the events of the buttons, after `machines` moved, and give back
+    /// the ones to run callbacks for, along with the chord made, if any.
+    pub fn filter(&mut self, chords: &[(String, u8)], machines: &ButtonSet<ButtonStateMachine>, events: ButtonSet<Vec<ButtonEvent>>, now: Instant, window: Duration) -> (Option<String>, ButtonSet<Vec<ButtonEvent>>) {
+        let machines: [ButtonStateMachine; 8] = machines.clone().into();
+        let mut events: [Vec<ButtonEvent>; 8] = events.into();
+        let mut out: [Vec<ButtonEvent>; 8] = Default::default();
+        let members = chords.iter().fold(0, |members, (_, mask)| members | mask);
+        let down = (0..8).filter(|i| machines[*i].is_pressed()).fold(0u8, |down, i| down | (1 << i));
+
+        for (i, machine) in machines.iter().enumerate() {
+            if self.quiet & (1 << i) != 0 {
+                events[i].clear();
+                if matches!(machine, ButtonStateMachine::Idle) {
+                    self.quiet &= !(1 << i);
+                }
+            }
+        }
+
+        let pressing = (0..8).any(|i| members & (1 << i) != 0 && events[i].iter().any(|e| matches!(e, ButtonEvent::OnPress)));
+        if pressing && self.pending.is_none() {
+            self.pending = Some(Pending { until: now + window, pressed: 0, events: Vec::new() });
+        }
+        let mut chord = None;
+        if let Some(mut pending) = self.pending.take() {
+            for (i, button_events) in events.iter_mut().enumerate() {
+                if members & (1 << i) == 0 {
+                    continue;
+                }
+                if button_events.iter().any(|e| matches!(e, ButtonEvent::OnPress)) {
+                    pending.pressed |= 1 << i;
+                }
+                pending.events.extend(button_events.drain(..).map(|event| (i, event)));
+            }
+            let due = now >= pending.until;
+            // Other buttons held meanwhile, like a shift, are no part of it
+            let made = chords.iter().find(|(_, mask)| *mask == pending.pressed && *mask & down == *mask);
+            // Wait for the window to close when more buttons could still make a bigger chord
+            let bigger = chords.iter().any(|(_, mask)| *mask != pending.pressed && *mask & pending.pressed == pending.pressed);
+            let let_go = pending.pressed & !down != 0;
+            match made {
+                Some((name, mask)) if due || !bigger => {
+                    chord = Some(name.clone());
+                    self.quiet |= mask;
+                    for (i, event) in pending.events {
+                        if mask & (1 << i) == 0 {
+                            out[i].push(event);
+                        }
+                    }
+                },
+                _ if due || let_go => {
+                    for (i, event) in pending.events {
+                        out[i].push(event);
+                    }
+                },
+                _ => self.pending = Some(pending),
+            }
+        }
+
+        for (i, button_events) in events.into_iter().enumerate() {
+            out[i].extend(button_events);
+        }
+        (chord, out.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRESS: &[ButtonEvent] = &[ButtonEvent::OnPress, ButtonEvent::OnClickPress(1)];
+    const RELEASE: &[ButtonEvent] = &[ButtonEvent::OnRelease, ButtonEvent::OnClickRelease(1)];
+
+    struct Buttons {
+        chords: Chords,
+        masks: Vec<(String, u8)>,
+        start: Instant,
+    }
+
+    impl Buttons {
+        fn new(names: &[&str]) -> Self {
+            let masks = names.iter().map(|name| (name.to_string(), parse(name).unwrap())).collect();
+            Buttons { chords: Chords::default(), masks, start: Instant::now() }
+        }
+
+        /// Filter `events` at `millis`, with the buttons in `down` held and
+        /// the others waiting for more clicks.  Gives the chord and what is
+        /// let through, as `"button Event"`.
+        fn at(&mut self, millis: u64, down: u8, events: &[(usize, &[ButtonEvent])]) -> (Option<String>, Vec<String>) {
+            let machines: [ButtonStateMachine; 8] = std::array::from_fn(|i| match down & (1 << i) {
+                0 => ButtonStateMachine::WaitingForClick(self.start, 1),
+                _ => ButtonStateMachine::Pressed(self.start, 0),
+            });
+            let mut in_events: [Vec<ButtonEvent>; 8] = Default::default();
+            for (i, button_events) in events {
+                in_events[*i].extend(button_events.iter().cloned());
+            }
+            let now = self.start + Duration::from_millis(millis);
+            let (chord, out) = self.chords.filter(&self.masks, &machines.into(), in_events.into(), now, Duration::from_millis(50));
+            let out: [Vec<ButtonEvent>; 8] = out.into();
+            let out = out.iter().enumerate()
+                .flat_map(|(i, events)| events.iter().map(move |event| format!("{i} {event:?}")))
+                .collect();
+            (chord, out)
+        }
+
+        /// Let every button but the ones in `waiting` go idle.
+        fn settle(&mut self, waiting: u8) {
+            let machines: [ButtonStateMachine; 8] = std::array::from_fn(|i| match waiting & (1 << i) {
+                0 => ButtonStateMachine::Idle,
+                _ => ButtonStateMachine::WaitingForClick(self.start, 1),
+            });
+            self.chords.filter(&self.masks, &machines.into(), ButtonSet::default(), self.start, Duration::from_millis(50));
+        }
+    }
+
+    fn nothing() -> (Option<String>, Vec<String>) {
+        (None, Vec::new())
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(parse("button0+button4"), Some(0b1_0001));
+        assert_eq!(parse("button7 + button1 + button2"), Some(0b1000_0110));
+        assert_eq!(parse("button0"), None);
+        assert_eq!(parse("button0+button0"), None);
+        assert_eq!(parse("button0+button8"), None);
+        assert_eq!(parse("button0+wheel"), None);
+    }
+
+    #[test]
+    fn chord_within_the_window() {
+        let mut buttons = Buttons::new(&["button0+button4"]);
+        assert_eq!(buttons.at(0, 0b1, &[(0, PRESS)]), nothing());
+        assert_eq!(buttons.chords.deadline(), Some(buttons.start + Duration::from_millis(50)));
+        assert_eq!(buttons.at(30, 0b1_0001, &[(4, PRESS)]), (Some("button0+button4".to_string()), Vec::new()));
+        assert_eq!(buttons.chords.deadline(), None);
+
+        // Its buttons keep quiet until they are done, the others do not
+        assert_eq!(buttons.at(100, 0b1_0000, &[(0, RELEASE), (1, PRESS)]), (None, vec!["1 OnPress".to_string(), "1 OnClickPress(1)".to_string()]));
+        assert_eq!(buttons.chords.quiet, 0b1_0001);
+        buttons.settle(0b1_0000);
+        assert_eq!(buttons.chords.quiet, 0b1_0000);
+        buttons.settle(0);
+        assert_eq!(buttons.chords.quiet, 0);
+    }
+
+    #[test]
+    fn no_chord_outside_the_window() {
+        let mut buttons = Buttons::new(&["button0+button4"]);
+        assert_eq!(buttons.at(0, 0b1, &[(0, PRESS)]), nothing());
+        assert_eq!(buttons.at(49, 0b1, &[]), nothing());
+        assert_eq!(buttons.at(50, 0b1, &[]), (None, vec!["0 OnPress".to_string(), "0 OnClickPress(1)".to_string()]));
+        // Too late, and starting a window of its own
+        assert_eq!(buttons.at(60, 0b1_0001, &[(4, PRESS)]), nothing());
+        assert_eq!(buttons.at(110, 0b1_0001, &[]), (None, vec!["4 OnPress".to_string(), "4 OnClickPress(1)".to_string()]));
+    }
+
+    #[test]
+    fn letting_go_early_is_no_chord() {
+        let mut buttons = Buttons::new(&["button0+button4"]);
+        assert_eq!(buttons.at(0, 0b1, &[(0, PRESS)]), nothing());
+        let (chord, out) = buttons.at(20, 0, &[(0, RELEASE)]);
+        assert_eq!(chord, None);
+        assert_eq!(out, ["0 OnPress", "0 OnClickPress(1)", "0 OnRelease", "0 OnClickRelease(1)"]);
+    }
+
+    #[test]
+    fn held_button_outside_the_chord() {
+        let mut buttons = Buttons::new(&["button0+button4"]);
+        // Button 2 is no member, so its press goes right through
+        let (chord, out) = buttons.at(0, 0b100, &[(2, PRESS)]);
+        assert_eq!(chord, None);
+        assert_eq!(out, ["2 OnPress", "2 OnClickPress(1)"]);
+        assert_eq!(buttons.at(500, 0b101, &[(0, PRESS)]), nothing());
+        assert_eq!(buttons.at(520, 0b1_0101, &[(4, PRESS)]), (Some("button0+button4".to_string()), Vec::new()));
+    }
+
+    #[test]
+    fn held_member_of_another_chord() {
+        let mut buttons = Buttons::new(&["button0+button4", "button1+button2"]);
+        assert_eq!(buttons.at(0, 0b10, &[(1, PRESS)]), nothing());
+        assert_eq!(buttons.at(50, 0b10, &[]).1, ["1 OnPress", "1 OnClickPress(1)"]);
+        assert_eq!(buttons.at(500, 0b1_0011, &[(0, PRESS), (4, PRESS)]), (Some("button0+button4".to_string()), Vec::new()));
+    }
+
+    #[test]
+    fn bigger_chord_waits_for_the_window() {
+        let mut buttons = Buttons::new(&["button0+button1", "button0+button1+button2"]);
+        assert_eq!(buttons.at(0, 0b11, &[(0, PRESS), (1, PRESS)]), nothing());
+        assert_eq!(buttons.at(50, 0b11, &[]), (Some("button0+button1".to_string()), Vec::new()));
+
+        let mut buttons = Buttons::new(&["button0+button1", "button0+button1+button2"]);
+        assert_eq!(buttons.at(0, 0b11, &[(0, PRESS), (1, PRESS)]), nothing());
+        assert_eq!(buttons.at(30, 0b111, &[(2, PRESS)]), (Some("button0+button1+button2".to_string()), Vec::new()));
+    }
+}
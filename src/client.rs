@@ -0,0 +1,113 @@
+//! Talks to a running daemon through its control socket.
+
+use std::path::Path;
+
+use axum::body::Body;
+use indexmap::IndexMap;
+use http_body_util::BodyExt;
+use hyper::{header, Method, Request};
+use hyper_util::rt::TokioIo;
+use tokio::net::UnixStream;
+
+use crate::actions::{Action, ChangeRef, GoTo};
+use crate::state::Status;
+
+/// `next`, `prev`, `first`, `last` and `this` are keywords, anything else is a name.
+pub fn change_ref(target: &str) -> ChangeRef {
+    match target {
+        "next" => ChangeRef::Next,
+        "prev" | "previous" => ChangeRef::Previous,
+        "first" => ChangeRef::First,
+        "last" => ChangeRef::Last,
+        "this" => ChangeRef::This,
+        name => ChangeRef::Name(name.to_string()),
+    }
+}
+
+/// `uri` for `device`, or for the default device when `None`.
+fn for_device(uri: &str, device: Option<&str>) -> String {
+    match device {
+        Some(device) => {
+            let encoded: String = device.bytes().map(|byte| match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            }).collect();
+            format!("{}?device={}", uri, encoded)
+        },
+        None => uri.to_string(),
+    }
+}
+
+async fn request(socket: &Path, method: Method, uri: &str, body: Option<String>) -> anyhow::Result<String> {
+    let stream = UnixStream::connect(socket).await
+        .map_err(|e| anyhow::anyhow!("Cannot connect to {}: {}", socket.display(), e))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            eprintln!("Connection failed: {:?}", err);
+        }
+    });
+
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("http://qkeypie{}", uri))
+        .header(header::HOST, "qkeypie")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.map(Body::from).unwrap_or_else(Body::empty))?;
+
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let body = response.collect().await?.to_bytes();
+    let body = String::from_utf8(body.to_vec())?;
+    if !status.is_success() {
+        anyhow::bail!("{}: {}", status, body);
+    }
+    Ok(body)
+}
+
+pub async fn status(socket: &Path, device: Option<&str>) -> anyhow::Result<Status> {
+    Ok(serde_json::from_str(&request(socket, Method::GET, &for_device("/state", device), None).await?)?)
+}
+
+/// The status of every device the daemon drives, by name.
+pub async fn devices(socket: &Path) -> anyhow::Result<IndexMap<String, Status>> {
+    Ok(serde_json::from_str(&request(socket, Method::GET, "/devices", None).await?)?)
+}
+
+pub async fn goto(socket: &Path, device: Option<&str>, goto: GoTo) -> anyhow::Result<Status> {
+    let body = serde_json::to_string(&goto)?;
+    Ok(serde_json::from_str(&request(socket, Method::POST, &for_device("/goto", device), Some(body)).await?)?)
+}
+
+pub async fn run_actions(socket: &Path, device: Option<&str>, actions: Vec<Action>) -> anyhow::Result<Status> {
+    let body = serde_json::to_string(&actions)?;
+    Ok(serde_json::from_str(&request(socket, Method::POST, &for_device("/actions", device), Some(body)).await?)?)
+}
+
+pub async fn reload(socket: &Path) -> anyhow::Result<Status> {
+    Ok(serde_json::from_str(&request(socket, Method::POST, "/reload", None).await?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_refs() {
+        assert!(matches!(change_ref("next"), ChangeRef::Next));
+        assert!(matches!(change_ref("prev"), ChangeRef::Previous));
+        assert!(matches!(change_ref("previous"), ChangeRef::Previous));
+        assert!(matches!(change_ref("first"), ChangeRef::First));
+        assert!(matches!(change_ref("last"), ChangeRef::Last));
+        assert!(matches!(change_ref("this"), ChangeRef::This));
+        assert!(matches!(change_ref("games"), ChangeRef::Name(name) if name == "games"));
+        assert!(matches!(change_ref("Next"), ChangeRef::Name(name) if name == "Next"));
+    }
+
+    #[test]
+    fn device_names_are_escaped() {
+        assert_eq!(for_device("/state", None), "/state");
+        assert_eq!(for_device("/state", Some("left")), "/state?device=left");
+        assert_eq!(for_device("/state", Some("my pad&co")), "/state?device=my%20pad%26co");
+    }
+}
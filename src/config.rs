@@ -4,24 +4,95 @@ use indexmap::IndexMap;
 
 use serde::Serialize;
 use serde::Deserialize;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use xencelabs_quick_keys::ConnectionMode;
 
-use crate::actions::{Action, ButtonCallback, WheelSetCallback, ButtonSetCallback, ProfileCallback, ButtonId, WheelId, ButtonSetId, ProfileId, MacroId, ActiveCallback};
+use crate::focus::FocusRule;
+use crate::events::Timings;
+use crate::actions::{Action, ButtonCallback, WheelSetCallback, ButtonSetCallback, Shift, ProfileCallback, ButtonId, WheelId, ButtonSetId, ProfileId, MacroId, VariableId, Macro, ActiveCallback, Concurrency};
 
-type Actions = Option<Vec<Action>>;
+type Actions = Option<CallbackConfig>;
 
+/// A callback is a list of actions, or a table when it needs options:
+/// `on_press = { concurrency = "Cancel", actions = [ ... ] }`.
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum CallbackConfig {
+    Actions(Vec<Action>),
+    Options {
+        #[serde(default)]
+        concurrency: Concurrency,
+        actions: Vec<Action>,
+    },
+}
+
+// By the shape of the value rather than untagged, so a mistake in either form
+// is reported as such
+impl<'de> Deserialize<'de> for CallbackConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Options {
+            #[serde(default)]
+            concurrency: Concurrency,
+            actions: Vec<Action>,
+        }
+
+        struct CallbackVisitor;
+
+        impl<'de> Visitor<'de> for CallbackVisitor {
+            type Value = CallbackConfig;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of actions, or a table with concurrency and actions")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<CallbackConfig, A::Error> {
+                Ok(CallbackConfig::Actions(Vec::deserialize(de::value::SeqAccessDeserializer::new(seq))?))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<CallbackConfig, A::Error> {
+                let Options { concurrency, actions } = Options::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(CallbackConfig::Options { concurrency, actions })
+            }
+        }
 
-type ButtonSetConfig = ButtonSetCallback<Option<ButtonSetId>,Actions>;
-type ProfileConfig = ProfileCallback<Option<IndexMap<String, ButtonSetId>>, Option<IndexMap<String, WheelId>>, Option<ButtonId>, Actions>;
+        deserializer.deserialize_any(CallbackVisitor)
+    }
+}
+
+
+type ButtonSetConfig = ButtonSetCallback<Option<ButtonSetId>,Actions,Shift<Option<ButtonId>,Option<WheelId>>>;
+pub type ProfileConfig = ProfileCallback<Option<IndexMap<String, ButtonSetId>>, Option<IndexMap<String, WheelId>>, Option<ButtonId>, Actions>;
+
+/// Which Quick Keys a `[devices.NAME]` section is for, and what it shows.
+/// Whatever it leaves out comes from the `[device]` section.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub serial: Option<String>,
+    /// The hidraw node, like `/dev/hidraw3`
+    pub path: Option<String>,
+    pub connection: Option<ConnectionMode>,
+    /// All the profiles when missing
+    pub profiles: Option<Vec<ProfileId>>,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub server: Option<ActiveCallback<Actions>>,
-    pub macros: Option<IndexMap<MacroId, Actions>>,
+    pub device: Option<DeviceConfig>,
+    pub devices: Option<IndexMap<String, DeviceConfig>>,
+    pub macros: Option<IndexMap<MacroId, Macro>>,
+    /// The values variables start with
+    pub variables: Option<IndexMap<VariableId, toml::Value>>,
+    pub timings: Option<Timings>,
     pub buttons: Option<IndexMap<ButtonId, ButtonCallback<Actions>>>,
     pub wheels: Option<IndexMap<WheelId, WheelSetCallback<Actions>>>,
     pub buttonsets: Option<IndexMap<ButtonSetId, ButtonSetConfig>>,
     pub profiles: Option<IndexMap<ProfileId, ProfileConfig>>,
+    pub focus: Option<Vec<FocusRule>>,
 }
 
 pub fn read_config(filename: &str) -> anyhow::Result<Config> {
@@ -1,25 +1,151 @@
-use xencelabs_quick_keys::{QKDevice, ConnectionMode };
-
-use hidapi::HidApi;
 use std::{time, thread};
-use enigo::{
-    agent::Agent,
-    Enigo, Settings,
-};
-
-use crate::model::Model;
-use crate::actions::{Action, NonEnigoAction, ProfileButton, WhichButton};
-use crate::actions::{ButtonSet, WheelSet, ButtonCallback, WheelSetCallback, GoTo, ChangeRef};
+use std::sync::Arc;
+use enigo::agent;
+use indexmap::IndexMap;
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use xencelabs_quick_keys::QKError;
+
+use crate::model::{self, Model};
+use crate::actions::{Action, Condition, Conditional, NonEnigoAction, ProfileButton, WhichButton};
+use crate::actions::{ButtonSet, WheelSet, ButtonCallback, WheelSetCallback, GoTo, ChangeRef, Callback, Concurrency};
+use crate::executor::{Executor, Lane, Outcome};
 use crate::events::{ButtonState, WheelState, ButtonEvent, WheelEvent};
+use crate::device::Device;
+use crate::input::{HeldInput, Input};
 use crate::state;
+use crate::focus::{self, Window};
+use crate::chords;
+
+/// Things the outside world can ask a running controller to do.
+#[derive(Debug)]
+pub enum Command {
+    Status,
+    GoTo(GoTo),
+    Run(Vec<Action>),
+    Reload(Box<Model>),
+    /// Another window got the focus, which may switch by the focus rules
+    Focus(Window),
+    /// Leave the current state, run the server `on_exit` and stop `run`
+    Shutdown,
+}
+
+struct Request {
+    command: Command,
+    reply: oneshot::Sender<anyhow::Result<state::Status>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum DeviceEvent {
+    Button(WhichButton, ButtonEvent),
+    Wheel(WheelEvent),
+    /// A chord of the buttonset, by name
+    Chord(String),
+}
+
+/// A button or wheel event together with the device it came from and what was
+/// active on it when it happened.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub device: String,
+    pub event: DeviceEvent,
+    pub profile: String,
+    pub buttonset: String,
+    pub wheel: String,
+}
+
+// Slow subscribers start losing events past this many
+const EVENTS_CAPACITY: usize = 256;
+
+/// The sending side of the command queues of every controller, one per device.
+#[derive(Clone)]
+pub struct Handle {
+    // The first one is the default device
+    controllers: Arc<IndexMap<String, mpsc::UnboundedSender<Request>>>,
+    events: broadcast::Sender<EventRecord>,
+}
+
+/// The receiving side of a controller's command queue, consumed by `run`.
+pub struct Commands {
+    device: String,
+    rx: mpsc::UnboundedReceiver<Request>,
+    events: broadcast::Sender<EventRecord>,
+}
 
-fn eval(enigo: &mut Enigo, dev: &QKDevice, action: &Action, current_button: Option<WhichButton>) -> anyhow::Result<Option<GoTo>> {
+/// The queue of a single controller, driving `model::DEFAULT_DEVICE`.
+pub fn channel() -> (Handle, Commands) {
+    let (handle, mut commands) = channels(&[model::DEFAULT_DEVICE.to_string()]);
+    (handle, commands.remove(0))
+}
+
+/// One queue per device, all reachable through the same `Handle`.
+pub fn channels(devices: &[String]) -> (Handle, Vec<Commands>) {
+    let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+    let mut controllers = IndexMap::new();
+    let mut commands = Vec::new();
+    for device in devices {
+        let (tx, rx) = mpsc::unbounded_channel();
+        controllers.insert(device.clone(), tx);
+        commands.push(Commands { device: device.clone(), rx, events: events.clone() });
+    }
+    (Handle { controllers: Arc::new(controllers), events }, commands)
+}
+
+impl Commands {
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    fn publish(&self, state: &state::State, event: DeviceEvent) {
+        // Nobody listening is not an error
+        let _ = self.events.send(EventRecord {
+            device: self.device.clone(),
+            event,
+            profile: state.current_profile_id.clone(),
+            buttonset: state.current_buttonset_id.clone(),
+            wheel: state.current_wheel_id.clone(),
+        });
+    }
+
+    fn publish_buttons(&self, state: &state::State, button: WhichButton, events: &[ButtonEvent]) {
+        for event in events {
+            self.publish(state, DeviceEvent::Button(button.clone(), event.clone()));
+        }
+    }
+}
+
+impl Handle {
+    pub fn subscribe(&self) -> broadcast::Receiver<EventRecord> {
+        self.events.subscribe()
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &String> {
+        self.controllers.keys()
+    }
+
+    /// Ask the controller of the default device.
+    pub async fn request(&self, command: Command) -> anyhow::Result<state::Status> {
+        let device = self.controllers.keys().next().ok_or_else(|| anyhow::anyhow!("No devices"))?.clone();
+        self.request_device(&device, command).await
+    }
+
+    /// Ask the controller of `device`.
+    pub async fn request_device(&self, device: &str, command: Command) -> anyhow::Result<state::Status> {
+        let tx = self.controllers.get(device).ok_or_else(|| anyhow::anyhow!("Unknown device {}", device))?;
+        let (reply, response) = oneshot::channel();
+        tx.send(Request { command, reply }).map_err(|_| anyhow::anyhow!("Controller is not running"))?;
+        response.await.map_err(|_| anyhow::anyhow!("Controller is not running"))?
+    }
+}
+
+fn eval<D: Device, I: Input>(input: &mut I, dev: &D, state: &mut state::State, action: &Action, current_button: Option<WhichButton>) -> anyhow::Result<Option<GoTo>> {
     match action {
-        Action::NonEnigo(NonEnigoAction::Sleep(millis)) => {
-            thread::sleep(time::Duration::from_millis(*millis));
+        Action::NonEnigo(NonEnigoAction::Sleep(_)) => {
+            // The executor does the waiting, without holding up the device
             Ok(None)
         },
         Action::NonEnigo(NonEnigoAction::SetButtonText(wb, txt)) => {
+            let txt = &state.interpolate(txt);
             let res = match wb {
                 WhichButton::Button0 => dev.set_key_text(0, txt),
                 WhichButton::Button1 => dev.set_key_text(1, txt),
@@ -44,23 +170,29 @@ fn eval(enigo: &mut Enigo, dev: &QKDevice, action: &Action, current_button: Opti
             };
             match res {
                 Ok(_) => Ok(None),
-                Err(e) => anyhow::bail!("error: {:?}", e),
+                Err(e) => Err(e.into()),
             }
         },
         Action::NonEnigo(NonEnigoAction::SetWheelColor(r, g, b)) => {
             match dev.set_ring_color(*r, *g, *b) {
                 Ok(_) => Ok(None),
-                Err(e) => anyhow::bail!("error: {:?}", e),
+                Err(e) => Err(e.into()),
             }
         },
         Action::NonEnigo(NonEnigoAction::ShowBanner(seconds, txt)) => {
-            match dev.show_overlay_text(txt, *seconds) {
+            match dev.show_overlay_text(&state.interpolate(txt), *seconds) {
+                Ok(_) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        },
+        Action::Input(agent::Token::Text(text)) => {
+            match input.execute(&agent::Token::Text(state.interpolate(text))) {
                 Ok(_) => Ok(None),
                 Err(e) => anyhow::bail!("error: {:?}", e),
             }
         },
         Action::Input(token) => {
-            match enigo.execute(token) {
+            match input.execute(token) {
                 Ok(_) => Ok(None),
                 Err(e) => anyhow::bail!("error: {:?}", e),
             }
@@ -77,17 +209,38 @@ fn eval(enigo: &mut Enigo, dev: &QKDevice, action: &Action, current_button: Opti
         Action::NonEnigo(NonEnigoAction::Swap) => {
             Ok(Some(GoTo::Swap))
         },
+        Action::NonEnigo(NonEnigoAction::SetVariable(name, value)) => {
+            state.set_variable(name, value);
+            Ok(None)
+        },
+        Action::NonEnigo(NonEnigoAction::IncrementVariable(name, by)) => {
+            // A variable of the wrong type is a config mistake, not worth stopping for
+            if let Err(err) = state.increment_variable(name, *by) {
+                tracing::error!("{err:#}");
+            }
+            Ok(None)
+        },
+        Action::NonEnigo(NonEnigoAction::ToggleVariable(name)) => {
+            if let Err(err) = state.toggle_variable(name) {
+                tracing::error!("{err:#}");
+            }
+            Ok(None)
+        },
         Action::NonEnigo(NonEnigoAction::Macro(_)) => {
             anyhow::bail!("Macro action not resolved");
         },
+        Action::NonEnigo(NonEnigoAction::If(_)) => {
+            // `run_tasks` picks the branch
+            Ok(None)
+        },
         Action::NonEnigo(NonEnigoAction::Debug(txt)) => {
             println!("Debug: {}", txt);
             Ok(None)
         },
         Action::NonEnigo(NonEnigoAction::Run(args)) => {
-            let mut cmd = std::process::Command::new(&args[0]);
+            let mut cmd = std::process::Command::new(state.interpolate(&args[0]));
             for arg in &args[1..] {
-                cmd.arg(arg);
+                cmd.arg(state.interpolate(arg));
             }
             match cmd.spawn() {
                 Ok(_) => Ok(None),
@@ -97,361 +250,725 @@ fn eval(enigo: &mut Enigo, dev: &QKDevice, action: &Action, current_button: Opti
         Action::NonEnigo(NonEnigoAction::SetScreenOrientation(orientation)) => {
             match dev.set_screen_orientation(*orientation) {
                 Ok(_) => Ok(None),
-                Err(e) => anyhow::bail!("error: {:?}", e),
+                Err(e) => Err(e.into()),
             }
         },
         Action::NonEnigo(NonEnigoAction::SetScreenBrightness(brightness)) => {
             match dev.set_screen_brightness(*brightness) {
                 Ok(_) => Ok(None),
-                Err(e) => anyhow::bail!("error: {:?}", e),
+                Err(e) => Err(e.into()),
             }
         },
         Action::NonEnigo(NonEnigoAction::SetWheelSpeed(speed)) => {
             match dev.set_wheel_speed(*speed) {
                 Ok(_) => Ok(None),
-                Err(e) => anyhow::bail!("error: {:?}", e),
+                Err(e) => Err(e.into()),
             }
         },
         Action::NonEnigo(NonEnigoAction::SetSleepTimeout(minutes)) => {
             match dev.set_sleep_timeout(*minutes) {
                 Ok(_) => Ok(None),
-                Err(e) => anyhow::bail!("error: {:?}", e),
+                Err(e) => Err(e.into()),
             }
         },
     }
 }
 
-fn process_button_event(enigo: &mut Enigo, dev: &QKDevice, event: &ButtonEvent, callbacks: &ButtonCallback<Vec<Action>>, current_button: WhichButton) -> anyhow::Result<Option<GoTo>> {
-    match event {
-        ButtonEvent::OnPress => {
-            callbacks.on_press.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-            })
-        },
-        ButtonEvent::OnRelease => {
-            callbacks.on_release.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-            })
-        },
-        ButtonEvent::OnLongPress => {
-            callbacks.on_long_press.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-            })
+/// How long a `Condition::Command` may take before it counts as false.
+const CHECK_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// Whether `args` runs and exits with status 0 within `CHECK_TIMEOUT`.
+/// Failing to start or taking too long is a warning, not a reason to stop.
+fn check_command(args: &[String]) -> bool {
+    let Some((program, args)) = args.split_first() else {
+        tracing::warn!("empty command in condition");
+        return false;
+    };
+    let mut child = match std::process::Command::new(program).args(args).stdin(std::process::Stdio::null()).spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            tracing::warn!("condition command {program}: {err}");
+            return false;
         },
+    };
+    let deadline = time::Instant::now() + CHECK_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return status.success(),
+            Ok(None) if time::Instant::now() < deadline => thread::sleep(time::Duration::from_millis(10)),
+            Ok(None) => {
+                tracing::warn!("condition command {program} took longer than {:?}", CHECK_TIMEOUT);
+                let _ = child.kill();
+                let _ = child.wait();
+                return false;
+            },
+            Err(err) => {
+                tracing::warn!("condition command {program}: {err}");
+                return false;
+            },
+        }
+    }
+}
+
+/// Whether `condition` asks to run a command.
+fn has_command(condition: &Condition) -> bool {
+    match condition {
+        Condition::Command(_) => true,
+        Condition::All(conditions) | Condition::Any(conditions) => conditions.iter().any(has_command),
+        Condition::Not(condition) => has_command(condition),
+        _ => false,
+    }
+}
+
+fn test(state: &state::State, condition: &Condition) -> bool {
+    match condition {
+        Condition::Profile(profile) => state.current_profile_id == *profile,
+        Condition::ButtonSet(buttonset) => state.current_buttonset_id == *buttonset,
+        Condition::Wheel(wheel) => state.current_wheel_id == *wheel,
+        Condition::Command(args) => check_command(args),
+        Condition::Env(name) => std::env::var_os(name).is_some_and(|value| !value.is_empty()),
+        Condition::EnvEquals(name, value) => std::env::var(name).is_ok_and(|v| v == *value),
+        Condition::Variable(name) => state.is_set(name),
+        Condition::VariableEquals(name, value) => state.variables.get(name) == Some(value),
+        Condition::All(conditions) => conditions.iter().all(|condition| test(state, condition)),
+        Condition::Any(conditions) => conditions.iter().any(|condition| test(state, condition)),
+        Condition::Not(condition) => !test(state, condition),
+    }
+}
+
+/// The branch `conditional` takes.  One that runs commands is decided on a
+/// thread of its own, against the state as it is now, so the device goes
+/// on meanwhile.
+fn branch(state: &state::State, conditional: &Conditional) -> Outcome {
+    if !has_command(&conditional.when) {
+        let holds = test(state, &conditional.when);
+        return Outcome::Then(if holds { conditional.then.clone() } else { conditional.otherwise.clone() });
+    }
+    let (tx, rx) = std::sync::mpsc::channel();
+    let state = state.clone();
+    let conditional = conditional.clone();
+    thread::spawn(move || {
+        let holds = test(&state, &conditional.when);
+        // Nobody waits any more when the task was cancelled meanwhile
+        let _ = tx.send(if holds { conditional.then } else { conditional.otherwise });
+    });
+    Outcome::Wait(rx)
+}
+
+fn button_callback<'a>(event: &ButtonEvent, callbacks: &'a ButtonCallback<Callback>) -> Option<&'a Callback> {
+    match event {
+        ButtonEvent::OnPress => Some(&callbacks.on_press),
+        ButtonEvent::OnRelease => Some(&callbacks.on_release),
+        ButtonEvent::OnLongPress => Some(&callbacks.on_long_press),
+        ButtonEvent::OnHoldRepeat => Some(&callbacks.on_hold_repeat),
         ButtonEvent::OnClickPress(click_count) => {
             match click_count {
-                1 => {
-                    callbacks.on_click_press.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                2 => {
-                    callbacks.on_double_click_press.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                3 => {
-                    callbacks.on_triple_click_press.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                _ => {
-                    println!("Unknown click count {}", click_count);
-                    Ok(None)
-                }
+                1 => Some(&callbacks.on_click_press),
+                2 => Some(&callbacks.on_double_click_press),
+                3 => Some(&callbacks.on_triple_click_press),
+                // Only clicks have callbacks past three
+                _ => None,
             }
         },
         ButtonEvent::OnClick(click_count) => {
             match click_count {
-                1 => {
-                    callbacks.on_click.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                2 => {
-                    callbacks.on_double_click.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                3 => {
-                    callbacks.on_triple_click.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                _ => {
-                    println!("Unknown click count {}", click_count);
-                    Ok(None)
-                }
+                1 => Some(&callbacks.on_click),
+                2 => Some(&callbacks.on_double_click),
+                3 => Some(&callbacks.on_triple_click),
+                _ => callbacks.on_click_n.get(&click_count.to_string()),
             }
         },
         ButtonEvent::OnClickRelease(click_count) => {
             match click_count {
-                1 => {
-                    callbacks.on_click_release.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                2 => {
-                    callbacks.on_double_click_release.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                3 => {
-                    callbacks.on_triple_click_release.iter().try_fold(None, |acc, action| {
-                        eval(enigo, dev, action, Some(current_button.clone())).map(|opt_value| opt_value.or(acc))
-                    })
-                },
-                _ => {
-                    println!("Unknown click count {}", click_count);
-                    Ok(None)
-                }
+                1 => Some(&callbacks.on_click_release),
+                2 => Some(&callbacks.on_double_click_release),
+                3 => Some(&callbacks.on_triple_click_release),
+                _ => None,
             }
         },
     }
 }
 
-fn process_wheel_event(enigo: &mut Enigo, dev: &QKDevice, event: &WheelEvent, callbacks: &WheelSetCallback<Vec<Action>>) -> anyhow::Result<Option<GoTo>> {
+fn wheel_callback<'a>(event: &WheelEvent, callbacks: &'a WheelSetCallback<Callback>) -> &'a Callback {
     match event {
-        WheelEvent::OnRotateClockwiseStart => {
-            callbacks.wheel.on_clockwise_start.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, None).map(|opt_value| opt_value.or(acc))
-            })
-        },
-        WheelEvent::OnRotateClockwiseEnd => {
-            callbacks.wheel.on_clockwise_stop.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, None).map(|opt_value| opt_value.or(acc))
-            })
-        },
-        WheelEvent::OnRotateClockwiseStep => {
-            callbacks.wheel.on_clockwise.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, None).map(|opt_value| opt_value.or(acc))
-            })
-        },
-        WheelEvent::OnRotateCounterClockwiseStart => {
-            callbacks.wheel.on_counterclockwise_start.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, None).map(|opt_value| opt_value.or(acc))
-            })
-        },
-        WheelEvent::OnRotateCounterClockwiseEnd => {
-            callbacks.wheel.on_counterclockwise_stop.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, None).map(|opt_value| opt_value.or(acc))
-            })
-        },
-        WheelEvent::OnRotateCounterClockwiseStep => {
-            callbacks.wheel.on_counterclockwise.iter().try_fold(None, |acc, action| {
-                eval(enigo, dev, action, None).map(|opt_value| opt_value.or(acc))
-            })
-        },
+        WheelEvent::OnRotateClockwiseStart => &callbacks.wheel.on_clockwise_start,
+        WheelEvent::OnRotateClockwiseEnd => &callbacks.wheel.on_clockwise_stop,
+        WheelEvent::OnRotateClockwiseStep => &callbacks.wheel.on_clockwise,
+        WheelEvent::OnRotateCounterClockwiseStart => &callbacks.wheel.on_counterclockwise_start,
+        WheelEvent::OnRotateCounterClockwiseEnd => &callbacks.wheel.on_counterclockwise_stop,
+        WheelEvent::OnRotateCounterClockwiseStep => &callbacks.wheel.on_counterclockwise,
     }
 }
 
+/// The actions of `callback`, each paired with the button it runs for.
+fn tagged(callback: &Callback, button: Option<WhichButton>) -> Vec<(Action, Option<WhichButton>)> {
+    callback.actions.iter().map(|action| (action.clone(), button.clone())).collect()
+}
 
-fn process_buttonset_events(enigo: &mut Enigo, dev: &QKDevice, events: Vec<ButtonEvent>, callbacks: &ButtonCallback<Vec<Action>>, current_button: WhichButton) -> anyhow::Result<Option<GoTo>> {
-    events.iter().try_fold(None, |acc, event| {
-        process_button_event(enigo, dev, event, callbacks, current_button.clone()).map(|opt_value| opt_value.or(acc))
-    })
+fn process_buttonset_events(executor: &mut Executor, events: Vec<ButtonEvent>, callbacks: &ButtonCallback<Callback>, current_button: WhichButton) {
+    for event in &events {
+        if let Some(callback) = button_callback(event, callbacks) {
+            executor.submit(Lane::Button(current_button.clone()), callback.concurrency, tagged(callback, Some(current_button.clone())));
+        }
+    }
 }
 
-pub fn run(model: Model) -> anyhow::Result<()> {
-    let mut state = state::State::new(model)?;
+fn process_wheel_events(executor: &mut Executor, events: Vec<WheelEvent>, callbacks: &WheelSetCallback<Callback>) {
+    for event in &events {
+        let callback = wheel_callback(event, callbacks);
+        executor.submit(Lane::Wheel, callback.concurrency, tagged(callback, None));
+    }
+}
 
-    let mut enigo = Enigo::new(&Settings::default()).unwrap_or_else(|e| panic!("Failed to create enigo: {:?}", e));
-    let api = HidApi::new()?;
-    let dev = QKDevice::open(api, ConnectionMode::Auto)?;
+fn enter_state(executor: &mut Executor, state: &state::State) {
+    let current_profile = state.get_current_profile();
+    let current_buttonset = state.get_current_buttonset();
+    let current_buttons = state.get_active_buttons();
+    let current_wheel = state.get_active_wheel();
+    let mut actions = Vec::new();
+    actions.extend(tagged(&current_profile.active.on_enter, None));
+    actions.extend(tagged(&current_buttonset.active.on_enter, None));
+    actions.extend(tagged(&current_buttons.button0.active.on_enter, Some(WhichButton::Button0)));
+    actions.extend(tagged(&current_buttons.button1.active.on_enter, Some(WhichButton::Button1)));
+    actions.extend(tagged(&current_buttons.button2.active.on_enter, Some(WhichButton::Button2)));
+    actions.extend(tagged(&current_buttons.button3.active.on_enter, Some(WhichButton::Button3)));
+    actions.extend(tagged(&current_buttons.button4.active.on_enter, Some(WhichButton::Button4)));
+    actions.extend(tagged(&current_buttons.button5.active.on_enter, Some(WhichButton::Button5)));
+    actions.extend(tagged(&current_buttons.button6.active.on_enter, Some(WhichButton::Button6)));
+    actions.extend(tagged(&current_buttons.button7.active.on_enter, Some(WhichButton::Button7)));
+    actions.extend(tagged(&current_wheel.active.on_enter, None));
+    actions.extend(tagged(&state.get_active_wheel_button().active.on_enter, Some(WhichButton::WheelButton)));
+    executor.submit(Lane::Transition, Concurrency::Queue, actions);
+}
 
-    // Enter the initial state
-    {
-        for action in &state.model.server.on_enter {
-            eval(&mut enigo, &dev, action, None)?;
-        }
-        let current_profile = state.get_current_profile();
-        let current_buttonset = state.get_current_buttonset();
-        let current_wheel = state.get_current_wheel();
-        for action in &current_profile.active.on_enter {
-            eval(&mut enigo, &dev, action, None)?;
-        }
-        for action in &current_buttonset.active.on_enter {
-            eval(&mut enigo, &dev, action, None)?;
-        }
-        for action in &current_buttonset.buttonset.button0.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button0))?;
-        }
-        for action in &current_buttonset.buttonset.button1.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button1))?;
-        }
-        for action in &current_buttonset.buttonset.button2.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button2))?;
-        }
-        for action in &current_buttonset.buttonset.button3.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button3))?;
-        }
-        for action in &current_buttonset.buttonset.button4.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button4))?;
-        }
-        for action in &current_buttonset.buttonset.button5.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button5))?;
-        }
-        for action in &current_buttonset.buttonset.button6.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button6))?;
+fn exit_state(executor: &mut Executor, state: &state::State) {
+    let current_profile = state.get_current_profile();
+    let current_buttonset = state.get_current_buttonset();
+    let current_buttons = state.get_active_buttons();
+    let current_wheel = state.get_active_wheel();
+    let mut actions = Vec::new();
+    actions.extend(tagged(&state.get_active_wheel_button().active.on_exit, Some(WhichButton::WheelButton)));
+    actions.extend(tagged(&current_wheel.active.on_exit, None));
+    actions.extend(tagged(&current_buttons.button0.active.on_exit, Some(WhichButton::Button0)));
+    actions.extend(tagged(&current_buttons.button1.active.on_exit, Some(WhichButton::Button1)));
+    actions.extend(tagged(&current_buttons.button2.active.on_exit, Some(WhichButton::Button2)));
+    actions.extend(tagged(&current_buttons.button3.active.on_exit, Some(WhichButton::Button3)));
+    actions.extend(tagged(&current_buttons.button4.active.on_exit, Some(WhichButton::Button4)));
+    actions.extend(tagged(&current_buttons.button5.active.on_exit, Some(WhichButton::Button5)));
+    actions.extend(tagged(&current_buttons.button6.active.on_exit, Some(WhichButton::Button6)));
+    actions.extend(tagged(&current_buttons.button7.active.on_exit, Some(WhichButton::Button7)));
+    actions.extend(tagged(&current_buttonset.active.on_exit, None));
+    actions.extend(tagged(&current_profile.button.active.on_exit, Some(WhichButton::ButtonExtra)));
+    actions.extend(tagged(&current_profile.active.on_exit, None));
+    executor.submit(Lane::Transition, Concurrency::Queue, actions);
+}
+
+fn switch_state(executor: &mut Executor, state: &state::State, goto: GoTo) -> anyhow::Result<state::State> {
+    let new_state = state.process_goto(goto)?;
+    let current_profile = state.get_current_profile();
+    let current_buttonset = state.get_current_buttonset();
+    let current_buttons = state.get_active_buttons();
+    let current_wheel = state.get_active_wheel();
+    println!("current_profile_id: {}, current_buttonset_id: {}, current_wheel_id: {}", new_state.current_profile_id, new_state.current_buttonset_id, new_state.current_wheel_id);
+    let mut actions = Vec::new();
+    if new_state.current_profile_id != state.current_profile_id {
+        actions.extend(tagged(&current_profile.active.on_exit, None));
+        actions.extend(tagged(&current_profile.button.active.on_exit, Some(WhichButton::ButtonExtra)));
+        actions.extend(tagged(&new_state.get_current_profile().button.active.on_enter, Some(WhichButton::ButtonExtra)));
+        actions.extend(tagged(&new_state.get_current_profile().active.on_enter, None));
+    }
+    if new_state.current_profile_id != state.current_profile_id || new_state.current_buttonset_id != state.current_buttonset_id {
+        actions.extend(tagged(&current_buttons.button0.active.on_exit, Some(WhichButton::Button0)));
+        actions.extend(tagged(&current_buttons.button1.active.on_exit, Some(WhichButton::Button1)));
+        actions.extend(tagged(&current_buttons.button2.active.on_exit, Some(WhichButton::Button2)));
+        actions.extend(tagged(&current_buttons.button3.active.on_exit, Some(WhichButton::Button3)));
+        actions.extend(tagged(&current_buttons.button4.active.on_exit, Some(WhichButton::Button4)));
+        actions.extend(tagged(&current_buttons.button5.active.on_exit, Some(WhichButton::Button5)));
+        actions.extend(tagged(&current_buttons.button6.active.on_exit, Some(WhichButton::Button6)));
+        actions.extend(tagged(&current_buttons.button7.active.on_exit, Some(WhichButton::Button7)));
+        actions.extend(tagged(&current_buttonset.active.on_exit, None));
+
+        actions.extend(tagged(&new_state.get_current_buttonset().active.on_enter, None));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button0.active.on_enter, Some(WhichButton::Button0)));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button1.active.on_enter, Some(WhichButton::Button1)));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button2.active.on_enter, Some(WhichButton::Button2)));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button3.active.on_enter, Some(WhichButton::Button3)));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button4.active.on_enter, Some(WhichButton::Button4)));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button5.active.on_enter, Some(WhichButton::Button5)));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button6.active.on_enter, Some(WhichButton::Button6)));
+        actions.extend(tagged(&new_state.get_current_buttonset().buttonset.button7.active.on_enter, Some(WhichButton::Button7)));
+    }
+    if new_state.current_profile_id != state.current_profile_id || new_state.current_wheel_id != state.current_wheel_id {
+        actions.extend(tagged(&state.get_active_wheel_button().active.on_exit, Some(WhichButton::WheelButton)));
+        actions.extend(tagged(&current_wheel.active.on_exit, None));
+        actions.extend(tagged(&new_state.get_active_wheel().active.on_enter, None));
+        actions.extend(tagged(&new_state.get_active_wheel_button().active.on_enter, Some(WhichButton::WheelButton)));
+    }
+    executor.submit(Lane::Transition, Concurrency::Queue, actions);
+    Ok(new_state)
+}
+
+/// Turn the shift layer of the buttonset on or off.  Only the buttons and
+/// wheel it has change, running their `on_exit` and `on_enter` for the labels.
+fn shift(executor: &mut Executor, state: &mut state::State, on: bool) {
+    let Some(layer) = state.get_current_buttonset().shift.clone() else { return };
+    state.shifted = on;
+    let buttonset = &state.get_current_buttonset().buttonset;
+    let current_wheel = state.get_current_wheel();
+    let mut actions = Vec::new();
+    let buttons = [
+        (WhichButton::Button0, &buttonset.button0, &layer.buttonset.button0),
+        (WhichButton::Button1, &buttonset.button1, &layer.buttonset.button1),
+        (WhichButton::Button2, &buttonset.button2, &layer.buttonset.button2),
+        (WhichButton::Button3, &buttonset.button3, &layer.buttonset.button3),
+        (WhichButton::Button4, &buttonset.button4, &layer.buttonset.button4),
+        (WhichButton::Button5, &buttonset.button5, &layer.buttonset.button5),
+        (WhichButton::Button6, &buttonset.button6, &layer.buttonset.button6),
+        (WhichButton::Button7, &buttonset.button7, &layer.buttonset.button7),
+    ];
+    for (which, button, layer_button) in buttons {
+        let Some(layer_button) = layer_button.as_ref().filter(|_| layer.hold != which) else { continue };
+        let (from, to) = if on { (button, layer_button) } else { (layer_button, button) };
+        actions.extend(tagged(&from.active.on_exit, Some(which.clone())));
+        actions.extend(tagged(&to.active.on_enter, Some(which)));
+    }
+    if let Some(layer_wheel) = &layer.wheel {
+        let (from, to) = if on { (current_wheel, layer_wheel) } else { (layer_wheel, current_wheel) };
+        let button = layer.hold != WhichButton::WheelButton;
+        if button {
+            actions.extend(tagged(&from.wheel.button.active.on_exit, Some(WhichButton::WheelButton)));
         }
-        for action in &current_buttonset.buttonset.button7.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::Button7))?;
+        actions.extend(tagged(&from.active.on_exit, None));
+        actions.extend(tagged(&to.active.on_enter, None));
+        if button {
+            actions.extend(tagged(&to.wheel.button.active.on_enter, Some(WhichButton::WheelButton)));
         }
-        for action in &current_wheel.active.on_enter {
-            eval(&mut enigo, &dev, action, None)?;
+    }
+    executor.submit(Lane::Transition, Concurrency::Queue, actions);
+}
+
+/// Run whatever is ready in the executor, switching state when asked to.
+fn run_tasks<D: Device, I: Input>(executor: &mut Executor, input: &mut I, dev: &D, state: &mut state::State) -> anyhow::Result<()> {
+    loop {
+        let gotos = executor.step(time::Instant::now(), |action, button| match action {
+            Action::NonEnigo(NonEnigoAction::If(conditional)) => Ok(branch(state, conditional)),
+            _ => Ok(eval(input, dev, state, action, button)?.map_or(Outcome::Done, Outcome::GoTo)),
+        })?;
+        if gotos.is_empty() {
+            return Ok(());
         }
-        for action in &current_wheel.wheel.button.active.on_enter {
-            eval(&mut enigo, &dev, action, Some(WhichButton::WheelButton))?;
+        for (lane, goto) in gotos {
+            // Entering a state does not move on to another one
+            if lane != Lane::Transition {
+                *state = switch_state(executor, state, goto)?;
+            }
         }
     }
+}
 
+/// Run the `on_exit` callbacks to the end and leave the device blank.
+fn shutdown<D: Device, I: Input>(executor: &mut Executor, input: &mut HeldInput<I>, dev: &D, state: &mut state::State) -> anyhow::Result<()> {
+    // Whatever was still running is left unfinished, only the exit callbacks run to the end
+    executor.cancel_all();
+    exit_state(executor, state);
+    executor.submit(Lane::Transition, Concurrency::Queue, tagged(&state.model.server.on_exit, None));
     loop {
-        let ev = dev.read_timeout(100)?;
-        let buttonset_event : ButtonSet<ButtonState> = ev.into();
-        let wheel_event : WheelSet<WheelState, ButtonState> = ev.into();
-        let profilebutton_event : ProfileButton<ButtonState> = ev.into();
+        run_tasks(executor, input, dev, state)?;
+        match executor.next_wake() {
+            Some(wake_at) => thread::sleep(wake_at.saturating_duration_since(time::Instant::now())),
+            None => break,
+        }
+    }
 
-        let now = time::Instant::now();
-        let (new_buttonset_state, buttonset_events) = state.buttonset_state.transition(buttonset_event, now);
-        let (new_wheel_state, wheel_events) = state.wheel_state.transition(wheel_event, now);
-        let (new_profilebutton_state, profilebutton_events) = state.profilebutton_state.transition(profilebutton_event, now);
+    if let Err(e) = input.release_all() {
+        anyhow::bail!("error: {:?}", e);
+    }
+    for key in 0..8 {
+        dev.set_key_text(key, "")?;
+    }
+    dev.set_ring_color(0, 0, 0)?;
+    Ok(())
+}
 
-        state.buttonset_state = new_buttonset_state;
-        state.wheel_state = new_wheel_state;
-        state.profilebutton_state = new_profilebutton_state;
+fn process_command<D: Device, I: Input>(executor: &mut Executor, input: &mut I, dev: &D, state: &mut state::State, command: Command) -> anyhow::Result<state::Status> {
+    match command {
+        Command::Status => Ok(state.status()),
+        Command::GoTo(goto) => {
+            *state = switch_state(executor, state, goto)?;
+            run_tasks(executor, input, dev, state)?;
+            Ok(state.status())
+        },
+        Command::Run(actions) => {
+            let actions = model::replace_macros(&actions, &state.model.macros)?;
+            executor.submit(Lane::Api, Concurrency::Queue, actions.into_iter().map(|action| (action, None)).collect());
+            run_tasks(executor, input, dev, state)?;
+            Ok(state.status())
+        },
+        Command::Reload(model) => {
+            let new_state = state.reload(*model)?;
+            exit_state(executor, state);
+            enter_state(executor, &new_state);
+            *state = new_state;
+            run_tasks(executor, input, dev, state)?;
+            Ok(state.status())
+        },
+        Command::Focus(window) => {
+            if let Some(goto) = focus::goto(state, &window) {
+                *state = switch_state(executor, state, goto)?;
+                run_tasks(executor, input, dev, state)?;
+            }
+            Ok(state.status())
+        },
+        // Handled by `run`, which has to stop afterwards
+        Command::Shutdown => Ok(state.status()),
+    }
+}
 
-        let current_profile = state.get_current_profile();
-        let current_buttonset = state.get_current_buttonset();
-        let current_wheel = state.get_current_wheel();
+// How long to wait for the device when nothing is sleeping
+const READ_TIMEOUT_MILLIS: u128 = 100;
 
-        let mut final_goto = None;
+// How often to look for the device while it is away
+const RECONNECT_INTERVAL: time::Duration = time::Duration::from_secs(1);
 
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button0, &current_buttonset.buttonset.button0, WhichButton::Button0)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button1, &current_buttonset.buttonset.button1, WhichButton::Button1)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button2, &current_buttonset.buttonset.button2, WhichButton::Button2)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button3, &current_buttonset.buttonset.button3, WhichButton::Button3)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button4, &current_buttonset.buttonset.button4, WhichButton::Button4)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button5, &current_buttonset.buttonset.button5, WhichButton::Button5)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button6, &current_buttonset.buttonset.button6, WhichButton::Button6)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, buttonset_events.button7, &current_buttonset.buttonset.button7, WhichButton::Button7)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, wheel_events.wheel_button, &current_wheel.wheel.button, WhichButton::WheelButton)? {
-            final_goto = Some(goto);
-        }
-        if let Some(goto) = wheel_events.wheel.iter().try_fold(None, |_, event| {
-            process_wheel_event(&mut enigo, &dev, event, current_wheel)
-        })? {
-            final_goto = Some(goto);
-        }
+/// Whether `err` came from the device, which means it is gone.  `eval` keeps
+/// device errors as `QKError`s for this.
+fn is_device_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<QKError>().is_some()
+}
 
-        if let Some(goto) = process_buttonset_events(&mut enigo, &dev, profilebutton_events.button, &current_profile.button, WhichButton::ThisButton)? {
-            final_goto = Some(goto);
-        }
+/// Forget everything that was going on with a device that went away.  Running
+/// callbacks are dropped, held keys released and every button is up again, but
+/// the profile, buttonset and wheel stay where they were.
+fn disconnect<I: Input>(executor: &mut Executor, input: &mut HeldInput<I>, state: &mut state::State, device: &str, err: &anyhow::Error) {
+    tracing::warn!("device {device} lost: {err:#}");
+    executor.cancel_all();
+    if let Err(e) = input.release_all() {
+        tracing::error!("error: {:?}", e);
+    }
+    state.buttonset_state = Default::default();
+    state.wheel_state = Default::default();
+    state.profilebutton_state = Default::default();
+    state.chords = Default::default();
+    state.shifted = false;
+    state.shifted_presses.clear();
+}
 
-        if let Some(goto) = final_goto.clone() {
-            let new_state = state.process_goto(goto)?;
-            println!("current_profile_id: {}, current_buttonset_id: {}, current_wheel_id: {}", new_state.current_profile_id, new_state.current_buttonset_id, new_state.current_wheel_id);
-            if new_state.current_profile_id != state.current_profile_id {
-                for action in &current_profile.active.on_exit {
-                    eval(&mut enigo, &dev, action, None)?;
-                }
-                for action in &current_profile.button.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::ButtonExtra))?;
-                }
-                for action in &new_state.get_current_profile().button.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::ButtonExtra))?;
-                }
-                for action in &new_state.get_current_profile().active.on_enter {
-                    eval(&mut enigo, &dev, action, None)?;
-                }
+/// Bring a device that was just connected to where the `State` is.
+fn connect_state(executor: &mut Executor, state: &state::State) {
+    // The device forgets everything when it goes away, so the server setup runs every time
+    executor.submit(Lane::Transition, Concurrency::Queue, tagged(&state.model.server.on_enter, None));
+    enter_state(executor, state);
+}
+
+/// What commands can do while there is no device.  Moving the `State` still
+/// works, it is shown once the device is back.
+fn process_command_disconnected(state: &mut state::State, command: Command) -> anyhow::Result<state::Status> {
+    match command {
+        Command::Status => Ok(state.status()),
+        Command::GoTo(goto) => {
+            *state = state.process_goto(goto)?;
+            Ok(state.status())
+        },
+        Command::Run(_) => anyhow::bail!("Device is not connected"),
+        Command::Reload(model) => {
+            *state = state.reload(*model)?;
+            Ok(state.status())
+        },
+        Command::Focus(window) => {
+            if let Some(goto) = focus::goto(state, &window) {
+                *state = state.process_goto(goto)?;
             }
-            if new_state.current_profile_id != state.current_profile_id || new_state.current_buttonset_id != state.current_buttonset_id {
-                for action in &current_buttonset.buttonset.button0.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button0))?;
-                }
-                for action in &current_buttonset.buttonset.button1.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button1))?;
-                }
-                for action in &current_buttonset.buttonset.button2.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button2))?;
-                }
-                for action in &current_buttonset.buttonset.button3.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button3))?;
-                }
-                for action in &current_buttonset.buttonset.button4.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button4))?;
-                }
-                for action in &current_buttonset.buttonset.button5.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button5))?;
-                }
-                for action in &current_buttonset.buttonset.button6.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button6))?;
-                }
-                for action in &current_buttonset.buttonset.button7.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button7))?;
-                }
-                for action in &current_buttonset.active.on_exit {
-                    eval(&mut enigo, &dev, action, None)?;
-                }
+            Ok(state.status())
+        },
+        // Handled by `run`, which has to stop afterwards
+        Command::Shutdown => Ok(state.status()),
+    }
+}
 
-                for action in &new_state.get_current_buttonset().active.on_enter {
-                    eval(&mut enigo, &dev, action, None)?;
-                }
-                for action in &new_state.get_current_buttonset().buttonset.button0.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button0))?;
-                }
-                for action in &new_state.get_current_buttonset().buttonset.button1.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button1))?;
-                }
-                for action in &new_state.get_current_buttonset().buttonset.button2.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button2))?;
-                }
-                for action in &new_state.get_current_buttonset().buttonset.button3.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button3))?;
-                }
-                for action in &new_state.get_current_buttonset().buttonset.button4.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button4))?;
-                }
-                for action in &new_state.get_current_buttonset().buttonset.button5.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button5))?;
+/// Read what the device has to say and run the callbacks for it.
+fn poll_device<D: Device, I: Input>(executor: &mut Executor, input: &mut I, dev: &D, state: &mut state::State, commands: &Commands) -> anyhow::Result<()> {
+    // Wake up in time for the next sleeping task, or the next long press, hold repeat and such.
+    // Rounding up, as waking up early only makes for another wait.
+    let timeout = executor.next_wake().into_iter().chain(state.next_deadline()).min()
+        .map(|wake_at| wake_at.saturating_duration_since(time::Instant::now()).as_micros().div_ceil(1000).min(READ_TIMEOUT_MILLIS))
+        .unwrap_or(READ_TIMEOUT_MILLIS);
+    let ev = dev.read_timeout(timeout as i32)?;
+    let buttonset_event : ButtonSet<ButtonState> = ev.into();
+    let wheel_event : WheelSet<WheelState, ButtonState> = ev.into();
+    let profilebutton_event : ProfileButton<ButtonState> = ev.into();
+
+    let hold = state.get_current_buttonset().shift.as_ref().map(|shift| shift.hold.clone());
+    let was_held = hold.as_ref().is_some_and(|hold| state.is_held(hold));
+
+    let now = time::Instant::now();
+    let (new_buttonset_state, buttonset_events) = state.buttonset_state.transition(buttonset_event, now, &state.buttonset_timings());
+    let (new_wheel_state, wheel_events) = state.wheel_state.transition(wheel_event, now, &state.wheel_timings());
+    let (new_profilebutton_state, profilebutton_events) = state.profilebutton_state.transition(profilebutton_event, now, &state.profilebutton_timings());
+
+    state.buttonset_state = new_buttonset_state;
+    state.wheel_state = new_wheel_state;
+    state.profilebutton_state = new_profilebutton_state;
+
+    // The layer goes on and off with the button, before the other buttons in the same read
+    let held = hold.as_ref().is_some_and(|hold| state.is_held(hold));
+    if held != was_held && held != state.shifted {
+        shift(executor, state, held);
+    }
+
+    let chords = chords::masks(&state.get_current_buttonset().chords);
+    let (chord, buttonset_events) = state.chords.filter(&chords, &state.buttonset_state, buttonset_events, now, state.model.timings.chord_window());
+    // What a chord holds back or swallows is only told about when, and if, it runs
+    if let Some(chord) = &chord {
+        commands.publish(state, DeviceEvent::Chord(chord.clone()));
+    }
+    commands.publish_buttons(state, WhichButton::Button0, &buttonset_events.button0);
+    commands.publish_buttons(state, WhichButton::Button1, &buttonset_events.button1);
+    commands.publish_buttons(state, WhichButton::Button2, &buttonset_events.button2);
+    commands.publish_buttons(state, WhichButton::Button3, &buttonset_events.button3);
+    commands.publish_buttons(state, WhichButton::Button4, &buttonset_events.button4);
+    commands.publish_buttons(state, WhichButton::Button5, &buttonset_events.button5);
+    commands.publish_buttons(state, WhichButton::Button6, &buttonset_events.button6);
+    commands.publish_buttons(state, WhichButton::Button7, &buttonset_events.button7);
+    commands.publish_buttons(state, WhichButton::WheelButton, &wheel_events.wheel_button);
+    commands.publish_buttons(state, WhichButton::ButtonExtra, &profilebutton_events.button);
+    for event in &wheel_events.wheel {
+        commands.publish(state, DeviceEvent::Wheel(event.clone()));
+    }
+
+    // Each button goes on with the layer that got its press, even after the shift is let go
+    state.route_presses(WhichButton::Button0, &buttonset_events.button0);
+    state.route_presses(WhichButton::Button1, &buttonset_events.button1);
+    state.route_presses(WhichButton::Button2, &buttonset_events.button2);
+    state.route_presses(WhichButton::Button3, &buttonset_events.button3);
+    state.route_presses(WhichButton::Button4, &buttonset_events.button4);
+    state.route_presses(WhichButton::Button5, &buttonset_events.button5);
+    state.route_presses(WhichButton::Button6, &buttonset_events.button6);
+    state.route_presses(WhichButton::Button7, &buttonset_events.button7);
+    state.route_presses(WhichButton::WheelButton, &wheel_events.wheel_button);
+
+    let current_profile = state.get_current_profile();
+    let current_buttonset = state.get_current_buttonset();
+    let current_buttons = state.get_pressed_buttons();
+    let current_wheel = state.get_active_wheel();
+
+    if let Some(callback) = chord.and_then(|chord| current_buttonset.chords.get(&chord)) {
+        executor.submit(Lane::Chord, callback.concurrency, tagged(callback, None));
+    }
+    process_buttonset_events(executor, buttonset_events.button0, current_buttons.button0, WhichButton::Button0);
+    process_buttonset_events(executor, buttonset_events.button1, current_buttons.button1, WhichButton::Button1);
+    process_buttonset_events(executor, buttonset_events.button2, current_buttons.button2, WhichButton::Button2);
+    process_buttonset_events(executor, buttonset_events.button3, current_buttons.button3, WhichButton::Button3);
+    process_buttonset_events(executor, buttonset_events.button4, current_buttons.button4, WhichButton::Button4);
+    process_buttonset_events(executor, buttonset_events.button5, current_buttons.button5, WhichButton::Button5);
+    process_buttonset_events(executor, buttonset_events.button6, current_buttons.button6, WhichButton::Button6);
+    process_buttonset_events(executor, buttonset_events.button7, current_buttons.button7, WhichButton::Button7);
+    process_buttonset_events(executor, wheel_events.wheel_button, state.get_pressed_wheel_button(), WhichButton::WheelButton);
+    process_wheel_events(executor, wheel_events.wheel, current_wheel);
+    process_buttonset_events(executor, profilebutton_events.button, &current_profile.button, WhichButton::ThisButton);
+
+    run_tasks(executor, input, dev, state)
+}
+
+/// Drive the device returned by `connect` with `model` until a
+/// `Command::Shutdown` arrives or something fails.  When the device goes away
+/// `connect` is tried again every `RECONNECT_INTERVAL`, and the current state
+/// is shown again once it is back.
+pub fn run<D: Device, I: Input>(model: Model, mut connect: impl FnMut() -> anyhow::Result<D>, input: I, mut commands: Commands) -> anyhow::Result<()> {
+    let mut state = state::State::new(model)?;
+    let mut input = HeldInput::new(input);
+    let mut executor = Executor::new();
+    let mut dev: Option<D> = None;
+    let mut next_attempt = time::Instant::now();
+    let mut waiting = false;
+
+    loop {
+        match &dev {
+            Some(connected) => {
+                if let Err(err) = poll_device(&mut executor, &mut input, connected, &mut state, &commands) {
+                    if !is_device_error(&err) {
+                        return Err(err);
+                    }
+                    disconnect(&mut executor, &mut input, &mut state, &commands.device, &err);
+                    dev = None;
                 }
-                for action in &new_state.get_current_buttonset().buttonset.button6.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button6))?;
+            },
+            None if time::Instant::now() >= next_attempt => {
+                match connect() {
+                    Ok(new_dev) => {
+                        tracing::info!("device {} connected", commands.device);
+                        waiting = false;
+                        connect_state(&mut executor, &state);
+                        match run_tasks(&mut executor, &mut input, &new_dev, &mut state) {
+                            Ok(()) => dev = Some(new_dev),
+                            Err(err) if is_device_error(&err) => disconnect(&mut executor, &mut input, &mut state, &commands.device, &err),
+                            Err(err) => return Err(err),
+                        }
+                    },
+                    Err(err) => {
+                        if !waiting {
+                            tracing::warn!("waiting for device {}: {err:#}", commands.device);
+                            waiting = true;
+                        }
+                    },
                 }
-                for action in &new_state.get_current_buttonset().buttonset.button7.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::Button7))?;
+                next_attempt = time::Instant::now() + RECONNECT_INTERVAL;
+            },
+            None => thread::sleep(time::Duration::from_millis(READ_TIMEOUT_MILLIS as u64)),
+        }
+
+        while let Ok(Request { command, reply }) = commands.rx.try_recv() {
+            let Some(connected) = &dev else {
+                if let Command::Shutdown = command {
+                    // Nothing to run the exit callbacks on
+                    let result = input.release_all().map_err(|e| anyhow::anyhow!("error: {:?}", e));
+                    let _ = reply.send(Ok(state.status()));
+                    return result;
                 }
+                let _ = reply.send(process_command_disconnected(&mut state, command));
+                continue;
+            };
+            if let Command::Shutdown = command {
+                let result = shutdown(&mut executor, &mut input, connected, &mut state);
+                let _ = reply.send(Ok(state.status()));
+                return result;
             }
-            if new_state.current_profile_id != state.current_profile_id || new_state.current_wheel_id != state.current_wheel_id {
-                for action in &current_wheel.wheel.button.active.on_exit {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::WheelButton))?;
-                }
-                for action in &current_wheel.active.on_exit {
-                    eval(&mut enigo, &dev, action, None)?;
-                }
-                for action in &new_state.get_current_wheel().active.on_enter {
-                    eval(&mut enigo, &dev, action, None)?;
-                }
-                for action in &new_state.get_current_wheel().wheel.button.active.on_enter {
-                    eval(&mut enigo, &dev, action, Some(WhichButton::WheelButton))?;
+            // Errors go back to whoever asked instead of stopping the daemon
+            let result = process_command(&mut executor, &mut input, connected, &mut state, command);
+            if let Err(err) = &result {
+                if is_device_error(err) {
+                    disconnect(&mut executor, &mut input, &mut state, &commands.device, err);
+                    dev = None;
                 }
             }
-            state = new_state;
+            let _ = reply.send(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::FakeDevice;
+    use crate::input::RecordingInput;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// A controller running `config` on a fake device, in a thread of its own.
+    fn start(config: &str) -> (FakeDevice, RecordingInput, Handle, thread::JoinHandle<anyhow::Result<()>>) {
+        let model = model::from_config(toml::from_str(config).unwrap()).unwrap();
+        model::validate(&model).unwrap();
+        let dev = FakeDevice::new();
+        let input = RecordingInput::new();
+        let (handle, commands) = channel();
+        let (fake, recording) = (dev.clone(), input.clone());
+        let controller = thread::spawn(move || run(model, || Ok(fake.connect()?), recording, commands));
+        (dev, input, handle, controller)
+    }
+
+    fn request(handle: &Handle, command: Command) -> anyhow::Result<state::Status> {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(handle.request(command))
+    }
+
+    /// Wait for `done`, failing after a few reconnect attempts.
+    fn wait_for(what: &str, done: impl Fn() -> bool) {
+        let deadline = time::Instant::now() + RECONNECT_INTERVAL * 5;
+        while !done() {
+            assert!(time::Instant::now() < deadline, "timed out waiting for {what}");
+            thread::sleep(time::Duration::from_millis(10));
         }
     }
+
+    const CONFIG: &str = r#"
+        [buttons.hello]
+        on_enter = [{ SetButtonText = ["ThisButton", "Hello"] }]
+        on_press = [{ Text = "hello" }]
+        [buttons.other]
+        on_enter = [{ SetButtonText = ["ThisButton", "Other"] }]
+        on_press = [{ Text = "other" }]
+
+        [buttonsets.hello]
+        button0 = "hello"
+        [buttonsets.other]
+        button0 = "other"
+
+        [wheels.green]
+        on_enter = [{ SetWheelColor = [0, 255, 0] }]
+
+        [profiles.main.buttonsets]
+        hello = "hello"
+        other = "other"
+        [profiles.main.wheels]
+        green = "green"
+    "#;
+
+    #[test]
+    fn redraws_the_current_state_on_reconnect() {
+        let (dev, input, handle, controller) = start(CONFIG);
+        wait_for("the first draw", || dev.display().key_text[0] == "Hello" && dev.display().ring_color == (0, 255, 0));
+
+        dev.set_connected(false);
+        // Whether reading or this write notices it first, the device is gone after it
+        assert!(request(&handle, Command::Run(vec![Action::NonEnigo(NonEnigoAction::SetWheelColor(1, 2, 3))])).is_err());
+        let err = request(&handle, Command::Run(vec![Action::Input(agent::Token::Text("lost".to_string()))])).unwrap_err();
+        assert_eq!(err.to_string(), "Device is not connected");
+        dev.set_button(&WhichButton::Button0, true);
+        // Moving still works, and is shown once the device is back
+        let status = request(&handle, Command::GoTo(GoTo::Switch(ChangeRef::This, ChangeRef::Name("other".to_string()), ChangeRef::This))).unwrap();
+        assert_eq!(status.buttonset.id, "other");
+        thread::sleep(time::Duration::from_millis(200));
+        assert!(input.tokens().is_empty());
+        assert_eq!(dev.display().key_text[0], "");
+
+        dev.set_connected(true);
+        wait_for("the redraw", || dev.display().key_text[0] == "Other" && dev.display().ring_color == (0, 255, 0));
+        dev.set_button(&WhichButton::Button0, true);
+        wait_for("the press", || !input.tokens().is_empty());
+        assert_eq!(input.tokens(), [agent::Token::Text("other".to_string())]);
+
+        request(&handle, Command::Shutdown).unwrap();
+        controller.join().unwrap().unwrap();
+        assert_eq!(dev.display().key_text[0], "");
+    }
+
+    #[test]
+    fn shutdown_leaves_the_shift_layer() {
+        let (dev, input, handle, controller) = start(r#"
+            [buttons.base]
+            on_exit = [{ Text = "base off" }]
+            [buttons.layer]
+            on_enter = [{ Text = "layer on" }]
+            on_exit = [{ Text = "layer off" }]
+            [buttons.shift]
+            [buttonsets.main]
+            button0 = "base"
+            button7 = "shift"
+            shift = { hold = "Button7", button0 = "layer" }
+            [wheels.main]
+            [profiles.main.buttonsets]
+            main = "main"
+            [profiles.main.wheels]
+            main = "main"
+        "#);
+        dev.set_button(&WhichButton::Button7, true);
+        wait_for("the shift", || input.tokens().len() == 2);
+
+        request(&handle, Command::Shutdown).unwrap();
+        controller.join().unwrap().unwrap();
+        let text = |text: &str| agent::Token::Text(text.to_string());
+        assert_eq!(input.tokens(), [text("base off"), text("layer on"), text("layer off")]);
+    }
+
+    #[test]
+    fn commands_that_cannot_answer_are_false() {
+        assert!(check_command(&args(&["true"])));
+        assert!(!check_command(&args(&["false"])));
+        assert!(!check_command(&[]));
+        assert!(!check_command(&args(&["/nonexistent/qkeypie-check"])));
+
+        let start = time::Instant::now();
+        assert!(!check_command(&args(&["sleep", "10"])));
+        assert!(start.elapsed() < CHECK_TIMEOUT * 2);
+    }
 }
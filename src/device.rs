@@ -0,0 +1,310 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use xencelabs_quick_keys::{QKDevice, QKError, QKResult, Event, ButtonState, WheelDirection};
+use xencelabs_quick_keys::{ScreenOrientation, ScreenBrightness, WheelSpeed};
+
+use crate::actions::WhichButton;
+
+/// Everything the controller needs from a Quick Keys, real or not.
+pub trait Device {
+    fn read_timeout(&self, timeout: i32) -> QKResult<Event>;
+
+    fn set_screen_orientation(&self, orientation: ScreenOrientation) -> QKResult<()>;
+    fn set_screen_brightness(&self, level: ScreenBrightness) -> QKResult<()>;
+    fn set_wheel_speed(&self, speed: WheelSpeed) -> QKResult<()>;
+    fn set_sleep_timeout(&self, minutes: u8) -> QKResult<()>;
+    fn set_ring_color(&self, red: u8, green: u8, blue: u8) -> QKResult<()>;
+    fn set_key_text(&self, key: u8, text: &str) -> QKResult<()>;
+    fn show_overlay_text(&self, text: &str, seconds: u8) -> QKResult<()>;
+}
+
+impl Device for QKDevice {
+    fn read_timeout(&self, timeout: i32) -> QKResult<Event> {
+        QKDevice::read_timeout(self, timeout)
+    }
+
+    fn set_screen_orientation(&self, orientation: ScreenOrientation) -> QKResult<()> {
+        QKDevice::set_screen_orientation(self, orientation)
+    }
+
+    fn set_screen_brightness(&self, level: ScreenBrightness) -> QKResult<()> {
+        QKDevice::set_screen_brightness(self, level)
+    }
+
+    fn set_wheel_speed(&self, speed: WheelSpeed) -> QKResult<()> {
+        QKDevice::set_wheel_speed(self, speed)
+    }
+
+    fn set_sleep_timeout(&self, minutes: u8) -> QKResult<()> {
+        QKDevice::set_sleep_timeout(self, minutes)
+    }
+
+    fn set_ring_color(&self, red: u8, green: u8, blue: u8) -> QKResult<()> {
+        QKDevice::set_ring_color(self, red, green, blue)
+    }
+
+    fn set_key_text(&self, key: u8, text: &str) -> QKResult<()> {
+        QKDevice::set_key_text(self, key, text)
+    }
+
+    fn show_overlay_text(&self, text: &str, seconds: u8) -> QKResult<()> {
+        QKDevice::show_overlay_text(self, text, seconds)
+    }
+}
+
+/// What a fake device would be showing right now.
+#[derive(Debug, Clone, Default)]
+pub struct FakeDisplay {
+    pub key_text: [String; 8],
+    pub ring_color: (u8, u8, u8),
+    pub overlay: Option<(String, Instant)>,
+    pub orientation: ScreenOrientation,
+    pub brightness: ScreenBrightness,
+    pub wheel_speed: WheelSpeed,
+    pub sleep_timeout: u8,
+}
+
+/// An in-memory Quick Keys.  Outputs are kept in a `FakeDisplay` and inputs
+/// are fed with `set_button` and `rotate`.  It can be unplugged and plugged
+/// back with `set_connected`.  Clones share the same device.
+#[derive(Clone)]
+pub struct FakeDevice {
+    display: Arc<Mutex<FakeDisplay>>,
+    buttons: Arc<Mutex<ButtonState>>,
+    connected: Arc<Mutex<bool>>,
+    events_tx: mpsc::Sender<Event>,
+    events_rx: Arc<Mutex<mpsc::Receiver<Event>>>,
+}
+
+impl Default for FakeDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeDevice {
+    pub fn new() -> Self {
+        let (events_tx, events_rx) = mpsc::channel();
+        FakeDevice {
+            display: Arc::new(Mutex::new(FakeDisplay::default())),
+            buttons: Arc::new(Mutex::new(ButtonState::default())),
+            connected: Arc::new(Mutex::new(true)),
+            events_tx,
+            events_rx: Arc::new(Mutex::new(events_rx)),
+        }
+    }
+
+    pub fn display(&self) -> FakeDisplay {
+        self.display.lock().unwrap().clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.connected.lock().unwrap()
+    }
+
+    /// Plug or unplug the device.  Like the real one, it forgets its labels,
+    /// colors and settings when unplugged.
+    pub fn set_connected(&self, connected: bool) {
+        *self.connected.lock().unwrap() = connected;
+        if !connected {
+            *self.display.lock().unwrap() = FakeDisplay::default();
+            *self.buttons.lock().unwrap() = ButtonState::default();
+            while self.events_rx.lock().unwrap().try_recv().is_ok() {}
+        }
+    }
+
+    /// A handle on the device, failing like `QKDevice::open` while it is unplugged.
+    pub fn connect(&self) -> QKResult<FakeDevice> {
+        match self.is_connected() {
+            true => Ok(self.clone()),
+            false => Err(QKError::QKDeviceNotFound),
+        }
+    }
+
+    /// Fails the way the real device does once it is unplugged.
+    fn check_connected(&self) -> QKResult<()> {
+        if self.is_connected() {
+            Ok(())
+        } else {
+            Err(QKError::QKConnectionError)
+        }
+    }
+
+    pub fn is_pressed(&self, button: &WhichButton) -> bool {
+        let buttons = self.buttons.lock().unwrap();
+        match button {
+            WhichButton::Button0 => buttons.button_0,
+            WhichButton::Button1 => buttons.button_1,
+            WhichButton::Button2 => buttons.button_2,
+            WhichButton::Button3 => buttons.button_3,
+            WhichButton::Button4 => buttons.button_4,
+            WhichButton::Button5 => buttons.button_5,
+            WhichButton::Button6 => buttons.button_6,
+            WhichButton::Button7 => buttons.button_7,
+            WhichButton::ButtonExtra => buttons.button_extra,
+            WhichButton::WheelButton => buttons.button_wheel,
+            WhichButton::ThisButton => false,
+        }
+    }
+
+    /// Press or release a button, queueing the resulting event for the next read.
+    pub fn set_button(&self, button: &WhichButton, pressed: bool) {
+        if !self.is_connected() {
+            return;
+        }
+        let mut buttons = self.buttons.lock().unwrap();
+        match button {
+            WhichButton::Button0 => buttons.button_0 = pressed,
+            WhichButton::Button1 => buttons.button_1 = pressed,
+            WhichButton::Button2 => buttons.button_2 = pressed,
+            WhichButton::Button3 => buttons.button_3 = pressed,
+            WhichButton::Button4 => buttons.button_4 = pressed,
+            WhichButton::Button5 => buttons.button_5 = pressed,
+            WhichButton::Button6 => buttons.button_6 = pressed,
+            WhichButton::Button7 => buttons.button_7 = pressed,
+            WhichButton::ButtonExtra => buttons.button_extra = pressed,
+            WhichButton::WheelButton => buttons.button_wheel = pressed,
+            WhichButton::ThisButton => return,
+        }
+        self.push(Event::Button { state: *buttons });
+    }
+
+    pub fn rotate(&self, direction: WheelDirection) {
+        self.push(Event::Wheel { direction });
+    }
+
+    pub fn push(&self, event: Event) {
+        if !self.is_connected() {
+            return;
+        }
+        // The receiving end lives as long as `self`, so this never fails.
+        let _ = self.events_tx.send(event);
+    }
+}
+
+impl Device for FakeDevice {
+    fn read_timeout(&self, timeout: i32) -> QKResult<Event> {
+        self.check_connected()?;
+        let rx = self.events_rx.lock().unwrap();
+        match rx.recv_timeout(Duration::from_millis(timeout.max(0) as u64)) {
+            Ok(event) => Ok(event),
+            // Same as the real device when nothing arrives in time
+            Err(_) => Ok(Event::Unknown { data: [0; 10] }),
+        }
+    }
+
+    fn set_screen_orientation(&self, orientation: ScreenOrientation) -> QKResult<()> {
+        self.check_connected()?;
+        tracing::debug!("fake device: orientation {:?}", orientation);
+        self.display.lock().unwrap().orientation = orientation;
+        Ok(())
+    }
+
+    fn set_screen_brightness(&self, level: ScreenBrightness) -> QKResult<()> {
+        self.check_connected()?;
+        tracing::debug!("fake device: brightness {:?}", level);
+        self.display.lock().unwrap().brightness = level;
+        Ok(())
+    }
+
+    fn set_wheel_speed(&self, speed: WheelSpeed) -> QKResult<()> {
+        self.check_connected()?;
+        tracing::debug!("fake device: wheel speed {:?}", speed);
+        self.display.lock().unwrap().wheel_speed = speed;
+        Ok(())
+    }
+
+    fn set_sleep_timeout(&self, minutes: u8) -> QKResult<()> {
+        self.check_connected()?;
+        tracing::debug!("fake device: sleep timeout {}", minutes);
+        self.display.lock().unwrap().sleep_timeout = minutes;
+        Ok(())
+    }
+
+    fn set_ring_color(&self, red: u8, green: u8, blue: u8) -> QKResult<()> {
+        self.check_connected()?;
+        tracing::debug!("fake device: ring color ({}, {}, {})", red, green, blue);
+        self.display.lock().unwrap().ring_color = (red, green, blue);
+        Ok(())
+    }
+
+    fn set_key_text(&self, key: u8, text: &str) -> QKResult<()> {
+        self.check_connected()?;
+        tracing::debug!("fake device: key {} text {:?}", key, text);
+        if let Some(label) = self.display.lock().unwrap().key_text.get_mut(key as usize) {
+            *label = text.to_string();
+        }
+        Ok(())
+    }
+
+    fn show_overlay_text(&self, text: &str, seconds: u8) -> QKResult<()> {
+        self.check_connected()?;
+        tracing::debug!("fake device: overlay {:?} for {}s", text, seconds);
+        self.display.lock().unwrap().overlay = Some((text.to_string(), Instant::now() + Duration::from_secs(seconds as u64)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons_and_the_wheel_come_out_as_events() {
+        let dev = FakeDevice::new();
+        dev.set_button(&WhichButton::Button3, true);
+        dev.set_button(&WhichButton::WheelButton, true);
+        dev.rotate(WheelDirection::Left);
+        dev.set_button(&WhichButton::Button3, false);
+
+        let pressed = ButtonState { button_3: true, ..ButtonState::default() };
+        assert_eq!(dev.read_timeout(0).unwrap(), Event::Button { state: pressed });
+        // Every event carries all the buttons down at the time
+        assert_eq!(dev.read_timeout(0).unwrap(), Event::Button { state: ButtonState { button_wheel: true, ..pressed } });
+        assert_eq!(dev.read_timeout(0).unwrap(), Event::Wheel { direction: WheelDirection::Left });
+        assert_eq!(dev.read_timeout(0).unwrap(), Event::Button { state: ButtonState { button_wheel: true, ..ButtonState::default() } });
+        assert!(matches!(dev.read_timeout(10).unwrap(), Event::Unknown { .. }));
+        assert!(dev.is_pressed(&WhichButton::WheelButton));
+        assert!(!dev.is_pressed(&WhichButton::Button3));
+    }
+
+    #[test]
+    fn keeps_what_it_is_told_to_show() {
+        let dev = FakeDevice::new();
+        dev.set_key_text(2, "Split").unwrap();
+        dev.set_key_text(8, "Nowhere").unwrap();
+        dev.set_ring_color(255, 0, 128).unwrap();
+        dev.show_overlay_text("-- SHELL --", 2).unwrap();
+        dev.set_wheel_speed(WheelSpeed::Slowest).unwrap();
+
+        let display = dev.display();
+        assert_eq!(display.key_text, ["", "", "Split", "", "", "", "", ""]);
+        assert_eq!(display.ring_color, (255, 0, 128));
+        let (banner, until) = display.overlay.unwrap();
+        assert_eq!(banner, "-- SHELL --");
+        assert!(until > Instant::now() + Duration::from_secs(1));
+        assert_eq!(display.wheel_speed, WheelSpeed::Slowest);
+    }
+
+    #[test]
+    fn forgets_everything_while_unplugged() {
+        let dev = FakeDevice::new();
+        let handle = dev.connect().unwrap();
+        handle.set_key_text(0, "Mute").unwrap();
+        dev.set_button(&WhichButton::Button0, true);
+
+        dev.set_connected(false);
+        assert!(matches!(handle.read_timeout(0), Err(QKError::QKConnectionError)));
+        assert!(matches!(handle.set_ring_color(1, 2, 3), Err(QKError::QKConnectionError)));
+        assert!(matches!(dev.connect(), Err(QKError::QKDeviceNotFound)));
+        dev.set_button(&WhichButton::Button1, true);
+
+        dev.set_connected(true);
+        let handle = dev.connect().unwrap();
+        assert_eq!(handle.display().key_text[0], "");
+        assert!(!handle.is_pressed(&WhichButton::Button0));
+        // Neither the press from before nor the one while unplugged is left over
+        assert!(matches!(handle.read_timeout(0).unwrap(), Event::Unknown { .. }));
+    }
+}
@@ -1,4 +1,6 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
 
 // use crate::actions;
 
@@ -19,77 +21,182 @@ impl From<bool> for ButtonState {
     }
 }
 
+/// How long the gestures take, in milliseconds.  What a button or wheel
+/// leaves out comes from the `[timings]` section, and then the defaults.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timings {
+    /// Holding a button this long is a long press, 500 by default
+    pub long_press: Option<u64>,
+    /// Pressing again within this long after a release adds to the click
+    /// count, 400 by default.  With 0 there are only single clicks, which
+    /// fire on release without waiting for another one.
+    pub multi_click: Option<u64>,
+    /// The wheel stops turning after this long without a step, 500 by default
+    pub wheel_stop: Option<u64>,
+    /// Holding a button this long starts `on_hold_repeat`, 500 by default
+    pub repeat_delay: Option<u64>,
+    /// How often `on_hold_repeat` runs after that, 100 by default
+    pub repeat_interval: Option<u64>,
+    /// Buttons pressed within this long of the first one can make a chord, 50
+    /// by default.  Only in `[timings]`, buttons and wheels cannot have their own.
+    pub chord: Option<u64>,
+    /// Whether there is an `on_hold_repeat` to run, without one a held button
+    /// does not repeat.  Not from the config, the state fills it in.
+    #[serde(skip)]
+    pub hold_repeat: bool,
+}
+
+impl Timings {
+    /// These timings, with the gaps filled in from `fallback`.
+    pub fn or(&self, fallback: &Timings) -> Timings {
+        Timings {
+            long_press: self.long_press.or(fallback.long_press),
+            multi_click: self.multi_click.or(fallback.multi_click),
+            wheel_stop: self.wheel_stop.or(fallback.wheel_stop),
+            repeat_delay: self.repeat_delay.or(fallback.repeat_delay),
+            repeat_interval: self.repeat_interval.or(fallback.repeat_interval),
+            chord: self.chord.or(fallback.chord),
+            hold_repeat: self.hold_repeat,
+        }
+    }
+
+    pub fn chord_window(&self) -> Duration {
+        Duration::from_millis(self.chord.unwrap_or(50))
+    }
+
+    fn long_press(&self) -> Duration {
+        Duration::from_millis(self.long_press.unwrap_or(500))
+    }
+
+    fn multi_click(&self) -> Duration {
+        Duration::from_millis(self.multi_click.unwrap_or(400))
+    }
+
+    fn wheel_stop(&self) -> Duration {
+        Duration::from_millis(self.wheel_stop.unwrap_or(500))
+    }
+
+    /// When the hold repeat after `repeats` earlier ones is due, for a button
+    /// pressed at `pressed_at`, if it repeats at all.
+    fn repeat_at(&self, pressed_at: Instant, repeats: u32) -> Option<Instant> {
+        if !self.hold_repeat {
+            return None;
+        }
+        // Never 0, or a held button would repeat as fast as it is polled
+        let interval = Duration::from_millis(self.repeat_interval.unwrap_or(100).max(1));
+        Some(pressed_at + Duration::from_millis(self.repeat_delay.unwrap_or(500)) + interval * repeats)
+    }
+}
+
+/// A button, with the time it was pressed or released when that matters.
+/// Held buttons also count the hold repeats so far.
 #[derive(Debug, Copy, Clone, Default)]
 pub enum ButtonStateMachine {
     #[default]
     Idle,
-    Pressed(Instant),
-    LongPressed,
+    Pressed(Instant, u32),
+    LongPressed(Instant, u32),
     WaitingForClick(Instant, u8),
     NonFirstPressed(Instant, u8),
 }
 
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub enum ButtonEvent {
     OnPress,
     OnRelease,
     OnLongPress,
+    OnHoldRepeat,
     OnClickPress(u8),
     OnClick(u8),
     OnClickRelease(u8),
 }
 
 impl ButtonStateMachine {
-    pub fn transition(self, event: ButtonState, when: Instant) -> (Self, Vec<ButtonEvent>) {
+    pub fn transition(self, event: ButtonState, when: Instant, timings: &Timings) -> (Self, Vec<ButtonEvent>) {
         match (self, event) {
             (ButtonStateMachine::Idle, ButtonState::Pressed) => {
-                (ButtonStateMachine::Pressed(when), vec![ButtonEvent::OnPress, ButtonEvent::OnClickPress(1)])
+                (ButtonStateMachine::Pressed(when, 0), vec![ButtonEvent::OnPress, ButtonEvent::OnClickPress(1)])
             },
             (ButtonStateMachine::Idle, _) => {
                 (ButtonStateMachine::Idle, vec![])
             },
-            (ButtonStateMachine::Pressed(pressed_at), ButtonState::Released) => {
-                let duration = when.duration_since(pressed_at);
-                if duration.as_millis() < 500 {
+            (ButtonStateMachine::Pressed(pressed_at, _), ButtonState::Released) => {
+                if when < pressed_at + timings.long_press() && timings.multi_click().is_zero() {
+                    (ButtonStateMachine::Idle, vec![ButtonEvent::OnRelease, ButtonEvent::OnClickRelease(1), ButtonEvent::OnClick(1)])
+                } else if when < pressed_at + timings.long_press() {
                     (ButtonStateMachine::WaitingForClick(when, 1), vec![ButtonEvent::OnRelease, ButtonEvent::OnClickRelease(1)])
                 } else {
-                    (ButtonStateMachine::LongPressed, vec![ButtonEvent::OnLongPress])
+                    // Only when the release comes before the long press was noticed
+                    (ButtonStateMachine::Idle, vec![ButtonEvent::OnLongPress, ButtonEvent::OnRelease])
                 }
             }
-            (ButtonStateMachine::Pressed(pressed_at), _) => {
-                let duration = when.duration_since(pressed_at);
-                if duration.as_millis() > 500 {
-                    (ButtonStateMachine::LongPressed, vec![ButtonEvent::OnLongPress])
+            (ButtonStateMachine::Pressed(pressed_at, repeats), _) => {
+                let mut events = vec![];
+                let mut repeats = repeats;
+                let long_pressed = when >= pressed_at + timings.long_press();
+                if long_pressed {
+                    events.push(ButtonEvent::OnLongPress);
+                }
+                if timings.repeat_at(pressed_at, repeats).is_some_and(|repeat_at| when >= repeat_at) {
+                    events.push(ButtonEvent::OnHoldRepeat);
+                    repeats += 1;
+                }
+                if long_pressed {
+                    (ButtonStateMachine::LongPressed(pressed_at, repeats), events)
                 } else {
-                    (ButtonStateMachine::Pressed(pressed_at), vec![])
+                    (ButtonStateMachine::Pressed(pressed_at, repeats), events)
                 }
             }
-            (ButtonStateMachine::LongPressed, ButtonState::Released) => {
+            (ButtonStateMachine::LongPressed(_, _), ButtonState::Released) => {
                 (ButtonStateMachine::Idle, vec![ButtonEvent::OnRelease])
             }
-            (ButtonStateMachine::LongPressed, _) => {
-                (ButtonStateMachine::LongPressed, vec![])
+            (ButtonStateMachine::LongPressed(pressed_at, repeats), _) => {
+                // One at a time, a late poll catches up on the next ones right away
+                if timings.repeat_at(pressed_at, repeats).is_some_and(|repeat_at| when >= repeat_at) {
+                    (ButtonStateMachine::LongPressed(pressed_at, repeats + 1), vec![ButtonEvent::OnHoldRepeat])
+                } else {
+                    (ButtonStateMachine::LongPressed(pressed_at, repeats), vec![])
+                }
             }
-            (ButtonStateMachine::WaitingForClick(_, count), ButtonState::Pressed) => {
-                (ButtonStateMachine::NonFirstPressed(when, count), vec![ButtonEvent::OnPress, ButtonEvent::OnClickPress(count + 1)])
+            (ButtonStateMachine::WaitingForClick(released_at, count), ButtonState::Pressed) => {
+                if when < released_at + timings.multi_click() {
+                    (ButtonStateMachine::NonFirstPressed(when, count), vec![ButtonEvent::OnPress, ButtonEvent::OnClickPress(count.saturating_add(1))])
+                } else {
+                    // Too late for this click, when no poll came in between to notice
+                    (ButtonStateMachine::Pressed(when, 0), vec![ButtonEvent::OnClick(count), ButtonEvent::OnPress, ButtonEvent::OnClickPress(1)])
+                }
             }
-            (ButtonStateMachine::WaitingForClick(pressed_at, count), _) => {
-                let duration = when.duration_since(pressed_at);
-                if duration.as_millis() < 400 {
-                    (ButtonStateMachine::WaitingForClick(pressed_at, count), vec![])
+            (ButtonStateMachine::WaitingForClick(released_at, count), _) => {
+                if when < released_at + timings.multi_click() {
+                    (ButtonStateMachine::WaitingForClick(released_at, count), vec![])
                 } else {
                     (ButtonStateMachine::Idle, vec![ButtonEvent::OnClick(count)])
                 }
             }
-            (ButtonStateMachine::NonFirstPressed(pressed_at, count), ButtonState::Released) => {
-                (ButtonStateMachine::WaitingForClick(pressed_at, count + 1), vec![ButtonEvent::OnRelease, ButtonEvent::OnClickRelease(count + 1)])
+            (ButtonStateMachine::NonFirstPressed(_, count), ButtonState::Released) => {
+                (ButtonStateMachine::WaitingForClick(when, count.saturating_add(1)), vec![ButtonEvent::OnRelease, ButtonEvent::OnClickRelease(count.saturating_add(1))])
             }
             (ButtonStateMachine::NonFirstPressed(pressed_at, count), _) => {
                 (ButtonStateMachine::NonFirstPressed(pressed_at, count), vec![])
             }
         }
     }
+
+    pub fn is_pressed(&self) -> bool {
+        matches!(self, ButtonStateMachine::Pressed(..) | ButtonStateMachine::LongPressed(..) | ButtonStateMachine::NonFirstPressed(..))
+    }
+
+    /// When something happens next without the button moving, if ever.
+    pub fn deadline(&self, timings: &Timings) -> Option<Instant> {
+        match self {
+            ButtonStateMachine::Pressed(pressed_at, repeats) => Some(*pressed_at + timings.long_press()).into_iter().chain(timings.repeat_at(*pressed_at, *repeats)).min(),
+            ButtonStateMachine::LongPressed(pressed_at, repeats) => timings.repeat_at(*pressed_at, *repeats),
+            ButtonStateMachine::WaitingForClick(released_at, _) => Some(*released_at + timings.multi_click()),
+            ButtonStateMachine::Idle | ButtonStateMachine::NonFirstPressed(_, _) => None,
+        }
+    }
 }
 
 pub enum WheelState {
@@ -121,7 +228,7 @@ impl From<xencelabs_quick_keys::Event> for WheelState {
 }
 
 #[allow(clippy::enum_variant_names)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub enum WheelEvent {
     OnRotateClockwiseStart,
     OnRotateClockwiseStep,
@@ -132,7 +239,7 @@ pub enum WheelEvent {
 }
 
 impl WheelStateMachine {
-    pub fn transition(self, event: WheelState, when: Instant) -> (Self, Vec<WheelEvent>) {
+    pub fn transition(self, event: WheelState, when: Instant, timings: &Timings) -> (Self, Vec<WheelEvent>) {
         match (self, event) {
             (WheelStateMachine::Idle, WheelState::Unknown) => {
                 (WheelStateMachine::Idle, vec![])
@@ -144,7 +251,7 @@ impl WheelStateMachine {
                 (WheelStateMachine::RotatingCounterClockwise(when), vec![WheelEvent::OnRotateCounterClockwiseStart])
             },
             (WheelStateMachine::RotatingClockwise(started_at), WheelState::Unknown) => {
-                if when.duration_since(started_at).as_millis() < 500 {
+                if when < started_at + timings.wheel_stop() {
                     (WheelStateMachine::RotatingClockwise(started_at), vec![])
                 } else {
                     (WheelStateMachine::Idle, vec![WheelEvent::OnRotateClockwiseEnd])
@@ -157,7 +264,7 @@ impl WheelStateMachine {
                 (WheelStateMachine::RotatingCounterClockwise(when), vec![WheelEvent::OnRotateClockwiseEnd, WheelEvent::OnRotateCounterClockwiseStart])
             },
             (WheelStateMachine::RotatingCounterClockwise(started_at), WheelState::Unknown) => {
-                if when.duration_since(started_at).as_millis() < 500 {
+                if when < started_at + timings.wheel_stop() {
                     (WheelStateMachine::RotatingCounterClockwise(started_at), vec![])
                 } else {
                     (WheelStateMachine::Idle, vec![WheelEvent::OnRotateCounterClockwiseEnd])
@@ -171,4 +278,164 @@ impl WheelStateMachine {
             },
         }
     }
+
+    /// When the wheel counts as stopped if it does not turn again.
+    pub fn deadline(&self, timings: &Timings) -> Option<Instant> {
+        match self {
+            WheelStateMachine::Idle => None,
+            WheelStateMachine::RotatingClockwise(turned_at) | WheelStateMachine::RotatingCounterClockwise(turned_at) => Some(*turned_at + timings.wheel_stop()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ButtonState::{Pressed, Released, Unknown};
+
+    /// The events of a button fed `steps`, each at its millisecond since the
+    /// start, as `"millis Event"`.
+    fn play(timings: &Timings, steps: &[(u64, ButtonState)]) -> Vec<String> {
+        let start = Instant::now();
+        let mut machine = ButtonStateMachine::default();
+        let mut played = Vec::new();
+        for (millis, state) in steps {
+            let (next, events) = machine.transition(*state, start + Duration::from_millis(*millis), timings);
+            machine = next;
+            played.extend(events.iter().map(|event| format!("{millis} {event:?}")));
+        }
+        played
+    }
+
+    #[test]
+    fn per_button_timings_fall_back_to_the_global_ones() {
+        let global = Timings { long_press: Some(800), multi_click: Some(300), ..Timings::default() };
+        let button = Timings { multi_click: Some(600), ..Timings::default() }.or(&global);
+        assert_eq!(button, Timings { long_press: Some(800), multi_click: Some(600), ..Timings::default() });
+        assert_eq!(Timings::default().chord_window(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn long_press_takes_as_long_as_configured() {
+        let slow = Timings { long_press: Some(1000), repeat_delay: Some(5000), ..Timings::default() };
+        assert_eq!(play(&slow, &[(0, Pressed), (600, Pressed), (999, Pressed), (1000, Pressed)]), [
+            "0 OnPress", "0 OnClickPress(1)", "1000 OnLongPress",
+        ]);
+        assert_eq!(play(&Timings { repeat_delay: Some(5000), ..Timings::default() }, &[(0, Pressed), (600, Pressed)]), [
+            "0 OnPress", "0 OnClickPress(1)", "600 OnLongPress",
+        ]);
+    }
+
+    #[test]
+    fn multi_click_window_as_configured() {
+        let steps = [(0, Pressed), (50, Released), (600, Pressed), (650, Released), (1200, Unknown), (1349, Unknown), (1350, Unknown)];
+        assert_eq!(play(&Timings { multi_click: Some(700), ..Timings::default() }, &steps), [
+            "0 OnPress", "0 OnClickPress(1)", "50 OnRelease", "50 OnClickRelease(1)",
+            "600 OnPress", "600 OnClickPress(2)", "650 OnRelease", "650 OnClickRelease(2)",
+            "1350 OnClick(2)",
+        ]);
+        assert_eq!(play(&Timings::default(), &steps), [
+            "0 OnPress", "0 OnClickPress(1)", "50 OnRelease", "50 OnClickRelease(1)",
+            "600 OnClick(1)", "600 OnPress", "600 OnClickPress(1)", "650 OnRelease", "650 OnClickRelease(1)",
+            "1200 OnClick(1)",
+        ]);
+    }
+
+    #[test]
+    fn long_press_fires_while_held() {
+        let timings = Timings { repeat_delay: Some(5000), ..Timings::default() };
+        assert_eq!(play(&timings, &[(0, Pressed), (499, Unknown), (500, Unknown), (550, Pressed), (900, Released), (1500, Unknown)]), [
+            "0 OnPress", "0 OnClickPress(1)", "500 OnLongPress", "900 OnRelease",
+        ]);
+    }
+
+    #[test]
+    fn release_after_a_long_press_nobody_polled_for() {
+        // The long press still comes first, and the release is no click
+        let timings = Timings { repeat_delay: Some(5000), ..Timings::default() };
+        assert_eq!(play(&timings, &[(0, Pressed), (700, Released), (1500, Unknown)]), [
+            "0 OnPress", "0 OnClickPress(1)", "700 OnLongPress", "700 OnRelease",
+        ]);
+        assert_eq!(play(&timings, &[(0, Pressed), (499, Released), (1000, Unknown)]), [
+            "0 OnPress", "0 OnClickPress(1)", "499 OnRelease", "499 OnClickRelease(1)", "1000 OnClick(1)",
+        ]);
+    }
+
+    #[test]
+    fn repeats_while_held() {
+        let timings = Timings { long_press: Some(1000), repeat_delay: Some(300), repeat_interval: Some(100), hold_repeat: true, ..Timings::default() };
+        let steps = [(0, Pressed), (299, Unknown), (300, Unknown), (400, Unknown), (450, Unknown), (500, Unknown), (1000, Unknown), (1001, Unknown), (1050, Released)];
+        assert_eq!(play(&timings, &steps), [
+            "0 OnPress", "0 OnClickPress(1)",
+            "300 OnHoldRepeat", "400 OnHoldRepeat", "500 OnHoldRepeat",
+            // Late polls catch up one repeat at a time
+            "1000 OnLongPress", "1000 OnHoldRepeat", "1001 OnHoldRepeat",
+            "1050 OnRelease",
+        ]);
+
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        assert_eq!(ButtonStateMachine::Pressed(at(0), 0).deadline(&timings), Some(at(300)));
+        assert_eq!(ButtonStateMachine::Pressed(at(0), 2).deadline(&timings), Some(at(500)));
+        assert_eq!(ButtonStateMachine::LongPressed(at(0), 10).deadline(&timings), Some(at(1300)));
+        // By default the long press and the first repeat are due together
+        assert_eq!(ButtonStateMachine::Pressed(at(0), 0).deadline(&Timings { hold_repeat: true, ..Timings::default() }), Some(at(500)));
+    }
+
+    #[test]
+    fn no_repeats_without_on_hold_repeat() {
+        let timings = Timings { long_press: Some(1000), repeat_delay: Some(300), ..Timings::default() };
+        assert_eq!(play(&timings, &[(0, Pressed), (300, Unknown), (400, Unknown), (1000, Unknown), (1500, Unknown), (1550, Released)]), [
+            "0 OnPress", "0 OnClickPress(1)", "1000 OnLongPress", "1550 OnRelease",
+        ]);
+
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        assert_eq!(ButtonStateMachine::Pressed(at(0), 0).deadline(&timings), Some(at(1000)));
+        assert_eq!(ButtonStateMachine::LongPressed(at(0), 0).deadline(&timings), None);
+    }
+
+    #[test]
+    fn counts_clicks_past_three() {
+        let steps = [
+            (0, Pressed), (50, Released), (150, Pressed), (200, Released), (300, Pressed), (350, Released),
+            (450, Pressed), (500, Released), (600, Pressed), (650, Released), (1049, Unknown), (1050, Unknown),
+        ];
+        assert_eq!(play(&Timings::default(), &steps), [
+            "0 OnPress", "0 OnClickPress(1)", "50 OnRelease", "50 OnClickRelease(1)",
+            "150 OnPress", "150 OnClickPress(2)", "200 OnRelease", "200 OnClickRelease(2)",
+            "300 OnPress", "300 OnClickPress(3)", "350 OnRelease", "350 OnClickRelease(3)",
+            "450 OnPress", "450 OnClickPress(4)", "500 OnRelease", "500 OnClickRelease(4)",
+            "600 OnPress", "600 OnClickPress(5)", "650 OnRelease", "650 OnClickRelease(5)",
+            "1050 OnClick(5)",
+        ]);
+    }
+
+    #[test]
+    fn no_multi_click_clicks_on_release() {
+        let timings = Timings { multi_click: Some(0), ..Timings::default() };
+        assert_eq!(play(&timings, &[(0, Pressed), (50, Released), (100, Pressed), (150, Released), (600, Unknown)]), [
+            "0 OnPress", "0 OnClickPress(1)", "50 OnRelease", "50 OnClickRelease(1)", "50 OnClick(1)",
+            "100 OnPress", "100 OnClickPress(1)", "150 OnRelease", "150 OnClickRelease(1)", "150 OnClick(1)",
+        ]);
+        // A long press is still one
+        assert_eq!(play(&Timings { repeat_delay: Some(5000), ..timings }, &[(0, Pressed), (600, Released)]), [
+            "0 OnPress", "0 OnClickPress(1)", "600 OnLongPress", "600 OnRelease",
+        ]);
+    }
+
+    #[test]
+    fn wheel_stops_as_configured() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let timings = Timings { wheel_stop: Some(200), ..Timings::default() };
+        let (wheel, events) = WheelStateMachine::Idle.transition(WheelState::RotatingClockwise, at(0), &timings);
+        assert!(matches!(events[..], [WheelEvent::OnRotateClockwiseStart]));
+        assert_eq!(wheel.deadline(&timings), Some(at(200)));
+        let (wheel, events) = wheel.transition(WheelState::Unknown, at(199), &timings);
+        assert!(events.is_empty());
+        let (wheel, events) = wheel.transition(WheelState::Unknown, at(200), &timings);
+        assert!(matches!(events[..], [WheelEvent::OnRotateClockwiseEnd]));
+        assert!(matches!(wheel, WheelStateMachine::Idle));
+    }
 }
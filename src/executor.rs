@@ -0,0 +1,207 @@
+//! Runs action lists a few actions at a time, so a `Sleep` only holds up
+//! its own list and never the device.
+
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use indexmap::IndexMap;
+
+use crate::actions::{Action, Concurrency, GoTo, NonEnigoAction, WhichButton};
+
+/// Lists on the same lane run one after the other, lists on different lanes side by side.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Lane {
+    Button(WhichButton),
+    Wheel,
+    Chord,
+    /// The `on_enter` and `on_exit` callbacks, and the server ones
+    Transition,
+    /// Actions sent over the control socket
+    Api,
+}
+
+/// What running one action asks of the executor.
+pub enum Outcome {
+    Done,
+    GoTo(GoTo),
+    /// Run these in place of the action, like the branch an `If` takes
+    Then(Vec<Action>),
+    /// Hold the list until these arrive, then run them in place of the
+    /// action.  For an `If` waiting on a command.
+    Wait(mpsc::Receiver<Vec<Action>>),
+}
+
+/// How often a waiting task looks for what it waits for.
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An action list part way through.  Each action remembers the button it
+/// belongs to, for `ThisButton`.
+struct Task {
+    actions: VecDeque<(Action, Option<WhichButton>)>,
+    wake_at: Option<Instant>,
+    /// What the task waits for, and the button it is for
+    waiting: Option<(mpsc::Receiver<Vec<Action>>, Option<WhichButton>)>,
+}
+
+#[derive(Default)]
+pub struct Executor {
+    // The first task of each lane is the running one
+    lanes: IndexMap<Lane, VecDeque<Task>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a list of actions to `lane`.  `concurrency` decides what happens
+    /// when the lane is still busy with earlier ones.
+    pub fn submit(&mut self, lane: Lane, concurrency: Concurrency, actions: Vec<(Action, Option<WhichButton>)>) {
+        if actions.is_empty() {
+            return;
+        }
+        let tasks = self.lanes.entry(lane).or_default();
+        match concurrency {
+            Concurrency::Queue => {},
+            Concurrency::Cancel => tasks.clear(),
+            Concurrency::Ignore if !tasks.is_empty() => return,
+            Concurrency::Ignore => {},
+        }
+        tasks.push_back(Task { actions: actions.into(), wake_at: None, waiting: None });
+    }
+
+    /// Drop every task, running or not.
+    pub fn cancel_all(&mut self) {
+        for tasks in self.lanes.values_mut() {
+            tasks.clear();
+        }
+    }
+
+    /// When the first sleeping task wants to go on, or a waiting one wants
+    /// to look again.
+    pub fn next_wake(&self) -> Option<Instant> {
+        self.lanes.values()
+            .filter_map(|tasks| tasks.front())
+            .filter_map(|task| if task.waiting.is_some() { Some(Instant::now() + WAIT_INTERVAL) } else { task.wake_at })
+            .min()
+    }
+
+    /// Run every lane until its tasks are done, sleeping or waiting, using `eval` for
+    /// everything but `Sleep`.  Returns the gotos asked for, in order.  A
+    /// failing API task is only logged, any other failure stops the run.
+    pub fn step(&mut self, now: Instant, mut eval: impl FnMut(&Action, Option<WhichButton>) -> anyhow::Result<Outcome>) -> anyhow::Result<Vec<(Lane, GoTo)>> {
+        let mut gotos = Vec::new();
+        for (lane, tasks) in self.lanes.iter_mut() {
+            while let Some(task) = tasks.front_mut() {
+                if task.wake_at.is_some_and(|wake_at| wake_at > now) {
+                    break;
+                }
+                task.wake_at = None;
+                if let Some((waiting, button)) = &task.waiting {
+                    match waiting.try_recv() {
+                        Ok(actions) => {
+                            for next in actions.into_iter().rev() {
+                                task.actions.push_front((next, button.clone()));
+                            }
+                        },
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        // Whoever was to answer is gone, with nothing to run
+                        Err(mpsc::TryRecvError::Disconnected) => {},
+                    }
+                    task.waiting = None;
+                }
+                while let Some((action, button)) = task.actions.pop_front() {
+                    if let Action::NonEnigo(NonEnigoAction::Sleep(millis)) = action {
+                        task.wake_at = Some(now + Duration::from_millis(millis));
+                        break;
+                    }
+                    match eval(&action, button.clone()) {
+                        Ok(Outcome::Done) => {},
+                        Ok(Outcome::GoTo(goto)) => gotos.push((lane.clone(), goto)),
+                        Ok(Outcome::Then(actions)) => {
+                            for next in actions.into_iter().rev() {
+                                task.actions.push_front((next, button.clone()));
+                            }
+                        },
+                        Ok(Outcome::Wait(waiting)) => {
+                            task.waiting = Some((waiting, button));
+                            break;
+                        },
+                        Err(err) if *lane == Lane::Api => {
+                            tracing::error!("{err:#}");
+                            task.actions.clear();
+                        },
+                        Err(err) => return Err(err),
+                    }
+                }
+                if task.wake_at.is_some() || task.waiting.is_some() {
+                    break;
+                }
+                tasks.pop_front();
+            }
+        }
+        Ok(gotos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug(text: &str) -> (Action, Option<WhichButton>) {
+        (Action::NonEnigo(NonEnigoAction::Debug(text.to_string())), Some(WhichButton::Button0))
+    }
+
+    #[test]
+    fn waiting_holds_only_its_own_lane() {
+        let mut executor = Executor::new();
+        let (tx, rx) = mpsc::channel();
+        let mut rx = Some(rx);
+        executor.submit(Lane::Button(WhichButton::Button0), Concurrency::Queue, vec![debug("ask"), debug("after")]);
+        executor.submit(Lane::Wheel, Concurrency::Queue, vec![debug("wheel")]);
+
+        let mut ran = Vec::new();
+        let mut step = |executor: &mut Executor, ran: &mut Vec<String>| {
+            executor.step(Instant::now(), |action, button| {
+                let Action::NonEnigo(NonEnigoAction::Debug(text)) = action else { unreachable!() };
+                ran.push(format!("{text} {button:?}"));
+                Ok(match text.as_str() {
+                    "ask" => Outcome::Wait(rx.take().unwrap()),
+                    _ => Outcome::Done,
+                })
+            }).unwrap();
+        };
+
+        step(&mut executor, &mut ran);
+        assert_eq!(ran, ["ask Some(Button0)", "wheel Some(Button0)"]);
+        assert!(executor.next_wake().is_some());
+        step(&mut executor, &mut ran);
+        assert_eq!(ran.len(), 2);
+
+        tx.send(vec![Action::NonEnigo(NonEnigoAction::Debug("answer".to_string()))]).unwrap();
+        step(&mut executor, &mut ran);
+        // The branch belongs to the button of the action that waited
+        assert_eq!(ran[2..], ["answer Some(Button0)", "after Some(Button0)"]);
+        assert_eq!(executor.next_wake(), None);
+    }
+
+    #[test]
+    fn nobody_answering_moves_on() {
+        let mut executor = Executor::new();
+        let (tx, rx) = mpsc::channel::<Vec<Action>>();
+        let mut rx = Some(rx);
+        executor.submit(Lane::Chord, Concurrency::Queue, vec![debug("ask"), debug("after")]);
+        drop(tx);
+
+        let mut ran = Vec::new();
+        for _ in 0..2 {
+            executor.step(Instant::now(), |action, _| {
+                let Action::NonEnigo(NonEnigoAction::Debug(text)) = action else { unreachable!() };
+                ran.push(text.clone());
+                Ok(rx.take().map_or(Outcome::Done, Outcome::Wait))
+            }).unwrap();
+        }
+        assert_eq!(ran, ["ask", "after"]);
+    }
+}
@@ -0,0 +1,279 @@
+//! Switching profiles by the focused window, following the `[[focus]]` rules:
+//!
+//! ```toml
+//! [[focus]]
+//! match = { class = "kitty" }
+//! profile = "shell"
+//! buttonset = "tmux"
+//! ```
+//!
+//! A `WindowSource` tells which window has the focus, `watch` hands every
+//! change to the controllers, and each one picks the first rule for one of
+//! its profiles.
+
+use std::sync::{mpsc, Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::actions::{ButtonSetId, ChangeRef, GoTo, ProfileId, WheelId};
+use crate::controller::{Command, Handle};
+use crate::state::State;
+
+/// What is known about the focused window.  All empty when nothing has the focus.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Window {
+    /// The two halves of `WM_CLASS`.  Wayland windows only have an app id,
+    /// which goes in `class`.
+    pub instance: String,
+    pub class: String,
+    pub title: String,
+}
+
+/// Which windows a rule is for.  Every field given has to match: `class`
+/// against either half of `WM_CLASS`, `title` anywhere in the title.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowMatch {
+    pub class: Option<String>,
+    pub title: Option<String>,
+}
+
+impl WindowMatch {
+    pub fn matches(&self, window: &Window) -> bool {
+        self.class.as_ref().map_or(true, |class| *class == window.class || *class == window.instance)
+            && self.title.as_ref().map_or(true, |title| window.title.contains(title.as_str()))
+    }
+}
+
+/// Where to go when a matching window gets the focus.  A buttonset or wheel
+/// left out stays as it is, or is the first one of a new profile.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FocusRule {
+    #[serde(rename = "match")]
+    pub window: WindowMatch,
+    pub profile: ProfileId,
+    pub buttonset: Option<ButtonSetId>,
+    pub wheel: Option<WheelId>,
+}
+
+/// Where `window` getting the focus takes `state`, if anywhere.  Only a
+/// different rule than last time switches, so a title changing under the
+/// same rule leaves alone whatever was picked by hand meanwhile.
+pub fn goto(state: &mut State, window: &Window) -> Option<GoTo> {
+    // Rules for profiles this device does not have are not for it
+    let found = state.model.focus.iter().enumerate()
+        .find(|(_, rule)| state.model.profiles.contains_key(&rule.profile) && rule.window.matches(window));
+    let Some((index, rule)) = found else {
+        state.focus_rule = None;
+        return None;
+    };
+    if state.focus_rule == Some(index) {
+        return None;
+    }
+    state.focus_rule = Some(index);
+
+    let unset = if rule.profile == state.current_profile_id { ChangeRef::This } else { ChangeRef::First };
+    Some(GoTo::Switch(
+        ChangeRef::Name(rule.profile.clone()),
+        rule.buttonset.clone().map(ChangeRef::Name).unwrap_or(unset.clone()),
+        rule.wheel.clone().map(ChangeRef::Name).unwrap_or(unset),
+    ))
+}
+
+/// Something that knows which window has the focus.
+pub trait WindowSource {
+    /// Wait for the focus to move, or the focused window to change, and say
+    /// where it is now.  The first call answers right away.
+    fn next(&mut self) -> anyhow::Result<Window>;
+}
+
+/// Focus changes fed by hand with `focus`.  Clones share the same source.
+#[derive(Clone)]
+pub struct FakeWindows {
+    tx: mpsc::Sender<Window>,
+    rx: Arc<Mutex<mpsc::Receiver<Window>>>,
+}
+
+impl Default for FakeWindows {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeWindows {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        FakeWindows { tx, rx: Arc::new(Mutex::new(rx)) }
+    }
+
+    pub fn focus(&self, window: Window) {
+        // The receiving end lives as long as `self`, so this never fails.
+        let _ = self.tx.send(window);
+    }
+}
+
+impl WindowSource for FakeWindows {
+    fn next(&mut self) -> anyhow::Result<Window> {
+        Ok(self.rx.lock().unwrap().recv()?)
+    }
+}
+
+/// Tell every controller about each focus change from `source`, in the
+/// background, until the source fails.  The watcher gets a thread of its own
+/// rather than a blocking task, as the runtime would wait on it forever when
+/// shutting down.
+pub fn watch<S: WindowSource + Send + 'static>(mut source: S, controller: Handle) {
+    let runtime = tokio::runtime::Handle::current();
+    std::thread::spawn(move || {
+        loop {
+            let window = match source.next() {
+                Ok(window) => window,
+                Err(err) => {
+                    tracing::error!("focus watcher stopped: {err:#}");
+                    return;
+                },
+            };
+            tracing::debug!("focus on {:?}", window);
+            for device in controller.devices() {
+                if let Err(err) = runtime.block_on(controller.request_device(device, Command::Focus(window.clone()))) {
+                    tracing::error!("{device}: {err:#}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model;
+
+    const CONFIG: &str = r#"
+        [wheels.zoom]
+        [buttonsets.tmux]
+        [buttonsets.neovim]
+
+        [profiles.shell.buttonsets]
+        tmux = "tmux"
+        neovim = "neovim"
+        [profiles.shell.wheels]
+        zoom = "zoom"
+
+        [profiles.browser.buttonsets]
+        tmux = "tmux"
+        [profiles.browser.wheels]
+        zoom = "zoom"
+
+        [[focus]]
+        match = { class = "kitty", title = "nvim" }
+        profile = "shell"
+        buttonset = "neovim"
+
+        [[focus]]
+        match = { class = "kitty" }
+        profile = "shell"
+
+        [[focus]]
+        match = { class = "firefox" }
+        profile = "browser"
+        wheel = "zoom"
+
+        [[focus]]
+        match = { class = "slack" }
+        profile = "chat"
+    "#;
+
+    fn state() -> State {
+        let model = model::from_config(toml::from_str(CONFIG).unwrap()).unwrap();
+        State::new(model).unwrap()
+    }
+
+    fn window(instance: &str, class: &str, title: &str) -> Window {
+        Window { instance: instance.to_string(), class: class.to_string(), title: title.to_string() }
+    }
+
+    /// The profile, buttonset and wheel of a switch, `None` for anything else.
+    fn names(goto: Option<GoTo>) -> Option<(String, String, String)> {
+        let name = |change: ChangeRef| match change {
+            ChangeRef::Name(name) => name,
+            other => format!("{:?}", other),
+        };
+        match goto? {
+            GoTo::Switch(profile, buttonset, wheel) => Some((name(profile), name(buttonset), name(wheel))),
+            GoTo::Swap => None,
+        }
+    }
+
+    fn switch(profile: &str, buttonset: &str, wheel: &str) -> Option<(String, String, String)> {
+        Some((profile.to_string(), buttonset.to_string(), wheel.to_string()))
+    }
+
+    #[test]
+    fn class_matches_either_half_of_wm_class() {
+        let rule = WindowMatch { class: Some("kitty".to_string()), title: None };
+        assert!(rule.matches(&window("kitty", "Kitty", "")));
+        assert!(rule.matches(&window("term", "kitty", "")));
+        assert!(!rule.matches(&window("Kitty", "KITTY", "")));
+        assert!(!rule.matches(&Window::default()));
+    }
+
+    #[test]
+    fn title_matches_anywhere() {
+        let rule = WindowMatch { class: None, title: Some("nvim".to_string()) };
+        assert!(rule.matches(&window("", "", "nvim")));
+        assert!(rule.matches(&window("", "", "~/src - nvim main.rs")));
+        assert!(!rule.matches(&window("", "", "NVIM")));
+        assert!(WindowMatch::default().matches(&Window::default()));
+
+        let both = WindowMatch { class: Some("kitty".to_string()), title: Some("nvim".to_string()) };
+        assert!(both.matches(&window("kitty", "kitty", "nvim")));
+        assert!(!both.matches(&window("kitty", "kitty", "zsh")));
+        assert!(!both.matches(&window("foot", "foot", "nvim")));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let mut state = state();
+        let windows = FakeWindows::new();
+        let mut source = windows.clone();
+
+        windows.focus(window("kitty", "kitty", "nvim src/focus.rs"));
+        assert_eq!(names(goto(&mut state, &source.next().unwrap())), switch("shell", "neovim", "This"));
+        assert_eq!(state.focus_rule, Some(0));
+
+        windows.focus(window("kitty", "kitty", "zsh"));
+        assert_eq!(names(goto(&mut state, &source.next().unwrap())), switch("shell", "This", "This"));
+        assert_eq!(state.focus_rule, Some(1));
+
+        windows.focus(window("Navigator", "firefox", "Rust"));
+        assert_eq!(names(goto(&mut state, &source.next().unwrap())), switch("browser", "First", "zoom"));
+        assert_eq!(state.focus_rule, Some(2));
+    }
+
+    #[test]
+    fn same_rule_does_not_switch_again() {
+        let mut state = state();
+        let windows = FakeWindows::new();
+        let mut source = windows.clone();
+
+        windows.focus(window("kitty", "kitty", "zsh"));
+        windows.focus(window("kitty", "kitty", "htop"));
+        assert!(goto(&mut state, &source.next().unwrap()).is_some());
+        assert!(goto(&mut state, &source.next().unwrap()).is_none());
+
+        // Leaving the rule and coming back switches again
+        windows.focus(window("", "xterm", ""));
+        windows.focus(window("kitty", "kitty", "zsh"));
+        assert!(goto(&mut state, &source.next().unwrap()).is_none());
+        assert_eq!(state.focus_rule, None);
+        assert!(goto(&mut state, &source.next().unwrap()).is_some());
+    }
+
+    #[test]
+    fn rules_for_other_profiles_are_skipped() {
+        let mut state = state();
+        assert!(goto(&mut state, &window("slack", "slack", "")).is_none());
+        assert_eq!(state.focus_rule, None);
+    }
+}
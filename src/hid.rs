@@ -0,0 +1,362 @@
+//! Quick Keys over hidapi.  `QKDevice::open` always takes the first device it
+//! finds, this opens the one asked for so several can be driven at once.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::sync::{Arc, Mutex};
+
+use hidapi::{DeviceInfo, HidApi, HidDevice};
+use xencelabs_quick_keys::{QKError, QKResult, ConnectionMode, Event, ButtonState, WheelDirection};
+use xencelabs_quick_keys::{ScreenOrientation, ScreenBrightness, WheelSpeed};
+
+use crate::device::Device;
+
+const VENDOR_ID: u16 = 0x28BD;
+const WIRED_PRODUCT_ID: u16 = 0x5202;
+const WIRELESS_PRODUCT_ID: u16 = 0x5204;
+
+/// Which Quick Keys to open.  With `ConnectionMode::Auto` a cable wins over
+/// the wireless dongle when both are there.
+#[derive(Debug, Clone)]
+pub struct Selector {
+    pub serial: Option<String>,
+    pub path: Option<String>,
+    pub connection: ConnectionMode,
+}
+
+/// What a `Selector` looks at of a device hidapi found.
+#[derive(Debug, Clone)]
+struct Found {
+    vendor_id: u16,
+    product_id: u16,
+    usage: u16,
+    usage_page: u16,
+    serial: Option<String>,
+    path: CString,
+}
+
+impl From<&DeviceInfo> for Found {
+    fn from(info: &DeviceInfo) -> Self {
+        Found {
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            usage: info.usage(),
+            usage_page: info.usage_page(),
+            serial: info.serial_number().map(String::from),
+            path: info.path().to_owned(),
+        }
+    }
+}
+
+impl Selector {
+    fn matches(&self, found: &Found) -> bool {
+        let product = match self.connection {
+            ConnectionMode::Wired => found.product_id == WIRED_PRODUCT_ID,
+            ConnectionMode::Wireless => found.product_id == WIRELESS_PRODUCT_ID,
+            ConnectionMode::Auto => found.product_id == WIRED_PRODUCT_ID || found.product_id == WIRELESS_PRODUCT_ID,
+        };
+        found.vendor_id == VENDOR_ID
+            && product
+            && found.usage == 1
+            && found.usage_page == 0xff0a
+            && self.serial.as_ref().map_or(true, |serial| found.serial.as_ref() == Some(serial))
+            && self.path.as_ref().map_or(true, |path| found.path.to_str() == Ok(path.as_str()))
+    }
+
+    /// The index of the device in `found` to open: the first match not in
+    /// `claimed`, a wired one before a wireless one.
+    fn pick(&self, found: &[Found], claimed: &HashSet<CString>) -> Option<usize> {
+        found.iter().enumerate()
+            .filter(|(_, found)| self.matches(found) && !claimed.contains(&found.path))
+            .min_by_key(|(_, found)| found.product_id != WIRED_PRODUCT_ID)
+            .map(|(index, _)| index)
+    }
+}
+
+/// The devices open right now.  Everyone opening devices shares one, so two
+/// selectors matching the same device do not both get it.
+#[derive(Debug, Clone, Default)]
+pub struct Claims(Arc<Mutex<HashSet<CString>>>);
+
+/// A Quick Keys opened through hidapi.  It gives up its claim when dropped.
+pub struct QuickKeys {
+    device: HidDevice,
+    path: CString,
+    claims: Claims,
+}
+
+impl QuickKeys {
+    /// Open the first device matching `selector` that nobody has claimed yet.
+    pub fn open(api: &HidApi, selector: &Selector, claims: &Claims) -> QKResult<Self> {
+        let mut claimed = claims.0.lock().unwrap();
+        let infos: Vec<&DeviceInfo> = api.device_list().collect();
+        let found: Vec<Found> = infos.iter().map(|info| Found::from(*info)).collect();
+        let info = infos[selector.pick(&found, &claimed).ok_or(QKError::QKDeviceNotFound)?];
+        let device = info.open_device(api).map_err(|_| QKError::QKConnectionError)?;
+        for subscribe in subscribe_messages() {
+            device.write(&subscribe)?;
+        }
+        tracing::debug!("opened {:?} ({:?})", info.path(), info.serial_number());
+        claimed.insert(info.path().to_owned());
+        Ok(QuickKeys {
+            device,
+            path: info.path().to_owned(),
+            claims: claims.clone(),
+        })
+    }
+}
+
+impl Drop for QuickKeys {
+    fn drop(&mut self) {
+        self.claims.0.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// An output report: `header` up front, and `text` as UTF-16 from byte 16 on.
+fn message(header: &[u8], text: &str) -> [u8; 32] {
+    let mut body = [0u8; 32];
+    body[..header.len()].copy_from_slice(header);
+    let payload = text.encode_utf16().flat_map(|c| c.to_le_bytes());
+    for (byte, value) in body[16..].iter_mut().zip(payload) {
+        *byte = value;
+    }
+    body
+}
+
+/// The byte length of up to 8 characters once in UTF-16.
+fn text_length(text: &str) -> u8 {
+    (text.encode_utf16().count().min(8) * 2) as u8
+}
+
+/// Asking for key events and battery changes, sent on opening.
+fn subscribe_messages() -> [[u8; 32]; 2] {
+    [message(&[0x02, 0xb0, 0x04], ""), message(&[0x02, 0xb4, 0x10], "")]
+}
+
+fn orientation_message(orientation: ScreenOrientation) -> [u8; 32] {
+    message(&[0x02, 0xb1, orientation as u8], "")
+}
+
+fn brightness_message(level: ScreenBrightness) -> [u8; 32] {
+    message(&[0x02, 0xb1, 0x0a, 0x01, level as u8], "")
+}
+
+fn wheel_speed_message(speed: WheelSpeed) -> [u8; 32] {
+    message(&[0x02, 0xb4, 0x04, 0x01, 0x01, speed as u8], "")
+}
+
+fn sleep_timeout_message(minutes: u8) -> [u8; 32] {
+    message(&[0x02, 0xb4, 0x08, 0x01, minutes], "")
+}
+
+fn ring_color_message(red: u8, green: u8, blue: u8) -> [u8; 32] {
+    message(&[0x02, 0xb4, 0x01, 0x01, 0x00, 0x00, red, green, blue], "")
+}
+
+/// The label of key 0 to 7, up to 8 characters.
+fn key_text_message(key: u8, text: &str) -> [u8; 32] {
+    let text: String = text.chars().take(8).collect();
+    message(&[0x02, 0xb1, 0x00, key + 1, 0x00, text_length(&text)], &text)
+}
+
+/// A banner of up to 32 characters, sent 8 at a time.
+fn overlay_messages(text: &str, seconds: u8) -> Vec<[u8; 32]> {
+    let chars: Vec<char> = text.chars().take(32).collect();
+    let chunks: Vec<String> = chars.chunks(8).map(|chunk| chunk.iter().collect()).collect();
+    chunks.iter().enumerate()
+        .map(|(i, chunk)| {
+            let kind = if i == 0 { 0x05 } else { 0x06 };
+            let has_more = i > 0 && i + 1 < chunks.len();
+            message(&[0x02, 0xb1, kind, seconds, 0x00, text_length(chunk), has_more as u8], chunk)
+        })
+        .collect()
+}
+
+fn parse_input(data: &[u8; 10]) -> Event {
+    match data {
+        [0x02, 0xf0, ..] if data[7] & 0x01 > 0 => Event::Wheel { direction: WheelDirection::Right },
+        [0x02, 0xf0, ..] if data[7] & 0x02 > 0 => Event::Wheel { direction: WheelDirection::Left },
+        [0x02, 0xf0, keys1, keys2, ..] => Event::Button {
+            state: ButtonState {
+                button_0: keys1 & (1 << 0) > 0,
+                button_1: keys1 & (1 << 1) > 0,
+                button_2: keys1 & (1 << 2) > 0,
+                button_3: keys1 & (1 << 3) > 0,
+                button_4: keys1 & (1 << 4) > 0,
+                button_5: keys1 & (1 << 5) > 0,
+                button_6: keys1 & (1 << 6) > 0,
+                button_7: keys1 & (1 << 7) > 0,
+                button_extra: keys2 & (1 << 0) > 0,
+                button_wheel: keys2 & (1 << 1) > 0,
+            },
+        },
+        [0x02, 0xf2, 0x01, percent, ..] => Event::Battery { percent: *percent },
+        _ => Event::Unknown { data: *data },
+    }
+}
+
+impl Device for QuickKeys {
+    fn read_timeout(&self, timeout: i32) -> QKResult<Event> {
+        let mut buf = [0u8; 10];
+        self.device.read_timeout(&mut buf[..], timeout)?;
+        Ok(parse_input(&buf))
+    }
+
+    fn set_screen_orientation(&self, orientation: ScreenOrientation) -> QKResult<()> {
+        self.device.write(&orientation_message(orientation))?;
+        Ok(())
+    }
+
+    fn set_screen_brightness(&self, level: ScreenBrightness) -> QKResult<()> {
+        self.device.write(&brightness_message(level))?;
+        Ok(())
+    }
+
+    fn set_wheel_speed(&self, speed: WheelSpeed) -> QKResult<()> {
+        self.device.write(&wheel_speed_message(speed))?;
+        Ok(())
+    }
+
+    fn set_sleep_timeout(&self, minutes: u8) -> QKResult<()> {
+        self.device.write(&sleep_timeout_message(minutes))?;
+        Ok(())
+    }
+
+    fn set_ring_color(&self, red: u8, green: u8, blue: u8) -> QKResult<()> {
+        self.device.write(&ring_color_message(red, green, blue))?;
+        Ok(())
+    }
+
+    fn set_key_text(&self, key: u8, text: &str) -> QKResult<()> {
+        self.device.write(&key_text_message(key, text))?;
+        Ok(())
+    }
+
+    fn show_overlay_text(&self, text: &str, seconds: u8) -> QKResult<()> {
+        for chunk in overlay_messages(text, seconds) {
+            self.device.write(&chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(product_id: u16, serial: &str, path: &str) -> Found {
+        Found {
+            vendor_id: VENDOR_ID,
+            product_id,
+            usage: 1,
+            usage_page: 0xff0a,
+            serial: Some(serial.to_string()),
+            path: CString::new(path).unwrap(),
+        }
+    }
+
+    fn selector(serial: Option<&str>, path: Option<&str>, connection: ConnectionMode) -> Selector {
+        Selector { serial: serial.map(String::from), path: path.map(String::from), connection }
+    }
+
+    #[test]
+    fn selects_by_serial_and_path() {
+        let devices = [found(WIRED_PRODUCT_ID, "AAA", "/dev/hidraw1"), found(WIRED_PRODUCT_ID, "BBB", "/dev/hidraw2")];
+        let claimed = HashSet::new();
+        assert_eq!(selector(Some("BBB"), None, ConnectionMode::Auto).pick(&devices, &claimed), Some(1));
+        assert_eq!(selector(None, Some("/dev/hidraw1"), ConnectionMode::Auto).pick(&devices, &claimed), Some(0));
+        assert_eq!(selector(Some("BBB"), Some("/dev/hidraw1"), ConnectionMode::Auto).pick(&devices, &claimed), None);
+        assert_eq!(selector(Some("CCC"), None, ConnectionMode::Auto).pick(&devices, &claimed), None);
+    }
+
+    #[test]
+    fn selects_by_connection() {
+        let wired = found(WIRED_PRODUCT_ID, "AAA", "/dev/hidraw2");
+        let both = [found(WIRELESS_PRODUCT_ID, "AAA", "/dev/hidraw1"), wired.clone()];
+        let claimed = HashSet::new();
+        // A cable wins, and the dongle does when there is no cable
+        assert_eq!(selector(None, None, ConnectionMode::Auto).pick(&both, &claimed), Some(1));
+        assert_eq!(selector(None, None, ConnectionMode::Auto).pick(&both[..1], &claimed), Some(0));
+        assert_eq!(selector(None, None, ConnectionMode::Wireless).pick(&both, &claimed), Some(0));
+        assert_eq!(selector(None, None, ConnectionMode::Wired).pick(&both[..1], &claimed), None);
+
+        // Other devices and the other interfaces of this one never match
+        let mouse = Found { vendor_id: 0x046d, ..wired.clone() };
+        let keyboard_interface = Found { usage: 6, usage_page: 1, ..wired };
+        assert_eq!(selector(None, None, ConnectionMode::Auto).pick(&[mouse, keyboard_interface], &claimed), None);
+    }
+
+    #[test]
+    fn two_selectors_do_not_claim_the_same_device() {
+        let devices = [found(WIRED_PRODUCT_ID, "AAA", "/dev/hidraw1"), found(WIRED_PRODUCT_ID, "BBB", "/dev/hidraw2")];
+        let any = selector(None, None, ConnectionMode::Auto);
+        let claims = Claims::default();
+        let mut claimed = claims.0.lock().unwrap();
+        let first = any.pick(&devices, &claimed).unwrap();
+        claimed.insert(devices[first].path.clone());
+        let second = any.pick(&devices, &claimed).unwrap();
+        assert_ne!(first, second);
+        claimed.insert(devices[second].path.clone());
+        assert_eq!(any.pick(&devices, &claimed), None);
+        // Until one is given up
+        claimed.remove(&devices[first].path);
+        assert_eq!(any.pick(&devices, &claimed), Some(first));
+    }
+
+    /// A 32 byte report from its first bytes and the bytes from 16 on.
+    fn report(header: &[u8], text: &[u8]) -> [u8; 32] {
+        let mut report = [0u8; 32];
+        report[..header.len()].copy_from_slice(header);
+        report[16..16 + text.len()].copy_from_slice(text);
+        report
+    }
+
+    #[test]
+    fn parses_every_input_report() {
+        let buttons = parse_input(&[0x02, 0xf0, 0b1000_0101, 0b10, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(buttons, Event::Button { state: ButtonState {
+            button_0: true, button_2: true, button_7: true, button_wheel: true, ..ButtonState::default()
+        } });
+        assert_eq!(parse_input(&[0x02, 0xf0, 0, 0b01, 0, 0, 0, 0, 0, 0]), Event::Button { state: ButtonState { button_extra: true, ..ButtonState::default() } });
+        assert_eq!(parse_input(&[0x02, 0xf0, 0, 0, 0, 0, 0, 0x01, 0, 0]), Event::Wheel { direction: WheelDirection::Right });
+        assert_eq!(parse_input(&[0x02, 0xf0, 0, 0, 0, 0, 0, 0x02, 0, 0]), Event::Wheel { direction: WheelDirection::Left });
+        assert_eq!(parse_input(&[0x02, 0xf2, 0x01, 87, 0, 0, 0, 0, 0, 0]), Event::Battery { percent: 87 });
+        // Nothing read, and reports nobody knows
+        assert_eq!(parse_input(&[0; 10]), Event::Unknown { data: [0; 10] });
+        assert_eq!(parse_input(&[0x02, 0xf2, 0x02, 1, 0, 0, 0, 0, 0, 0]), Event::Unknown { data: [0x02, 0xf2, 0x02, 1, 0, 0, 0, 0, 0, 0] });
+    }
+
+    #[test]
+    fn writes_every_setting() {
+        assert_eq!(subscribe_messages(), [report(&[2, 176, 4], &[]), report(&[2, 180, 16], &[])]);
+        assert_eq!(orientation_message(ScreenOrientation::Rotate180), report(&[2, 177, 3], &[]));
+        assert_eq!(brightness_message(ScreenBrightness::Medium), report(&[2, 177, 10, 1, 2], &[]));
+        assert_eq!(wheel_speed_message(WheelSpeed::Slowest), report(&[2, 180, 4, 1, 1, 5], &[]));
+        assert_eq!(sleep_timeout_message(30), report(&[2, 180, 8, 1, 30], &[]));
+        assert_eq!(ring_color_message(255, 128, 0), report(&[2, 180, 1, 1, 0, 0, 255, 128, 0], &[]));
+    }
+
+    #[test]
+    fn writes_key_labels() {
+        assert_eq!(key_text_message(0, "Hi"), report(&[2, 177, 0, 1, 0, 4], &[b'H', 0, b'i', 0]));
+        assert_eq!(key_text_message(7, ""), report(&[2, 177, 0, 8, 0, 0], &[]));
+        // Only 8 characters fit in the 16 bytes after the header
+        assert_eq!(key_text_message(3, "Splitting"), report(&[2, 177, 0, 4, 0, 16], &[
+            b'S', 0, b'p', 0, b'l', 0, b'i', 0, b't', 0, b't', 0, b'i', 0, b'n', 0,
+        ]));
+        assert_eq!(key_text_message(1, "é→"), report(&[2, 177, 0, 2, 0, 4], &[0xe9, 0x00, 0x92, 0x21]));
+    }
+
+    #[test]
+    fn writes_banners_in_chunks() {
+        assert_eq!(overlay_messages("-- SHELL --", 2), [
+            report(&[2, 177, 5, 2, 0, 16, 0], &[b'-', 0, b'-', 0, b' ', 0, b'S', 0, b'H', 0, b'E', 0, b'L', 0, b'L', 0]),
+            report(&[2, 177, 6, 2, 0, 6, 0], &[b' ', 0, b'-', 0, b'-', 0]),
+        ]);
+        // Past 32 characters the text is cut off, the middle chunks say more is coming
+        let chunks = overlay_messages(&"x".repeat(40), 1);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.iter().map(|chunk| (chunk[2], chunk[5], chunk[6])).collect::<Vec<_>>(), [(5, 16, 0), (6, 16, 1), (6, 16, 1), (6, 16, 0)]);
+        assert!(overlay_messages("", 1).is_empty());
+    }
+}
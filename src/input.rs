@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+
+use enigo::{agent::{Agent, Token}, Direction, Enigo, InputResult};
+
+/// Where `Action::Input` tokens end up.
+pub trait Input {
+    fn execute(&mut self, token: &Token) -> InputResult<()>;
+}
+
+impl Input for Enigo {
+    fn execute(&mut self, token: &Token) -> InputResult<()> {
+        Agent::execute(self, token)
+    }
+}
+
+/// Keeps the tokens instead of typing them.  Clones share the same record.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingInput {
+    tokens: Arc<Mutex<Vec<Token>>>,
+}
+
+impl RecordingInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tokens(&self) -> Vec<Token> {
+        self.tokens.lock().unwrap().clone()
+    }
+}
+
+impl Input for RecordingInput {
+    fn execute(&mut self, token: &Token) -> InputResult<()> {
+        tracing::debug!("recorded input: {:?}", token);
+        self.tokens.lock().unwrap().push(token.clone());
+        Ok(())
+    }
+}
+
+/// Remembers the keys and mouse buttons pressed and not yet released
+/// through `inner`, so they are not left stuck when the daemon stops.
+pub struct HeldInput<I> {
+    inner: I,
+    // The tokens that would release them, oldest first
+    releases: Vec<Token>,
+}
+
+impl<I: Input> HeldInput<I> {
+    pub fn new(inner: I) -> Self {
+        HeldInput { inner, releases: Vec::new() }
+    }
+
+    /// Release everything still held, the last pressed first.
+    pub fn release_all(&mut self) -> InputResult<()> {
+        while let Some(release) = self.releases.pop() {
+            self.inner.execute(&release)?;
+        }
+        Ok(())
+    }
+}
+
+impl<I: Input> Input for HeldInput<I> {
+    fn execute(&mut self, token: &Token) -> InputResult<()> {
+        let (release, direction) = match token {
+            Token::Key(key, direction) => (Token::Key(*key, Direction::Release), direction),
+            Token::Raw(keycode, direction) => (Token::Raw(*keycode, Direction::Release), direction),
+            Token::Button(button, direction) => (Token::Button(*button, Direction::Release), direction),
+            _ => return self.inner.execute(token),
+        };
+        self.inner.execute(token)?;
+        match direction {
+            Direction::Press if !self.releases.contains(&release) => self.releases.push(release),
+            Direction::Release => self.releases.retain(|held| *held != release),
+            _ => {},
+        }
+        Ok(())
+    }
+}
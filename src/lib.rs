@@ -0,0 +1,18 @@
+pub mod config;
+pub mod actions;
+pub mod model;
+pub mod controller;
+pub mod executor;
+pub mod events;
+pub mod chords;
+pub mod state;
+pub mod server;
+pub mod device;
+pub mod hid;
+pub mod input;
+pub mod client;
+pub mod reload;
+pub mod check;
+pub mod focus;
+pub mod x11;
+pub mod sway;
@@ -1,12 +1,14 @@
-mod config;
-mod actions;
-mod model;
-mod controller;
-mod events;
-mod state;
-mod server;
+use std::path::{Path, PathBuf};
 
-use clap::{Command, Arg};
+use clap::{Command, Arg, ArgAction, ArgMatches};
+use enigo::{Enigo, Settings};
+use hidapi::HidApi;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use xencelabs_quick_keys::ConnectionMode;
+use qkeypie::{controller, server, device, client, reload, check, hid, focus, x11, sway};
+use qkeypie::actions::{Action, NonEnigoAction, ChangeRef, GoTo, MacroCall};
+use qkeypie::state::Status;
 
 fn cli() -> Command {
     let xdg_dirs = xdg::BaseDirectories::with_prefix("qkeypie").unwrap();
@@ -17,27 +19,255 @@ fn cli() -> Command {
         .arg(Arg::new("CONFIG")
             .help("The configuration file")
             .short('c')
+            .global(true)
             .default_value(config_file.to_str().unwrap().to_string()))
+        .arg(Arg::new("SOCKET")
+            .help("The control socket")
+            .long("socket")
+            .global(true)
+            .default_value(server::default_socket_path().to_str().unwrap().to_string()))
+        .arg(Arg::new("DEVICE")
+            .help("The device to talk to, the first one in the configuration by default")
+            .long("device")
+            .global(true))
+        .arg(Arg::new("CONNECTION")
+            .help("How to reach the devices, instead of the configured connection")
+            .long("connection")
+            .value_parser(["wired", "wireless", "auto"]))
+        .arg(Arg::new("FAKE_DEVICE")
+            .help("Run against an in-memory device instead of a real Quick Keys")
+            .long("fake-device")
+            .action(ArgAction::SetTrue))
         // .arg(arg!(--config <CONFIG> "The configuration file").short('c').default_value(config_file.to_str().unwrap().to_string()))
+        .subcommand(Command::new("status")
+            .about("Show the active profile, buttonset and wheel of the running daemon"))
+        .subcommand(Command::new("devices")
+            .about("Show the active profile, buttonset and wheel of every device"))
+        .subcommand(Command::new("profile")
+            .about("Switch to another profile")
+            .arg(Arg::new("TARGET").help("A profile name, or next, prev, first or last").required(true)))
+        .subcommand(Command::new("buttonset")
+            .about("Switch to another buttonset of the active profile")
+            .arg(Arg::new("TARGET").help("A buttonset name, or next, prev, first or last").required(true)))
+        .subcommand(Command::new("wheel")
+            .about("Switch to another wheel of the active profile")
+            .arg(Arg::new("TARGET").help("A wheel name, or next, prev, first or last").required(true)))
+        .subcommand(Command::new("swap")
+            .about("Go back to the previous profile, buttonset and wheel"))
+        .subcommand(Command::new("run-macro")
+            .about("Run a macro from the configuration")
+            .arg(Arg::new("MACRO").required(true))
+            .arg(Arg::new("ARGS").help("Arguments for the macro parameters, as name=value").num_args(0..)))
+        .subcommand(Command::new("banner")
+            .about("Show a text banner on the device")
+            .arg(Arg::new("SECONDS").value_parser(clap::value_parser!(u8)).required(true))
+            .arg(Arg::new("TEXT").required(true)))
+        .subcommand(Command::new("reload")
+            .about("Read the configuration file again"))
+        .subcommand(Command::new("check")
+            .about("Check the configuration file and report every problem found"))
+}
+
+fn print_status(status: &Status) {
+    println!("profile:   {} ({}/{})", status.profile.id, status.profile.index + 1, status.profile.available.len());
+    println!("buttonset: {} ({}/{})", status.buttonset.id, status.buttonset.index + 1, status.buttonset.available.len());
+    println!("wheel:     {} ({}/{})", status.wheel.id, status.wheel.index + 1, status.wheel.available.len());
+    for (name, value) in &status.variables {
+        println!("{name} = {value}");
+    }
+}
+
+/// A command line macro argument: a TOML value like `3` or `true`, or else a plain string.
+fn macro_arg(arg: &str) -> anyhow::Result<(String, toml::Value)> {
+    let (param, value) = arg.split_once('=').ok_or_else(|| anyhow::anyhow!("Expected name=value, got {}", arg))?;
+    let value = format!("value = {}", value).parse::<toml::Table>().ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    Ok((param.to_string(), value))
+}
+
+fn check_main(config: &Path) -> anyhow::Result<()> {
+    let diagnostics = check::check_file(config)?;
+    for diagnostic in &diagnostics {
+        println!("{}:{}", config.display(), diagnostic);
+    }
+    let errors = diagnostics.iter().filter(|d| d.severity == check::Severity::Error).count();
+    if errors > 0 {
+        anyhow::bail!("{} errors in {}", errors, config.display());
+    }
+    println!("{}: ok ({} warnings)", config.display(), diagnostics.len());
+    Ok(())
+}
+
+async fn client_main(socket: &Path, subcommand: &str, matches: &ArgMatches) -> anyhow::Result<()> {
+    let target = || client::change_ref(matches.get_one::<String>("TARGET").unwrap());
+    let device = matches.get_one::<String>("DEVICE").map(String::as_str);
+    let status = match subcommand {
+        "status" => client::status(socket, device).await?,
+        "devices" => {
+            for (name, status) in client::devices(socket).await? {
+                println!("[{}]", name);
+                print_status(&status);
+            }
+            return Ok(());
+        },
+        "profile" => client::goto(socket, device, GoTo::Switch(target(), ChangeRef::First, ChangeRef::First)).await?,
+        "buttonset" => client::goto(socket, device, GoTo::Switch(ChangeRef::This, target(), ChangeRef::This)).await?,
+        "wheel" => client::goto(socket, device, GoTo::Switch(ChangeRef::This, ChangeRef::This, target())).await?,
+        "swap" => client::goto(socket, device, GoTo::Swap).await?,
+        "run-macro" => {
+            let name = matches.get_one::<String>("MACRO").unwrap().clone();
+            let call = match matches.get_many::<String>("ARGS") {
+                Some(args) => {
+                    let args = args.map(|arg| macro_arg(arg)).collect::<anyhow::Result<_>>()?;
+                    MacroCall::WithArgs { name, args }
+                },
+                None => MacroCall::Name(name),
+            };
+            client::run_actions(socket, device, vec![Action::NonEnigo(NonEnigoAction::Macro(call))]).await?
+        },
+        "banner" => {
+            let seconds = *matches.get_one::<u8>("SECONDS").unwrap();
+            let text = matches.get_one::<String>("TEXT").unwrap().clone();
+            client::run_actions(socket, device, vec![Action::NonEnigo(NonEnigoAction::ShowBanner(seconds, text))]).await?
+        },
+        "reload" => client::reload(socket).await?,
+        _ => unreachable!("unknown subcommand {}", subcommand),
+    };
+    print_status(&status);
+    Ok(())
+}
+
+/// Stop every controller cleanly on SIGINT or SIGTERM.  A second signal stops right away.
+fn shutdown_on_signal(controller: controller::Handle) -> anyhow::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {},
+            _ = terminate.recv() => {},
+        }
+        tracing::info!("shutting down");
+        let shutdown = async {
+            for device in controller.devices() {
+                if let Err(err) = controller.request_device(device, controller::Command::Shutdown).await {
+                    tracing::error!("{device}: {err:#}");
+                }
+            }
+        };
+        tokio::select! {
+            _ = shutdown => {},
+            _ = interrupt.recv() => std::process::exit(130),
+            _ = terminate.recv() => std::process::exit(143),
+        }
+    });
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let matches = cli().get_matches();
 
-    // start server::main() in a separate thread
-    server::main().await;
+    if let Some(("check", sub_matches)) = matches.subcommand() {
+        return check_main(Path::new(sub_matches.get_one::<String>("CONFIG").unwrap()));
+    }
 
-    let controller = tokio::spawn(async move {
-        // start the controller in the main thread
-        let cfg = config::read_config(matches.get_one::<String>("CONFIG").unwrap())?;
-        let model = model::from_config(cfg)?;
-        controller::run(model)
-    }); 
+    if let Some((subcommand, sub_matches)) = matches.subcommand() {
+        let socket = sub_matches.get_one::<String>("SOCKET").unwrap();
+        return client_main(Path::new(socket), subcommand, sub_matches).await;
+    }
 
-    // wait for the controller to finish
-    controller.await??;
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "debug".into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
 
-    Ok(())
+    let config_path = PathBuf::from(matches.get_one::<String>("CONFIG").unwrap());
+    let model = reload::load(&config_path)?;
+
+    let devices: Vec<String> = model.devices.keys().cloned().collect();
+    let (handle, commands) = controller::channels(&devices);
+
+    // the server, the config watcher and the signal handler run in the background
+    let socket = PathBuf::from(matches.get_one::<String>("SOCKET").unwrap());
+    reload::watch(config_path.clone(), handle.clone())?;
+    shutdown_on_signal(handle.clone())?;
+    // sway first, as XWayland sets DISPLAY too
+    match sway::SwayWindows::connect() {
+        Ok(windows) => focus::watch(windows, handle.clone()),
+        Err(sway_err) => match x11::X11Windows::connect() {
+            Ok(windows) => focus::watch(windows, handle.clone()),
+            Err(x11_err) => tracing::info!("not following the focus: {sway_err:#}, {x11_err:#}"),
+        },
+    }
+    server::serve(socket.clone(), handle.clone(), config_path).await?;
+
+    // every device gets a controller, and a thread of its own as it blocks on the device
+    let fake_device = matches.get_flag("FAKE_DEVICE");
+    let connection = matches.get_one::<String>("CONNECTION").map(|connection| match connection.as_str() {
+        "wired" => ConnectionMode::Wired,
+        "wireless" => ConnectionMode::Wireless,
+        _ => ConnectionMode::Auto,
+    });
+    let claims = hid::Claims::default();
+    let mut controllers = tokio::task::JoinSet::new();
+    for commands in commands {
+        let device_model = model.devices[commands.device()].clone();
+        let model = model.for_device(commands.device())?;
+        let claims = claims.clone();
+        controllers.spawn_blocking(move || {
+            let enigo = Enigo::new(&Settings::default()).unwrap_or_else(|e| panic!("Failed to create enigo: {:?}", e));
+            if fake_device {
+                let dev = device::FakeDevice::new();
+                controller::run(model, || Ok(dev.connect()?), enigo, commands)
+            } else {
+                let selector = hid::Selector {
+                    serial: device_model.serial,
+                    path: device_model.path,
+                    connection: connection.unwrap_or(device_model.connection),
+                };
+                // a fresh HidApi each time, so a device plugged in meanwhile is found
+                controller::run(model, || Ok(hid::QuickKeys::open(&HidApi::new()?, &selector, &claims)?), enigo, commands)
+            }
+        });
+    }
+
+    // wait for the controllers to finish, taking the rest down when one fails
+    let mut failure = None;
+    while let Some(result) = controllers.join_next().await {
+        let Err(err) = result.map_err(anyhow::Error::from).and_then(|result| result) else {
+            continue;
+        };
+        if failure.is_some() {
+            tracing::error!("{err:#}");
+            continue;
+        }
+        for device in handle.devices() {
+            // the one that failed, and any other that stopped, is not there to answer
+            let _ = handle.request_device(device, controller::Command::Shutdown).await;
+        }
+        failure = Some(err);
+    }
+    let _ = std::fs::remove_file(socket);
+
+    failure.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macro_args_are_toml_values_or_strings() {
+        assert_eq!(macro_arg("count=3").unwrap(), ("count".to_string(), toml::Value::Integer(3)));
+        assert_eq!(macro_arg("loud=true").unwrap(), ("loud".to_string(), toml::Value::Boolean(true)));
+        assert_eq!(macro_arg("name=world").unwrap(), ("name".to_string(), toml::Value::String("world".to_string())));
+        assert_eq!(macro_arg("name=\"quoted\"").unwrap(), ("name".to_string(), toml::Value::String("quoted".to_string())));
+        assert_eq!(macro_arg("expr=a=b").unwrap(), ("expr".to_string(), toml::Value::String("a=b".to_string())));
+        assert_eq!(macro_arg("empty=").unwrap(), ("empty".to_string(), toml::Value::String(String::new())));
+        assert_eq!(macro_arg("world").unwrap_err().to_string(), "Expected name=value, got world");
+    }
 }
-    
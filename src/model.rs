@@ -1,138 +1,611 @@
 use indexmap::IndexMap;
+use xencelabs_quick_keys::ConnectionMode;
 
-use crate::actions::{Action, WheelCallback, WheelSetCallback, ButtonSet, ButtonCallback, ButtonSetCallback, ProfileCallback, WheelId, ButtonId, ButtonSetId, ProfileId, MacroId, ActiveCallback};
-use crate::actions::NonEnigoAction;
-use crate::config::Config;
+use crate::actions::{Action, WheelCallback, WheelSetCallback, ButtonSet, ButtonCallback, ButtonSetCallback, Shift, ProfileCallback, WheelId, ButtonId, ButtonSetId, ProfileId, MacroId, VariableId, Macro, MacroCall, ActiveCallback, Callback};
+use crate::actions::{NonEnigoAction, Conditional, Condition, WhichButton, Toggle, ChangeRef};
+use crate::config::{Config, CallbackConfig, DeviceConfig};
+use crate::focus::FocusRule;
+use crate::events::Timings;
+use crate::chords;
 
-type Actions = Vec<Action>;
+type Actions = Callback;
 
-type ButtonSetModel = ButtonSetCallback<ButtonCallback<Actions>,Actions>;
 type WheelSetModel = WheelSetCallback<Actions>;
+/// Buttons the layer leaves out stay what they are in the buttonset
+pub type ShiftModel = Shift<Option<ButtonCallback<Actions>>, Option<WheelSetModel>>;
+pub type ButtonSetModel = ButtonSetCallback<ButtonCallback<Actions>,Actions,ShiftModel>;
 pub type ProfileModel = ProfileCallback<IndexMap<ButtonSetId, ButtonSetModel>, IndexMap<WheelId, WheelSetModel>, ButtonCallback<Actions>, Actions>;
 
+// The one device driven when the config has no `[devices]` section
+pub const DEFAULT_DEVICE: &str = "default";
+
+/// A Quick Keys to drive, found by serial number, path and connection type.
+#[derive(Debug, Clone)]
+pub struct DeviceModel {
+    pub serial: Option<String>,
+    pub path: Option<String>,
+    pub connection: ConnectionMode,
+    pub profiles: Option<Vec<ProfileId>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Model {
     pub server: ActiveCallback<Actions>,
+    pub devices: IndexMap<String, DeviceModel>,
     pub profiles: IndexMap<ProfileId, ProfileModel>,
+    pub macros: IndexMap<MacroId, Macro>,
+    pub variables: IndexMap<VariableId, toml::Value>,
+    pub timings: Timings,
+    pub focus: Vec<FocusRule>,
 }
 
-fn replace_macros(opt: &Option<Actions>, macros: &IndexMap<MacroId, Actions>) -> Actions {
-    opt.clone().unwrap_or_default().into_iter().flat_map(|action| {
+impl Model {
+    /// The model as seen by `device`, with only its profiles, in its order.
+    pub fn for_device(&self, device: &str) -> anyhow::Result<Model> {
+        let Some(device_model) = self.devices.get(device) else {
+            anyhow::bail!("Unknown device {}", device);
+        };
+        let mut model = self.clone();
+        if let Some(profile_ids) = &device_model.profiles {
+            model.profiles = profile_ids.iter()
+                .map(|profile_id| match self.profiles.get(profile_id) {
+                    Some(profile) => Ok((profile_id.clone(), profile.clone())),
+                    None => Err(anyhow::anyhow!("Device {} has an unknown profile {}", device, profile_id)),
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        Ok(model)
+    }
+}
+
+/// Put the arguments in place of `${param}` in every string.
+fn substitute(value: &toml::Value, args: &IndexMap<String, toml::Value>) -> toml::Value {
+    match value {
+        toml::Value::String(text) => {
+            // A lone placeholder takes the argument as is, so it can be a number or a table
+            let lone = text.strip_prefix("${").and_then(|rest| rest.strip_suffix('}'));
+            if let Some(arg) = lone.and_then(|param| args.get(param)) {
+                return arg.clone();
+            }
+            let mut text = text.clone();
+            for (param, arg) in args {
+                let arg = match arg {
+                    toml::Value::String(arg) => arg.clone(),
+                    other => other.to_string(),
+                };
+                text = text.replace(&format!("${{{}}}", param), &arg);
+            }
+            toml::Value::String(text)
+        },
+        toml::Value::Array(items) => toml::Value::Array(items.iter().map(|item| substitute(item, args)).collect()),
+        toml::Value::Table(table) => toml::Value::Table(table.iter().map(|(key, item)| (key.clone(), substitute(item, args))).collect()),
+        other => other.clone(),
+    }
+}
+
+fn call_macro(call: &MacroCall, macros: &IndexMap<MacroId, Macro>, stack: &mut Vec<MacroId>) -> anyhow::Result<Vec<Action>> {
+    let macro_id = call.name();
+    if stack.contains(macro_id) {
+        anyhow::bail!("Macro cycle: {} -> {}", stack.join(" -> "), macro_id);
+    }
+    let no_args = IndexMap::new();
+    let args = match call {
+        MacroCall::Name(_) => &no_args,
+        MacroCall::WithArgs { args, .. } => args,
+    };
+    let body = match macros.get(macro_id) {
+        Some(Macro::Actions(actions)) => {
+            if let Some(arg) = args.keys().next() {
+                anyhow::bail!("Macro {} has no parameter {}", macro_id, arg);
+            }
+            actions.clone()
+        },
+        Some(Macro::Template { params, actions }) => {
+            if let Some(param) = params.iter().find(|param| !args.contains_key(*param)) {
+                anyhow::bail!("Macro {} needs an argument for {}", macro_id, param);
+            }
+            if let Some(arg) = args.keys().find(|arg| !params.contains(arg)) {
+                anyhow::bail!("Macro {} has no parameter {}", macro_id, arg);
+            }
+            actions.iter()
+                .map(|action| substitute(action, args).try_into().map_err(|e| anyhow::anyhow!("Macro {}: {}", macro_id, e)))
+                .collect::<anyhow::Result<Vec<Action>>>()?
+        },
+        None => anyhow::bail!("Macro {} not found", macro_id),
+    };
+    stack.push(macro_id.clone());
+    let expanded = expand_macros(&body, macros, stack);
+    stack.pop();
+    expanded
+}
+
+fn expand_macros(actions: &[Action], macros: &IndexMap<MacroId, Macro>, stack: &mut Vec<MacroId>) -> anyhow::Result<Vec<Action>> {
+    let mut expanded = Vec::new();
+    for action in actions {
         match action {
-            Action::NonEnigo(NonEnigoAction::Macro(macro_id)) => {
-                match macros.get(&macro_id) {
-                    Some(actions) => actions.clone(),
-                    None => {
-                        println!("Macro {} not found", macro_id);
-                        Vec::new()
-                    },
-                }
-            },
-            _ => vec![action],
+            Action::NonEnigo(NonEnigoAction::Macro(call)) => expanded.extend(call_macro(call, macros, stack)?),
+            Action::NonEnigo(NonEnigoAction::If(conditional)) => expanded.push(Action::NonEnigo(NonEnigoAction::If(Conditional {
+                when: conditional.when.clone(),
+                then: expand_macros(&conditional.then, macros, stack)?,
+                otherwise: expand_macros(&conditional.otherwise, macros, stack)?,
+            }))),
+            _ => expanded.push(action.clone()),
         }
-    }).collect()
+    }
+    Ok(expanded)
+}
+
+/// Replace every `Macro` action with the actions of the macro, to any depth.
+pub fn replace_macros(actions: &[Action], macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<Vec<Action>> {
+    expand_macros(actions, macros, &mut Vec::new())
+}
+
+fn callback(opt: &Option<CallbackConfig>, macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<Callback> {
+    match opt {
+        Some(CallbackConfig::Actions(actions)) => Ok(Callback {
+            concurrency: Default::default(),
+            actions: replace_macros(actions, macros)?,
+        }),
+        Some(CallbackConfig::Options { concurrency, actions }) => Ok(Callback {
+            concurrency: *concurrency,
+            actions: replace_macros(actions, macros)?,
+        }),
+        None => Ok(Callback::default()),
+    }
+}
+
+fn click_n(cfg: &IndexMap<String, Option<CallbackConfig>>, macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<IndexMap<String, Callback>> {
+    cfg.iter()
+        .map(|(count, cfg_callback)| {
+            if count.parse::<u8>().is_err() {
+                anyhow::bail!("Bad click count {} in on_click_n", count);
+            }
+            Ok((count.clone(), callback(cfg_callback, macros)?))
+        })
+        .collect()
+}
+
+fn is_toggle(button: &ButtonCallback<Actions>) -> bool {
+    button.toggle.is_some() || !button.on_toggle_on.actions.is_empty() || !button.on_toggle_off.actions.is_empty()
+}
+
+/// The variable a toggle keeps its state in, `toggle.POSITION` unless the
+/// config names one.  The position says where the button is bound, like
+/// `buttonset.tmux.button0` or `profile.shell.wheel.volume`, so the same
+/// button in two places has two latches.
+fn toggle_variable(button: &ButtonCallback<Actions>, position: &str) -> VariableId {
+    button.toggle.as_ref().and_then(|toggle| toggle.variable.clone()).unwrap_or_else(|| format!("toggle.{}", position))
+}
+
+/// Make a toggle out of `on_press` and `on_enter`: the press flips the
+/// variable and runs the callback for the new state, both show its label.
+/// The variable is written down in `toggle`.
+fn with_toggle(mut button: ButtonCallback<Actions>, position: &str) -> ButtonCallback<Actions> {
+    if !is_toggle(&button) {
+        return button;
+    }
+    let variable = toggle_variable(&button, position);
+    let toggle = Toggle { variable: Some(variable.clone()), ..button.toggle.clone().unwrap_or_default() };
+    let label = |text: &Option<String>| -> Vec<Action> {
+        text.iter().map(|text| Action::NonEnigo(NonEnigoAction::SetButtonText(WhichButton::ThisButton, text.clone()))).collect()
+    };
+    let show = Action::NonEnigo(NonEnigoAction::If(Conditional {
+        when: Condition::Variable(variable.clone()),
+        then: label(&toggle.on),
+        otherwise: label(&toggle.off),
+    }));
+    let flip = Action::NonEnigo(NonEnigoAction::If(Conditional {
+        when: Condition::Variable(variable.clone()),
+        then: label(&toggle.on).into_iter().chain(button.on_toggle_on.actions.iter().cloned()).collect(),
+        otherwise: label(&toggle.off).into_iter().chain(button.on_toggle_off.actions.iter().cloned()).collect(),
+    }));
+    button.active.on_enter.actions.push(show);
+    button.on_press.actions.splice(0..0, [Action::NonEnigo(NonEnigoAction::ToggleVariable(variable)), flip]);
+    button.toggle = Some(toggle);
+    button
 }
 
-fn get_button_by_id(cfg: &Config, id: &ButtonId, macros: &IndexMap<MacroId, Actions>) -> anyhow::Result<ButtonCallback<Actions>> {
+/// The eight buttons of `buttonset`, in order.
+fn all<T>(buttonset: &ButtonSet<T>) -> [&T; 8] {
+    [
+        &buttonset.button0, &buttonset.button1, &buttonset.button2, &buttonset.button3,
+        &buttonset.button4, &buttonset.button5, &buttonset.button6, &buttonset.button7,
+    ]
+}
+
+/// The variables of the toggles in `profile`.
+fn toggle_variables(profile: &ProfileModel) -> Vec<VariableId> {
+    let layers = profile.buttonsets.values().filter_map(|buttonset| buttonset.shift.as_ref());
+    let wheels = profile.wheels.values().chain(layers.clone().filter_map(|layer| layer.wheel.as_ref()));
+    std::iter::once(&profile.button)
+        .chain(wheels.map(|wheel| &wheel.wheel.button))
+        .chain(profile.buttonsets.values().flat_map(|buttonset| all(&buttonset.buttonset)))
+        .chain(layers.flat_map(|layer| all(&layer.buttonset).into_iter().flatten()))
+        .filter_map(|button| button.toggle.as_ref()?.variable.clone())
+        .collect()
+}
+
+fn get_button_by_id(cfg: &Config, position: &str, id: &ButtonId, macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<ButtonCallback<Actions>> {
     let cfg_button = cfg.buttons.as_ref().and_then(|buttons| buttons.get(id)).ok_or_else(|| anyhow::anyhow!("Button {} not found", id))?;
+    if cfg_button.timings.chord.is_some() {
+        anyhow::bail!("Button {} has a chord timing, which only goes in [timings]", id);
+    }
 
     let button : ButtonCallback<Actions> = ButtonCallback {
-        on_press: replace_macros(&cfg_button.on_press, macros),
-        on_release: replace_macros(&cfg_button.on_release, macros),
-        on_click_press: replace_macros(&cfg_button.on_click_press, macros),
-        on_click: replace_macros(&cfg_button.on_click, macros),
-        on_click_release: replace_macros(&cfg_button.on_click_release, macros),
-        on_double_click_press: replace_macros(&cfg_button.on_double_click_press, macros),
-        on_double_click: replace_macros(&cfg_button.on_double_click, macros),
-        on_double_click_release: replace_macros(&cfg_button.on_double_click_release, macros),
-        on_triple_click_press: replace_macros(&cfg_button.on_triple_click_press, macros),
-        on_triple_click: replace_macros(&cfg_button.on_triple_click, macros),
-        on_triple_click_release: replace_macros(&cfg_button.on_triple_click_release, macros),
-        on_long_press: replace_macros(&cfg_button.on_long_press, macros),
+        on_press: callback(&cfg_button.on_press, macros)?,
+        on_release: callback(&cfg_button.on_release, macros)?,
+        on_click_press: callback(&cfg_button.on_click_press, macros)?,
+        on_click: callback(&cfg_button.on_click, macros)?,
+        on_click_release: callback(&cfg_button.on_click_release, macros)?,
+        on_double_click_press: callback(&cfg_button.on_double_click_press, macros)?,
+        on_double_click: callback(&cfg_button.on_double_click, macros)?,
+        on_double_click_release: callback(&cfg_button.on_double_click_release, macros)?,
+        on_triple_click_press: callback(&cfg_button.on_triple_click_press, macros)?,
+        on_triple_click: callback(&cfg_button.on_triple_click, macros)?,
+        on_triple_click_release: callback(&cfg_button.on_triple_click_release, macros)?,
+        on_click_n: click_n(&cfg_button.on_click_n, macros)?,
+        on_long_press: callback(&cfg_button.on_long_press, macros)?,
+        on_hold_repeat: callback(&cfg_button.on_hold_repeat, macros)?,
+        on_toggle_on: callback(&cfg_button.on_toggle_on, macros)?,
+        on_toggle_off: callback(&cfg_button.on_toggle_off, macros)?,
+        toggle: cfg_button.toggle.clone(),
+        timings: cfg_button.timings,
         active: ActiveCallback {
-            on_enter: replace_macros(&cfg_button.active.on_enter, macros),
-            on_exit: replace_macros(&cfg_button.active.on_exit, macros),
+            on_enter: callback(&cfg_button.active.on_enter, macros)?,
+            on_exit: callback(&cfg_button.active.on_exit, macros)?,
         },
     };
-    Ok(button)
+    Ok(with_toggle(button, position))
 }
 
-fn get_button(cfg: &Config, id: &Option<ButtonId>, macros: &IndexMap<MacroId, Actions>) -> anyhow::Result<ButtonCallback<Actions>> {
+fn get_button(cfg: &Config, position: &str, id: &Option<ButtonId>, macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<ButtonCallback<Actions>> {
     match id {
-        Some(id) => get_button_by_id(cfg, id, macros),
+        Some(id) => get_button_by_id(cfg, position, id, macros),
         None => Ok(ButtonCallback::default()),
     }
 }
 
-fn get_wheel(cfg: &Config, id: &WheelId, macros: &IndexMap<MacroId, Actions>) -> anyhow::Result<WheelSetCallback<Actions>> {
+fn get_wheel(cfg: &Config, position: &str, id: &WheelId, macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<WheelSetCallback<Actions>> {
     let cfg_wheel = cfg.wheels.as_ref().and_then(|wheels| wheels.get(id)).ok_or_else(|| anyhow::anyhow!("Wheel {} not found", id))?;
+    if cfg_wheel.wheel.button.timings.chord.is_some() {
+        anyhow::bail!("Wheel {} has a chord timing, which only goes in [timings]", id);
+    }
 
-    let wheel = WheelSetCallback {
+    let mut wheel = WheelSetCallback {
         wheel: WheelCallback {
-            on_clockwise: replace_macros(&cfg_wheel.wheel.on_clockwise, macros),
-            on_clockwise_start: replace_macros(&cfg_wheel.wheel.on_clockwise_start, macros),
-            on_clockwise_stop: replace_macros(&cfg_wheel.wheel.on_clockwise_stop, macros),
-            on_counterclockwise: replace_macros(&cfg_wheel.wheel.on_counterclockwise, macros),
-            on_counterclockwise_start: replace_macros(&cfg_wheel.wheel.on_counterclockwise_start, macros),
-            on_counterclockwise_stop: replace_macros(&cfg_wheel.wheel.on_counterclockwise_stop, macros),
+            on_clockwise: callback(&cfg_wheel.wheel.on_clockwise, macros)?,
+            on_clockwise_start: callback(&cfg_wheel.wheel.on_clockwise_start, macros)?,
+            on_clockwise_stop: callback(&cfg_wheel.wheel.on_clockwise_stop, macros)?,
+            on_counterclockwise: callback(&cfg_wheel.wheel.on_counterclockwise, macros)?,
+            on_counterclockwise_start: callback(&cfg_wheel.wheel.on_counterclockwise_start, macros)?,
+            on_counterclockwise_stop: callback(&cfg_wheel.wheel.on_counterclockwise_stop, macros)?,
             button: ButtonCallback {
-                on_press: replace_macros(&cfg_wheel.wheel.button.on_press, macros),
-                on_release: replace_macros(&cfg_wheel.wheel.button.on_release, macros),
-                on_click_press: replace_macros(&cfg_wheel.wheel.button.on_click_press, macros),
-                on_click: replace_macros(&cfg_wheel.wheel.button.on_click, macros),
-                on_click_release: replace_macros(&cfg_wheel.wheel.button.on_click_release, macros),
-                on_double_click_press: replace_macros(&cfg_wheel.wheel.button.on_double_click_press, macros),
-                on_double_click: replace_macros(&cfg_wheel.wheel.button.on_double_click, macros),
-                on_double_click_release: replace_macros(&cfg_wheel.wheel.button.on_double_click_release, macros),
-                on_triple_click_press: replace_macros(&cfg_wheel.wheel.button.on_triple_click_press, macros),
-                on_triple_click: replace_macros(&cfg_wheel.wheel.button.on_triple_click, macros),
-                on_triple_click_release: replace_macros(&cfg_wheel.wheel.button.on_triple_click_release, macros),
-                on_long_press: replace_macros(&cfg_wheel.wheel.button.on_long_press, macros),
+                on_press: callback(&cfg_wheel.wheel.button.on_press, macros)?,
+                on_release: callback(&cfg_wheel.wheel.button.on_release, macros)?,
+                on_click_press: callback(&cfg_wheel.wheel.button.on_click_press, macros)?,
+                on_click: callback(&cfg_wheel.wheel.button.on_click, macros)?,
+                on_click_release: callback(&cfg_wheel.wheel.button.on_click_release, macros)?,
+                on_double_click_press: callback(&cfg_wheel.wheel.button.on_double_click_press, macros)?,
+                on_double_click: callback(&cfg_wheel.wheel.button.on_double_click, macros)?,
+                on_double_click_release: callback(&cfg_wheel.wheel.button.on_double_click_release, macros)?,
+                on_triple_click_press: callback(&cfg_wheel.wheel.button.on_triple_click_press, macros)?,
+                on_triple_click: callback(&cfg_wheel.wheel.button.on_triple_click, macros)?,
+                on_triple_click_release: callback(&cfg_wheel.wheel.button.on_triple_click_release, macros)?,
+                on_click_n: click_n(&cfg_wheel.wheel.button.on_click_n, macros)?,
+                on_long_press: callback(&cfg_wheel.wheel.button.on_long_press, macros)?,
+                on_hold_repeat: callback(&cfg_wheel.wheel.button.on_hold_repeat, macros)?,
+                on_toggle_on: callback(&cfg_wheel.wheel.button.on_toggle_on, macros)?,
+                on_toggle_off: callback(&cfg_wheel.wheel.button.on_toggle_off, macros)?,
+                toggle: cfg_wheel.wheel.button.toggle.clone(),
+                timings: cfg_wheel.wheel.button.timings,
                 active: ActiveCallback {
-                    on_enter: replace_macros(&cfg_wheel.wheel.button.active.on_enter, macros),
-                    on_exit: replace_macros(&cfg_wheel.wheel.button.active.on_exit, macros),
+                    on_enter: callback(&cfg_wheel.wheel.button.active.on_enter, macros)?,
+                    on_exit: callback(&cfg_wheel.wheel.button.active.on_exit, macros)?,
                 },
             },
         },
         active: ActiveCallback {
-            on_enter: replace_macros(&cfg_wheel.active.on_enter, macros),
-            on_exit: replace_macros(&cfg_wheel.active.on_exit, macros),
+            on_enter: callback(&cfg_wheel.active.on_enter, macros)?,
+            on_exit: callback(&cfg_wheel.active.on_exit, macros)?,
         },
     };
 
+    wheel.wheel.button = with_toggle(wheel.wheel.button, position);
     Ok(wheel)
 }
 
-fn get_buttonset(cfg: &Config, id: &ButtonSetId, macros: &IndexMap<MacroId, Actions>) -> anyhow::Result<ButtonSetCallback<ButtonCallback<Actions>,Actions>> {
+fn get_shift(cfg: &Config, id: &ButtonSetId, shift: &Shift<Option<ButtonId>, Option<WheelId>>, macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<ShiftModel> {
+    if shift.hold == WhichButton::ThisButton {
+        anyhow::bail!("The shift of buttonset {} has to be held with a button of its own", id);
+    }
+    let button = |button_id: &Option<ButtonId>, position: &str| {
+        button_id.as_ref().map(|button_id| get_button_by_id(cfg, &format!("buttonset.{}.shift.{}", id, position), button_id, macros)).transpose()
+    };
+    Ok(Shift {
+        hold: shift.hold.clone(),
+        buttonset: ButtonSet {
+            button0: button(&shift.buttonset.button0, "button0")?,
+            button1: button(&shift.buttonset.button1, "button1")?,
+            button2: button(&shift.buttonset.button2, "button2")?,
+            button3: button(&shift.buttonset.button3, "button3")?,
+            button4: button(&shift.buttonset.button4, "button4")?,
+            button5: button(&shift.buttonset.button5, "button5")?,
+            button6: button(&shift.buttonset.button6, "button6")?,
+            button7: button(&shift.buttonset.button7, "button7")?,
+        },
+        wheel: shift.wheel.as_ref().map(|wheel_id| get_wheel(cfg, &format!("buttonset.{}.shift.wheel", id), wheel_id, macros)).transpose()?,
+    })
+}
+
+fn get_buttonset(cfg: &Config, id: &ButtonSetId, macros: &IndexMap<MacroId, Macro>) -> anyhow::Result<ButtonSetModel> {
     let cfg_buttonset = cfg.buttonsets.as_ref().and_then(|buttonsets| buttonsets.get(id)).ok_or_else(|| anyhow::anyhow!("Buttonset {} not found", id))?;
 
     let buttonset = ButtonSetCallback {
         buttonset: ButtonSet {
-            button0: get_button(cfg, &cfg_buttonset.buttonset.button0, macros)?,
-            button1: get_button(cfg, &cfg_buttonset.buttonset.button1, macros)?,
-            button2: get_button(cfg, &cfg_buttonset.buttonset.button2, macros)?,
-            button3: get_button(cfg, &cfg_buttonset.buttonset.button3, macros)?,
-            button4: get_button(cfg, &cfg_buttonset.buttonset.button4, macros)?,
-            button5: get_button(cfg, &cfg_buttonset.buttonset.button5, macros)?,
-            button6: get_button(cfg, &cfg_buttonset.buttonset.button6, macros)?,
-            button7: get_button(cfg, &cfg_buttonset.buttonset.button7, macros)?,
+            button0: get_button(cfg, &format!("buttonset.{}.button0", id), &cfg_buttonset.buttonset.button0, macros)?,
+            button1: get_button(cfg, &format!("buttonset.{}.button1", id), &cfg_buttonset.buttonset.button1, macros)?,
+            button2: get_button(cfg, &format!("buttonset.{}.button2", id), &cfg_buttonset.buttonset.button2, macros)?,
+            button3: get_button(cfg, &format!("buttonset.{}.button3", id), &cfg_buttonset.buttonset.button3, macros)?,
+            button4: get_button(cfg, &format!("buttonset.{}.button4", id), &cfg_buttonset.buttonset.button4, macros)?,
+            button5: get_button(cfg, &format!("buttonset.{}.button5", id), &cfg_buttonset.buttonset.button5, macros)?,
+            button6: get_button(cfg, &format!("buttonset.{}.button6", id), &cfg_buttonset.buttonset.button6, macros)?,
+            button7: get_button(cfg, &format!("buttonset.{}.button7", id), &cfg_buttonset.buttonset.button7, macros)?,
         },
+        chords: cfg_buttonset.chords.iter()
+            .map(|(name, cfg_callback)| {
+                if chords::parse(name).is_none() {
+                    anyhow::bail!("Bad chord {} in buttonset {}, it should be like button0+button4", name, id);
+                }
+                Ok((name.clone(), callback(cfg_callback, macros)?))
+            })
+            .collect::<anyhow::Result<_>>()?,
+        shift: cfg_buttonset.shift.as_ref().map(|shift| get_shift(cfg, id, shift, macros)).transpose()?,
         active: ActiveCallback {
-            on_enter: replace_macros(&cfg_buttonset.active.on_enter, macros),
-            on_exit: replace_macros(&cfg_buttonset.active.on_exit, macros),
+            on_enter: callback(&cfg_buttonset.active.on_enter, macros)?,
+            on_exit: callback(&cfg_buttonset.active.on_exit, macros)?,
         },
     };
 
     Ok(buttonset)
 }
 
+/// The names of the macros a template calls, found in its TOML as
+/// `{ Macro = "name" }` or `{ Macro = { name = "name", ... } }` at any depth.
+fn template_calls(value: &toml::Value, calls: &mut Vec<MacroId>) {
+    match value {
+        toml::Value::Table(table) => {
+            match table.get("Macro") {
+                Some(toml::Value::String(name)) => calls.push(name.clone()),
+                Some(toml::Value::Table(call)) => calls.extend(call.get("name").and_then(|name| name.as_str()).map(String::from)),
+                _ => {},
+            }
+            table.values().for_each(|item| template_calls(item, calls));
+        },
+        toml::Value::Array(items) => items.iter().for_each(|item| template_calls(item, calls)),
+        _ => {},
+    }
+}
+
+/// The macros `actions` call, in `If` branches too.
+fn action_calls(actions: &[Action], calls: &mut Vec<MacroId>) {
+    for action in actions {
+        match action {
+            Action::NonEnigo(NonEnigoAction::Macro(call)) => calls.push(call.name().clone()),
+            Action::NonEnigo(NonEnigoAction::If(conditional)) => {
+                action_calls(&conditional.then, calls);
+                action_calls(&conditional.otherwise, calls);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Follow the calls of `macro_id` through `macros`, failing on the first one
+/// that comes back to a macro on `stack`.  Names a template only gets from its
+/// `${param}` cannot be followed.
+fn check_macro_cycles(macro_id: &MacroId, macros: &IndexMap<MacroId, Macro>, stack: &mut Vec<MacroId>, checked: &mut Vec<MacroId>) -> anyhow::Result<()> {
+    if stack.contains(macro_id) {
+        anyhow::bail!("Macro cycle: {} -> {}", stack.join(" -> "), macro_id);
+    }
+    if checked.contains(macro_id) {
+        return Ok(());
+    }
+    let mut calls = Vec::new();
+    match macros.get(macro_id) {
+        Some(Macro::Actions(actions)) => action_calls(actions, &mut calls),
+        Some(Macro::Template { actions, .. }) => actions.iter().for_each(|action| template_calls(action, &mut calls)),
+        None if macro_id.contains("${") => return Ok(()),
+        None => anyhow::bail!("Macro {} not found", macro_id),
+    }
+    stack.push(macro_id.clone());
+    for call in &calls {
+        check_macro_cycles(call, macros, stack, checked)?;
+    }
+    stack.pop();
+    checked.push(macro_id.clone());
+    Ok(())
+}
+
+/// Every callback of `button`, `on_click_n` included.
+fn button_callbacks(button: &ButtonCallback<Actions>) -> Vec<&Actions> {
+    let mut callbacks = vec![
+        &button.on_press, &button.on_release,
+        &button.on_click_press, &button.on_click, &button.on_click_release,
+        &button.on_double_click_press, &button.on_double_click, &button.on_double_click_release,
+        &button.on_triple_click_press, &button.on_triple_click, &button.on_triple_click_release,
+        &button.on_long_press, &button.on_hold_repeat,
+        &button.on_toggle_on, &button.on_toggle_off,
+        &button.active.on_enter, &button.active.on_exit,
+    ];
+    callbacks.extend(button.on_click_n.values());
+    callbacks
+}
+
+fn wheel_callbacks(wheel: &WheelSetModel) -> Vec<&Actions> {
+    let mut callbacks = vec![
+        &wheel.wheel.on_clockwise, &wheel.wheel.on_clockwise_start, &wheel.wheel.on_clockwise_stop,
+        &wheel.wheel.on_counterclockwise, &wheel.wheel.on_counterclockwise_start, &wheel.wheel.on_counterclockwise_stop,
+        &wheel.active.on_enter, &wheel.active.on_exit,
+    ];
+    callbacks.extend(button_callbacks(&wheel.wheel.button));
+    callbacks
+}
+
+/// Every callback that can run while `profile` is active.
+fn profile_callbacks(profile: &ProfileModel) -> Vec<&Actions> {
+    let mut callbacks = vec![&profile.active.on_enter, &profile.active.on_exit];
+    callbacks.extend(button_callbacks(&profile.button));
+    for buttonset in profile.buttonsets.values() {
+        callbacks.extend(all(&buttonset.buttonset).into_iter().flat_map(button_callbacks));
+        callbacks.extend(buttonset.chords.values());
+        callbacks.extend([&buttonset.active.on_enter, &buttonset.active.on_exit]);
+        if let Some(layer) = &buttonset.shift {
+            callbacks.extend(all(&layer.buttonset).into_iter().flatten().flat_map(button_callbacks));
+            callbacks.extend(layer.wheel.iter().flat_map(wheel_callbacks));
+        }
+    }
+    callbacks.extend(profile.wheels.values().flat_map(wheel_callbacks));
+    callbacks
+}
+
+/// Check that a `Name` target of `action` is a buttonset or wheel in each of
+/// `profiles`, or in any profile when the profile it runs in is not known.
+fn check_target(model: &Model, action: &str, kind: &str, target: &ChangeRef, profiles: Option<&[&ProfileId]>) -> anyhow::Result<()> {
+    let ChangeRef::Name(name) = target else { return Ok(()) };
+    let has = |profile: &ProfileModel| match kind {
+        "buttonset" => profile.buttonsets.contains_key(name),
+        _ => profile.wheels.contains_key(name),
+    };
+    match profiles {
+        Some(profiles) => {
+            if let Some(profile) = profiles.iter().find(|id| !model.profiles.get(**id).is_some_and(has)) {
+                anyhow::bail!("{} target {} is not a {} of profile {}", action, name, kind, profile);
+            }
+        },
+        None => {
+            if !model.profiles.values().any(has) {
+                anyhow::bail!("{} target {} is not a {} of any profile", action, name, kind);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn check_condition(model: &Model, condition: &Condition) -> anyhow::Result<()> {
+    match condition {
+        Condition::Profile(name) if !model.profiles.contains_key(name) => {
+            anyhow::bail!("If condition profile {} is not a profile", name);
+        },
+        Condition::ButtonSet(name) if !model.profiles.values().any(|profile| profile.buttonsets.contains_key(name)) => {
+            anyhow::bail!("If condition buttonset {} is not a buttonset of any profile", name);
+        },
+        Condition::Wheel(name) if !model.profiles.values().any(|profile| profile.wheels.contains_key(name)) => {
+            anyhow::bail!("If condition wheel {} is not a wheel of any profile", name);
+        },
+        Condition::All(conditions) | Condition::Any(conditions) => {
+            conditions.iter().try_for_each(|condition| check_condition(model, condition))?;
+        },
+        Condition::Not(condition) => check_condition(model, condition)?,
+        _ => {},
+    }
+    Ok(())
+}
+
+/// Check that the profiles, buttonsets and wheels `actions` switch to or ask
+/// about exist, when run in one of `profiles` or in any profile when `None`.
+fn check_names(model: &Model, actions: &[Action], profiles: Option<&[&ProfileId]>) -> anyhow::Result<()> {
+    for action in actions {
+        let Action::NonEnigo(action) = action else { continue };
+        match action {
+            NonEnigoAction::ChangeProfile(profile, buttonset, wheel) => {
+                let profiles = match profile {
+                    ChangeRef::Name(name) => match model.profiles.get_key_value(name) {
+                        Some((id, _)) => Some(vec![id]),
+                        None => anyhow::bail!("ChangeProfile target {} is not a profile", name),
+                    },
+                    ChangeRef::This => profiles.map(<[_]>::to_vec),
+                    _ => None,
+                };
+                check_target(model, "ChangeProfile", "buttonset", buttonset, profiles.as_deref())?;
+                check_target(model, "ChangeProfile", "wheel", wheel, profiles.as_deref())?;
+            },
+            NonEnigoAction::ChangeButtonSet(target) => check_target(model, "ChangeButtonSet", "buttonset", target, profiles)?,
+            NonEnigoAction::ChangeWheel(target) => check_target(model, "ChangeWheel", "wheel", target, profiles)?,
+            NonEnigoAction::If(conditional) => {
+                check_condition(model, &conditional.when)?;
+                check_names(model, &conditional.then, profiles)?;
+                check_names(model, &conditional.otherwise, profiles)?;
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Reject models that would leave the controller stuck, like a profile with nothing to switch to.
+pub fn validate(model: &Model) -> anyhow::Result<()> {
+    if model.profiles.is_empty() {
+        anyhow::bail!("No profiles");
+    }
+    if model.devices.is_empty() {
+        anyhow::bail!("No devices");
+    }
+    for (device_id, device) in &model.devices {
+        if device.profiles.as_ref().is_some_and(|profiles| profiles.is_empty()) {
+            anyhow::bail!("Device {} has no profiles", device_id);
+        }
+        model.for_device(device_id)?;
+    }
+    for rule in &model.focus {
+        let Some(profile) = model.profiles.get(&rule.profile) else {
+            anyhow::bail!("Focus rule for unknown profile {}", rule.profile);
+        };
+        if let Some(buttonset) = rule.buttonset.as_ref().filter(|id| !profile.buttonsets.contains_key(*id)) {
+            anyhow::bail!("Focus rule for unknown buttonset {} of profile {}", buttonset, rule.profile);
+        }
+        if let Some(wheel) = rule.wheel.as_ref().filter(|id| !profile.wheels.contains_key(*id)) {
+            anyhow::bail!("Focus rule for unknown wheel {} of profile {}", wheel, rule.profile);
+        }
+    }
+    // Templates are only expanded when called, so every macro is walked here,
+    // including the ones nothing calls
+    let mut checked = Vec::new();
+    for macro_id in model.macros.keys() {
+        check_macro_cycles(macro_id, &model.macros, &mut Vec::new(), &mut checked)?;
+    }
+    for (profile_id, profile) in &model.profiles {
+        if profile.buttonsets.is_empty() {
+            anyhow::bail!("Profile {} has no buttonsets", profile_id);
+        }
+        if profile.wheels.is_empty() {
+            anyhow::bail!("Profile {} has no wheels", profile_id);
+        }
+    }
+    for callback in [&model.server.on_enter, &model.server.on_exit] {
+        check_names(model, &callback.actions, None)?;
+    }
+    for (profile_id, profile) in &model.profiles {
+        for callback in profile_callbacks(profile) {
+            check_names(model, &callback.actions, Some(&[profile_id]))?;
+        }
+    }
+    Ok(())
+}
+
 pub fn from_config(cfg: Config) -> anyhow::Result<Model> {
     let mut profiles = IndexMap::new();
 
-    let mut macros = IndexMap::new();
+    let cfg_macros = cfg.macros.clone().unwrap_or_default();
 
-    for (cfg_macro_name, cfg_macro_actions) in cfg.macros.clone().unwrap_or_default() {
-        macros.insert(cfg_macro_name, cfg_macro_actions.unwrap_or_default());
+    // Expanding every macro up front finds the cycles, even in macros nobody uses.
+    // Macros with parameters can only be expanded once they are called.
+    let mut macros = IndexMap::new();
+    for (macro_id, cfg_macro) in &cfg_macros {
+        let expanded = match cfg_macro {
+            Macro::Actions(actions) => Macro::Actions(expand_macros(actions, &cfg_macros, &mut vec![macro_id.clone()])?),
+            template => template.clone(),
+        };
+        macros.insert(macro_id.clone(), expanded);
     }
 
     for (cfg_profile_name, cfg_profile) in cfg.clone().profiles.unwrap_or_default() {
@@ -145,27 +618,288 @@ pub fn from_config(cfg: Config) -> anyhow::Result<Model> {
         }
 
         for (cfg_wheel_name, cfg_wheel_id) in cfg_profile.wheels.unwrap_or_default() {
-            let wheel = get_wheel(&cfg, &cfg_wheel_id, &macros)?;
+            let wheel = get_wheel(&cfg, &format!("profile.{}.wheel.{}", cfg_profile_name, cfg_wheel_name), &cfg_wheel_id, &macros)?;
             wheels.insert(cfg_wheel_name, wheel);
         }
 
+        let button = get_button(&cfg, &format!("profile.{}.button", cfg_profile_name), &cfg_profile.button, &macros)?;
         profiles.insert(cfg_profile_name, ProfileModel {
             buttonsets,
             wheels,
             active: ActiveCallback {
-                on_enter: replace_macros(&cfg_profile.active.on_enter, &macros),
-                on_exit: replace_macros(&cfg_profile.active.on_exit, &macros),
+                on_enter: callback(&cfg_profile.active.on_enter, &macros)?,
+                on_exit: callback(&cfg_profile.active.on_exit, &macros)?,
             },
-            button: get_button(&cfg, &cfg_profile.button, &macros)?,
+            button,
         });
     }
 
+    // Toggles start off, unless the config says otherwise
+    let mut variables = cfg.variables.clone().unwrap_or_default();
+    for variable in profiles.values().flat_map(toggle_variables) {
+        variables.entry(variable).or_insert(toml::Value::Boolean(false));
+    }
+
+    let cfg_defaults = cfg.device.clone().unwrap_or_default();
+    let cfg_devices = cfg.devices.clone()
+        .unwrap_or_else(|| IndexMap::from([(DEFAULT_DEVICE.to_string(), DeviceConfig::default())]));
+    let devices = cfg_devices.into_iter()
+        .map(|(device_id, cfg_device)| (device_id, DeviceModel {
+            serial: cfg_device.serial.or(cfg_defaults.serial.clone()),
+            path: cfg_device.path.or(cfg_defaults.path.clone()),
+            connection: cfg_device.connection.or(cfg_defaults.connection).unwrap_or(ConnectionMode::Auto),
+            profiles: cfg_device.profiles.or(cfg_defaults.profiles.clone()),
+        }))
+        .collect();
+
     Ok(Model {
         server: ActiveCallback {
-            on_enter: replace_macros(&cfg.server.clone().unwrap_or_default().on_enter, &macros),
-            on_exit: replace_macros(&cfg.server.unwrap_or_default().on_exit, &macros),
+            on_enter: callback(&cfg.server.clone().unwrap_or_default().on_enter, &macros)?,
+            on_exit: callback(&cfg.server.unwrap_or_default().on_exit, &macros)?,
         },
+        devices,
         profiles,
+        macros,
+        variables,
+        timings: cfg.timings.unwrap_or_default(),
+        focus: cfg.focus.unwrap_or_default(),
     })
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [buttons.mute]
+        toggle = { on = "Unmute", off = "Mute" }
+        [buttons.caps]
+        toggle = { variable = "caps" }
+        [buttons.plain]
+
+        [wheels.volume]
+        on_toggle_on = [{ Debug = "muted" }]
+
+        [buttonsets.tmux]
+        button0 = "mute"
+        button1 = "caps"
+        button2 = "plain"
+        button4 = "mute"
+        shift = { hold = "Button7", button3 = "mute" }
+        [buttonsets.neovim]
+        button0 = "mute"
+        button1 = "caps"
+
+        [profiles.shell]
+        button = "mute"
+        [profiles.shell.buttonsets]
+        tmux = "tmux"
+        neovim = "neovim"
+        [profiles.shell.wheels]
+        volume = "volume"
+        [profiles.media.buttonsets]
+        tmux = "tmux"
+        [profiles.media.wheels]
+        volume = "volume"
+
+        [variables]
+        caps = true
+    "#;
+
+    #[test]
+    fn toggles_latch_where_they_are_bound() {
+        let model = from_config(toml::from_str(CONFIG).unwrap()).unwrap();
+        let variables: Vec<_> = model.variables.iter().map(|(name, value)| format!("{name}={value}")).collect();
+        assert_eq!(variables, [
+            "caps=true",
+            "toggle.profile.shell.button=false",
+            "toggle.profile.shell.wheel.volume=false",
+            "toggle.buttonset.tmux.button0=false",
+            "toggle.buttonset.tmux.button4=false",
+            "toggle.buttonset.neovim.button0=false",
+            "toggle.buttonset.tmux.shift.button3=false",
+            "toggle.profile.media.wheel.volume=false",
+        ]);
+
+        // The same button in two positions of one buttonset, and in its layer
+        let tmux = &model.profiles["shell"].buttonsets["tmux"];
+        assert_eq!(variable(&tmux.buttonset.button0).as_deref(), Some("toggle.buttonset.tmux.button0"));
+        assert_eq!(variable(&tmux.buttonset.button4).as_deref(), Some("toggle.buttonset.tmux.button4"));
+        assert_eq!(variable(tmux.shift.as_ref().unwrap().buttonset.button3.as_ref().unwrap()).as_deref(), Some("toggle.buttonset.tmux.shift.button3"));
+        assert_eq!(variable(&tmux.buttonset.button1).as_deref(), Some("caps"));
+        assert_eq!(variable(&tmux.buttonset.button2), None);
+    }
+
+    fn variable(button: &ButtonCallback<Actions>) -> Option<VariableId> {
+        button.toggle.as_ref().and_then(|toggle| toggle.variable.clone())
+    }
+
+    #[test]
+    fn toggles_of_a_profile_and_buttonset_of_the_same_name_latch_apart() {
+        let model = from_config(toml::from_str(r#"
+            [buttons.mute]
+            toggle = { on = "Unmute", off = "Mute" }
+            [buttonsets.tmux]
+            button0 = "mute"
+            [wheels.tmux]
+            [profiles.tmux]
+            button = "mute"
+            [profiles.tmux.buttonsets]
+            tmux = "tmux"
+            [profiles.tmux.wheels]
+            tmux = "tmux"
+        "#).unwrap()).unwrap();
+        let profile = &model.profiles["tmux"];
+        assert_eq!(variable(&profile.button).as_deref(), Some("toggle.profile.tmux.button"));
+        assert_eq!(variable(&profile.buttonsets["tmux"].buttonset.button0).as_deref(), Some("toggle.buttonset.tmux.button0"));
+        assert_eq!(model.variables.len(), 2);
+    }
+
+    const PROFILE: &str = r#"
+        [buttonsets.main]
+        [wheels.main]
+        [profiles.main.buttonsets]
+        main = "main"
+        [profiles.main.wheels]
+        main = "main"
+    "#;
+
+    /// Load `macros` next to a minimal profile, like `reload::load` does.
+    fn load(macros: &str) -> anyhow::Result<Model> {
+        let model = from_config(toml::from_str(&format!("{PROFILE}\n{macros}"))?)?;
+        validate(&model)?;
+        Ok(model)
+    }
+
+    #[test]
+    fn finds_direct_macro_cycles() {
+        let err = load(r#"
+            [macros]
+            again = [{ Debug = "again" }, { Macro = "again" }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro cycle: again -> again");
+
+        // Also in a template nothing calls
+        let err = load(r#"
+            [macros.again]
+            params = ["text"]
+            actions = [{ Debug = "${text}" }, { Macro = { name = "again", args = { text = "${text}" } } }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro cycle: again -> again");
+    }
+
+    #[test]
+    fn finds_indirect_macro_cycles() {
+        let err = load(r#"
+            [macros]
+            ping = [{ Macro = "pong" }]
+            pong = [{ Macro = "ping" }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro cycle: ping -> pong -> ping");
+
+        let err = load(r#"
+            [macros]
+            ping = [{ Macro = { name = "pong", args = { n = "1" } } }]
+            [macros.pong]
+            params = ["n"]
+            actions = [{ Debug = "${n}" }, { Macro = "ping" }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro cycle: ping -> pong -> ping");
+    }
+
+    #[test]
+    fn finds_macro_cycles_through_if() {
+        let err = load(r#"
+            [macros]
+            twice = [{ If = { when = { Variable = "again" }, then = [], else = [{ Macro = "twice" }] } }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro cycle: twice -> twice");
+
+        let err = load(r#"
+            [macros.outer]
+            params = ["text"]
+            actions = [{ If = { when = { Variable = "${text}" }, then = [{ Macro = { name = "inner", args = {} } }] } }]
+            [macros.inner]
+            params = []
+            actions = [{ Macro = { name = "outer", args = { text = "x" } } }]
+        "#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro cycle: outer -> inner -> outer");
+    }
+
+    /// What the profile button runs on a press of `on_press`, with `macros`, as JSON.
+    fn expand(macros: &str, on_press: &str) -> anyhow::Result<String> {
+        let model = load(&format!("[profiles.main]\nbutton = \"pressed\"\n[buttons.pressed]\non_press = {on_press}\n{macros}"))?;
+        Ok(serde_json::to_string(&model.profiles["main"].button.on_press.actions)?)
+    }
+
+    const GREET: &str = r#"
+        [macros.greet]
+        params = ["name", "times"]
+        actions = [
+            { If = { when = { Variable = "${name}" }, then = [{ Debug = "hello ${name}, ${times} times" }], else = [{ IncrementVariable = ["${name}", "${times}"] }] } },
+        ]
+    "#;
+
+    #[test]
+    fn substitutes_macro_args_in_nested_actions() {
+        let actions = expand(GREET, r#"[{ Macro = { name = "greet", args = { name = "world", times = 2 } } }]"#).unwrap();
+        // A lone placeholder keeps the number, inside a string it is written out
+        assert_eq!(actions, concat!(
+            r#"[{"If":{"when":{"Variable":"world"},"then":[{"Debug":"hello world, 2 times"}],"#,
+            r#""else":[{"IncrementVariable":["world",2]}]}}]"#,
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_and_unknown_macro_args() {
+        let err = expand(GREET, r#"[{ Macro = { name = "greet", args = { name = "world" } } }]"#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro greet needs an argument for times");
+
+        let err = expand(GREET, r#"[{ Macro = { name = "greet", args = { name = "world", times = 2, loud = true } } }]"#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro greet has no parameter loud");
+
+        let plain = "[macros]\nplain = [{ Debug = \"plain\" }]";
+        let err = expand(plain, r#"[{ Macro = { name = "plain", args = { name = "world" } } }]"#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro plain has no parameter name");
+    }
+
+    #[test]
+    fn rejects_macro_args_of_the_wrong_type() {
+        // A number where the action wants a string
+        let err = expand(GREET, r#"[{ Macro = { name = "greet", args = { name = 1, times = 2 } } }]"#).unwrap_err();
+        assert!(err.to_string().starts_with("Macro greet: invalid type: integer `1`, expected a string"), "{err}");
+    }
+
+    #[test]
+    fn templates_need_their_args() {
+        let err = expand(GREET, r#"[{ Macro = "greet" }]"#).unwrap_err();
+        assert_eq!(err.to_string(), "Macro greet needs an argument for name");
+
+        // Unless they have no parameters
+        let bare = "[macros.bare]\nparams = []\nactions = [{ Debug = \"bare\" }]";
+        assert_eq!(expand(bare, r#"[{ Macro = "bare" }]"#).unwrap(), r#"[{"Debug":"bare"}]"#);
+    }
+
+    #[test]
+    fn example_config_expands_the_tmux_template() {
+        let model = from_config(toml::from_str(include_str!("../config.toml")).unwrap()).unwrap();
+        validate(&model).unwrap();
+        let paste = &model.profiles["shell"].buttonsets["tmux"].buttonset.button7;
+        assert_eq!(serde_json::to_string(&paste.on_press.actions).unwrap(), concat!(
+            r#"[{"Key":["Control","Press"]},{"Key":[{"Unicode":"b"},"Click"]},{"Key":["Control","Release"]},"#,
+            r#"{"Key":[{"Unicode":"]"},"Click"]}]"#,
+        ));
+    }
+
+    #[test]
+    fn macros_without_cycles_load() {
+        // The same macro twice is no cycle
+        let model = load(r#"
+            [macros]
+            both = [{ Macro = "one" }, { Macro = "one" }]
+            one = [{ Debug = "one" }]
+        "#).unwrap();
+        assert_eq!(model.macros.len(), 2);
+    }
+}
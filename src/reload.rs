@@ -0,0 +1,170 @@
+//! Picking up configuration changes without restarting the daemon.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::controller::{Command, Handle};
+use crate::model::{self, Model};
+use crate::state::Status;
+use crate::config;
+
+// How often the configuration file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Read and check a configuration file, ready to be handed to a controller.
+pub fn load(path: &Path) -> anyhow::Result<Model> {
+    let cfg = config::read_config(&path.to_string_lossy())?;
+    let model = model::from_config(cfg)?;
+    model::validate(&model)?;
+    Ok(model)
+}
+
+/// Swap every controller over to the current contents of `path`, returning
+/// the status of the default device.  A broken file is rejected and the
+/// controllers keep running the old model.
+pub async fn reload(path: &Path, controller: &Handle) -> anyhow::Result<Status> {
+    let model = load(path).map_err(|e| e.context(format!("Rejected {}", path.display())))?;
+    // There is one controller per device, started with the daemon
+    if !model.devices.keys().eq(controller.devices()) {
+        anyhow::bail!("Rejected {}: adding, removing or reordering devices needs a restart", path.display());
+    }
+    // Every device gets its model before any swaps, so none is left behind on the old one
+    let models = controller.devices()
+        .map(|device| Ok((device, model.for_device(device)?)))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| e.context(format!("Rejected {}", path.display())))?;
+    let mut statuses = Vec::new();
+    for (device, model) in models {
+        statuses.push(controller.request_device(device, Command::Reload(Box::new(model))).await?);
+    }
+    Ok(statuses.remove(0))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reload in the background whenever `path` changes or the process gets a SIGHUP.
+pub fn watch(path: PathBuf, controller: Handle) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut last_modified = modified(&path);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    tracing::info!("SIGHUP received, reloading {}", path.display());
+                },
+                _ = interval.tick() => {
+                    let current = modified(&path);
+                    // A missing file is most likely an editor halfway through saving it
+                    if current.is_none() || current == last_modified {
+                        continue;
+                    }
+                    tracing::info!("{} changed, reloading", path.display());
+                },
+            }
+            last_modified = modified(&path);
+            match reload(&path, &controller).await {
+                Ok(status) => tracing::info!("reloaded, now on {}/{}/{}", status.profile.id, status.buttonset.id, status.wheel.id),
+                Err(err) => tracing::error!("{err:#}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::controller;
+    use crate::device::FakeDevice;
+    use crate::input::RecordingInput;
+
+    const CONFIG: &str = r#"
+        [devices.left]
+        [devices.right]
+
+        [buttonsets.main]
+        [wheels.main]
+        [profiles.main.buttonsets]
+        main = "main"
+        [profiles.main.wheels]
+        main = "main"
+    "#;
+
+    const GAMES: &str = r#"
+        [profiles.games.buttonsets]
+        main = "main"
+        [profiles.games.wheels]
+        main = "main"
+    "#;
+
+    fn write(file: &mut tempfile::NamedTempFile, config: &str) {
+        file.as_file().set_len(0).unwrap();
+        file.reopen().unwrap().write_all(config.as_bytes()).unwrap();
+    }
+
+    /// Controllers for every device in `file`, each on a fake device.
+    fn start(file: &tempfile::NamedTempFile) -> Handle {
+        let model = load(file.path()).unwrap();
+        let devices: Vec<String> = model.devices.keys().cloned().collect();
+        let (handle, commands) = controller::channels(&devices);
+        for commands in commands {
+            let model = model.for_device(commands.device()).unwrap();
+            let dev = FakeDevice::new();
+            std::thread::spawn(move || controller::run(model, || Ok(dev.connect()?), RecordingInput::new(), commands));
+        }
+        handle
+    }
+
+    async fn profiles(controller: &Handle) -> Vec<Vec<String>> {
+        let mut profiles = Vec::new();
+        for device in controller.devices() {
+            profiles.push(controller.request_device(device, Command::Status).await.unwrap().profile.available);
+        }
+        profiles
+    }
+
+    #[tokio::test]
+    async fn reloads_every_device() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write(&mut file, CONFIG);
+        let controller = start(&file);
+        write(&mut file, &format!("{CONFIG}{GAMES}"));
+        let status = reload(file.path(), &controller).await.unwrap();
+        assert_eq!(status.profile.available, ["main", "games"]);
+        assert_eq!(profiles(&controller).await, [["main", "games"], ["main", "games"]]);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_reload_leaves_every_device_alone() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write(&mut file, CONFIG);
+        let controller = start(&file);
+        // Fine for the left device, not for the right one
+        let broken = format!("{GAMES}{}", CONFIG.replace("[devices.right]", "[devices.right]\nprofiles = [\"racing\"]"));
+        write(&mut file, &broken);
+        let err = reload(file.path(), &controller).await.unwrap_err();
+        assert!(format!("{err:#}").contains("Device right has an unknown profile racing"), "{err:#}");
+        assert_eq!(profiles(&controller).await, [["main"], ["main"]]);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_change_the_devices() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write(&mut file, CONFIG);
+        let controller = start(&file);
+        for devices in ["[devices.left]", "[devices.left]\n[devices.middle]", "[devices.right]\n[devices.left]"] {
+            let config = format!("{GAMES}{}", CONFIG.replace("[devices.left]\n        [devices.right]", devices));
+            write(&mut file, &config);
+            let err = reload(file.path(), &controller).await.unwrap_err();
+            assert!(err.to_string().ends_with("adding, removing or reordering devices needs a restart"), "{err:#}");
+        }
+        assert_eq!(profiles(&controller).await, [["main"], ["main"]]);
+    }
+}
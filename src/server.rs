@@ -1,60 +1,91 @@
-//! Run with
+//! Control API served over a Unix domain socket.
 //!
 //! ```not_rust
-//! cargo run -p example-unix-domain-socket
+//! curl --unix-socket $XDG_RUNTIME_DIR/qkeypie.sock http://qkeypie/state
+//! curl --unix-socket $XDG_RUNTIME_DIR/qkeypie.sock http://qkeypie/goto \
+//!     -H 'Content-Type: application/json' -d '{"Switch": [{"Name": "shell"}, "First", "First"]}'
+//! curl --unix-socket $XDG_RUNTIME_DIR/qkeypie.sock -X POST http://qkeypie/reload
+//! curl --unix-socket $XDG_RUNTIME_DIR/qkeypie.sock http://qkeypie/events
+//! curl --unix-socket $XDG_RUNTIME_DIR/qkeypie.sock http://qkeypie/actions \
+//!     -H 'Content-Type: application/toml' -d 'actions = [ { ShowBanner = [ 2, "Build OK" ] } ]'
+//! curl --unix-socket $XDG_RUNTIME_DIR/qkeypie.sock http://qkeypie/devices
+//! curl --unix-socket $XDG_RUNTIME_DIR/qkeypie.sock 'http://qkeypie/state?device=left'
 //! ```
+//!
+//! Every request but `/devices` and `/reload` goes to the default device, the
+//! first one in the configuration, unless another one is given with `?device=`.
+
+use std::path::PathBuf;
 
 #[cfg(unix)]
-pub async fn main() {
-    unix::server().await;
+pub use unix::serve;
+
+/// `$XDG_RUNTIME_DIR/qkeypie.sock`, or the temporary directory when there is no runtime directory.
+pub fn default_socket_path() -> PathBuf {
+    xdg::BaseDirectories::new()
+        .ok()
+        .and_then(|dirs| dirs.get_runtime_directory().ok().cloned())
+        .unwrap_or_else(std::env::temp_dir)
+        .join("qkeypie.sock")
 }
 
 #[cfg(unix)]
 mod unix {
     use axum::{
         body::Body,
-        extract::connect_info::{self, ConnectInfo},
-        http::{Method, Request, StatusCode},
-        routing::get,
-        Router,
+        extract::{connect_info::{self, ConnectInfo}, Query, State},
+        http::{header, HeaderMap, Request, StatusCode},
+        response::{IntoResponse, Response},
+        routing::{get, post},
+        Json, Router,
     };
-    use http_body_util::BodyExt;
     use hyper::body::Incoming;
     use hyper_util::{
         rt::{TokioExecutor, TokioIo},
         server,
     };
+    use indexmap::IndexMap;
     use std::{convert::Infallible, path::PathBuf, sync::Arc};
     use tokio::net::{unix::UCred, UnixListener, UnixStream};
+    use tokio::sync::broadcast::error::RecvError;
     use tower::Service;
-    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-    pub async fn server() {
-        tracing_subscriber::registry()
-            .with(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| "debug".into()),
-            )
-            .with(tracing_subscriber::fmt::layer())
-            .init();
+    use crate::actions::{Action, GoTo};
+    use crate::controller::{Command, Handle};
+    use crate::reload;
+    use crate::state::Status;
 
-        let path = PathBuf::from("/tmp/axum/helloworld");
+    #[derive(Clone)]
+    struct App {
+        controller: Handle,
+        config: Arc<PathBuf>,
+    }
 
+    /// Bind the socket at `path` and answer requests in the background.
+    /// `config` is the file read again on `POST /reload`.
+    pub async fn serve(path: PathBuf, controller: Handle, config: PathBuf) -> anyhow::Result<()> {
         let _ = tokio::fs::remove_file(&path).await;
-        tokio::fs::create_dir_all(path.parent().unwrap())
-            .await
-            .unwrap();
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
 
-        let uds = UnixListener::bind(path.clone()).unwrap();
+        let uds = UnixListener::bind(path.clone())?;
+        tracing::info!("listening on {}", path.display());
         tokio::spawn(async move {
-            let app = Router::new().route("/", get(handler));
+            let app = router(App { controller, config: Arc::new(config) });
 
             let mut make_service = app.into_make_service_with_connect_info::<UdsConnectInfo>();
 
             // See https://github.com/tokio-rs/axum/blob/main/examples/serve-with-hyper/src/main.rs for
             // more details about this setup
             loop {
-                let (socket, _remote_addr) = uds.accept().await.unwrap();
+                let (socket, _remote_addr) = match uds.accept().await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::error!("failed to accept connection: {err:#}");
+                        continue;
+                    },
+                };
 
                 let tower_service = unwrap_infallible(make_service.call(&socket).await);
 
@@ -70,39 +101,124 @@ mod unix {
                         .serve_connection_with_upgrades(socket, hyper_service)
                         .await
                     {
-                        eprintln!("failed to serve connection: {err:#}");
+                        tracing::error!("failed to serve connection: {err:#}");
                     }
                 });
             }
         });
 
-        let stream = TokioIo::new(UnixStream::connect(path).await.unwrap());
-        let (mut sender, conn) = hyper::client::conn::http1::handshake(stream).await.unwrap();
-        tokio::task::spawn(async move {
-            if let Err(err) = conn.await {
-                println!("Connection failed: {:?}", err);
+        Ok(())
+    }
+
+    fn router(app: App) -> Router {
+        Router::new()
+            .route("/state", get(state))
+            .route("/goto", post(goto))
+            .route("/actions", post(actions))
+            .route("/events", get(events))
+            .route("/reload", post(reload))
+            .route("/devices", get(devices))
+            .with_state(app)
+    }
+
+    /// `?device=NAME`, for the default device when missing.
+    #[derive(serde::Deserialize)]
+    struct Target {
+        device: Option<String>,
+    }
+
+    impl App {
+        async fn request(&self, target: Target, command: Command) -> anyhow::Result<Status> {
+            match target.device {
+                Some(device) => self.controller.request_device(&device, command).await,
+                None => self.controller.request(command).await,
             }
-        });
+        }
+    }
+
+    async fn state(State(app): State<App>, ConnectInfo(info): ConnectInfo<UdsConnectInfo>, Query(target): Query<Target>) -> Response {
+        tracing::debug!("state requested by {:?}", info.peer_cred);
+        reply(app.request(target, Command::Status).await)
+    }
+
+    /// The status of every device, by name.
+    async fn devices(State(app): State<App>, ConnectInfo(info): ConnectInfo<UdsConnectInfo>) -> Response {
+        tracing::debug!("devices requested by {:?}", info.peer_cred);
+        let mut statuses = IndexMap::new();
+        for device in app.controller.devices() {
+            match app.controller.request_device(device, Command::Status).await {
+                Ok(status) => statuses.insert(device.clone(), status),
+                Err(err) => return reply::<Status>(Err(err)),
+            };
+        }
+        Json(statuses).into_response()
+    }
 
-        let request = Request::builder()
-            .method(Method::GET)
-            .uri("http://uri-doesnt-matter.com")
-            .body(Body::empty())
-            .unwrap();
+    async fn goto(State(app): State<App>, ConnectInfo(info): ConnectInfo<UdsConnectInfo>, Query(target): Query<Target>, Json(goto): Json<GoTo>) -> Response {
+        tracing::debug!("{:?} requested by {:?}", goto, info.peer_cred);
+        reply(app.request(target, Command::GoTo(goto)).await)
+    }
+
+    /// Every button and wheel event as newline-delimited JSON, until the client
+    /// hangs up.  With `?device=` only the events of that device.
+    async fn events(State(app): State<App>, ConnectInfo(info): ConnectInfo<UdsConnectInfo>, Query(target): Query<Target>) -> Response {
+        tracing::debug!("events requested by {:?}", info.peer_cred);
+        let stream = futures_util::stream::unfold((app.controller.subscribe(), target.device), |(mut rx, device)| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(record) if device.as_ref().is_some_and(|device| *device != record.device) => {},
+                    Ok(record) => {
+                        let line = match serde_json::to_string(&record) {
+                            Ok(json) => json + "\n",
+                            Err(err) => return Some((Err(err), (rx, device))),
+                        };
+                        return Some((Ok(line), (rx, device)));
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("event stream lagging, {} events skipped", skipped);
+                    },
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(stream)).into_response()
+    }
 
-        let response = sender.send_request(request).await.unwrap();
+    /// The body of `POST /actions` when sent as TOML, which has no top-level arrays.
+    #[derive(serde::Deserialize)]
+    struct ActionList {
+        actions: Vec<Action>,
+    }
 
-        assert_eq!(response.status(), StatusCode::OK);
+    /// Parse a list of actions, either a JSON array or a TOML `actions = [...]` document.
+    fn parse_actions(headers: &HeaderMap, body: &str) -> anyhow::Result<Vec<Action>> {
+        let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("application/json");
+        if content_type.starts_with("application/toml") {
+            Ok(toml::from_str::<ActionList>(body)?.actions)
+        } else {
+            Ok(serde_json::from_str(body)?)
+        }
+    }
 
-        let body = response.collect().await.unwrap().to_bytes();
-        let body = String::from_utf8(body.to_vec()).unwrap();
-        assert_eq!(body, "Hello, World!");
+    async fn actions(State(app): State<App>, ConnectInfo(info): ConnectInfo<UdsConnectInfo>, Query(target): Query<Target>, headers: HeaderMap, body: String) -> Response {
+        let actions = match parse_actions(&headers, &body) {
+            Ok(actions) => actions,
+            Err(err) => return (StatusCode::BAD_REQUEST, format!("{err:#}")).into_response(),
+        };
+        tracing::debug!("{:?} requested by {:?}", actions, info.peer_cred);
+        reply(app.request(target, Command::Run(actions)).await)
     }
 
-    async fn handler(ConnectInfo(info): ConnectInfo<UdsConnectInfo>) -> &'static str {
-        println!("new connection from `{:?}`", info);
+    async fn reload(State(app): State<App>, ConnectInfo(info): ConnectInfo<UdsConnectInfo>) -> Response {
+        tracing::debug!("reload of {} requested by {:?}", app.config.display(), info.peer_cred);
+        reply(reload::reload(&app.config, &app.controller).await)
+    }
 
-        "Hello, World!"
+    fn reply<T: serde::Serialize>(result: anyhow::Result<T>) -> Response {
+        match result {
+            Ok(value) => Json(value).into_response(),
+            Err(err) => (StatusCode::UNPROCESSABLE_ENTITY, format!("{err:#}")).into_response(),
+        }
     }
 
     #[derive(Clone, Debug)]
@@ -130,4 +246,101 @@ mod unix {
             Err(err) => match err {},
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use connect_info::Connected;
+        use tower::ServiceExt;
+
+        use crate::controller;
+        use crate::device::FakeDevice;
+        use crate::input::RecordingInput;
+        use crate::model;
+
+        const CONFIG: &str = r#"
+            [devices.left]
+            [devices.right]
+
+            [buttonsets.main]
+            [wheels.main]
+            [profiles.main.buttonsets]
+            main = "main"
+            [profiles.main.wheels]
+            main = "main"
+        "#;
+
+        /// The API of controllers for `left` and `right`, each on a fake device.
+        fn app() -> Router {
+            let model = model::from_config(toml::from_str(CONFIG).unwrap()).unwrap();
+            let devices: Vec<String> = model.devices.keys().cloned().collect();
+            let (handle, commands) = controller::channels(&devices);
+            for commands in commands {
+                let model = model.for_device(commands.device()).unwrap();
+                let dev = FakeDevice::new();
+                std::thread::spawn(move || controller::run(model, || Ok(dev.connect()?), RecordingInput::new(), commands));
+            }
+            router(App { controller: handle, config: Arc::new(PathBuf::from("/nonexistent/config.toml")) })
+        }
+
+        async fn send(app: &Router, method: &str, uri: &str, content_type: &str, body: &str) -> (StatusCode, String) {
+            let mut request = Request::builder().method(method).uri(uri).header(header::CONTENT_TYPE, content_type).body(Body::from(body.to_string())).unwrap();
+            // What the socket tells about the client
+            let (client, _server) = UnixStream::pair().unwrap();
+            request.extensions_mut().insert(ConnectInfo(UdsConnectInfo::connect_info(&client)));
+            let response = app.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        #[tokio::test]
+        async fn state_is_json() {
+            let app = app();
+            let (status, body) = send(&app, "GET", "/state", "application/json", "").await;
+            assert_eq!(status, StatusCode::OK);
+            let position = r#"{"id":"main","index":0,"last_id":null,"last_index":null,"available":["main"]}"#;
+            assert_eq!(body, format!(r#"{{"profile":{position},"buttonset":{position},"wheel":{position},"variables":{{}}}}"#));
+
+            let (status, body) = send(&app, "GET", "/devices", "application/json", "").await;
+            assert_eq!(status, StatusCode::OK);
+            let devices: IndexMap<String, Status> = serde_json::from_str(&body).unwrap();
+            assert_eq!(devices.keys().collect::<Vec<_>>(), ["left", "right"]);
+        }
+
+        #[tokio::test]
+        async fn goes_to_the_device_asked_for() {
+            let app = app();
+            let (status, _) = send(&app, "POST", "/goto?device=right", "application/json", r#"{"Switch": ["This", "This", "First"]}"#).await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, body) = send(&app, "GET", "/state?device=right", "application/json", "").await;
+            assert!(body.contains(r#""last_id":"main""#), "{body}");
+            let (_, body) = send(&app, "GET", "/state?device=left", "application/json", "").await;
+            assert!(body.contains(r#""last_id":null"#), "{body}");
+        }
+
+        #[tokio::test]
+        async fn rejects_what_cannot_be_done() {
+            let app = app();
+            assert_eq!(send(&app, "GET", "/state?device=middle", "application/json", "").await, (StatusCode::UNPROCESSABLE_ENTITY, "Unknown device middle".to_string()));
+            assert_eq!(
+                send(&app, "POST", "/goto", "application/json", r#"{"Switch": [{"Name": "games"}, "This", "This"]}"#).await,
+                (StatusCode::UNPROCESSABLE_ENTITY, "Profile games not found".to_string()),
+            );
+            assert_eq!(send(&app, "POST", "/actions?device=middle", "application/json", "[]").await.0, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        #[tokio::test]
+        async fn rejects_malformed_bodies() {
+            let app = app();
+            assert_eq!(send(&app, "POST", "/goto", "application/json", r#"{"Switch": "#).await.0, StatusCode::BAD_REQUEST);
+            assert_eq!(send(&app, "POST", "/goto", "application/json", r#"{"Jump": []}"#).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+            let (status, body) = send(&app, "POST", "/actions", "application/toml", "actions = [{ Dbug = 1 }]").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(body.contains("unknown action `Dbug`"), "{body}");
+            assert_eq!(send(&app, "POST", "/actions", "application/json", "[{").await.0, StatusCode::BAD_REQUEST);
+            // A file that is not there is no reason to stop
+            assert_eq!(send(&app, "POST", "/reload", "application/json", "").await.0, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
 }
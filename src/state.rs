@@ -1,8 +1,13 @@
+use std::time::Instant;
+
 use anyhow::Error;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 
 use crate::events;
 use crate::actions;
 use crate::model;
+use crate::chords;
 
 #[derive(Debug, Clone)]
 pub struct State {
@@ -25,6 +30,59 @@ pub struct State {
     pub buttonset_state: actions::ButtonSet<events::ButtonStateMachine>,
     pub wheel_state: actions::WheelSet<events::WheelStateMachine, events::ButtonStateMachine>,
     pub profilebutton_state: actions::ProfileButton<events::ButtonStateMachine>,
+    /// The focus rule applied last, see `focus::goto`
+    pub focus_rule: Option<usize>,
+    pub variables: IndexMap<actions::VariableId, toml::Value>,
+    pub chords: chords::Chords,
+    /// Whether the shift layer of the buttonset is on, see `actions::Shift`
+    pub shifted: bool,
+    /// The buttons last pressed while the shift layer was on, whose events
+    /// go to the layer until they are pressed again
+    pub shifted_presses: Vec<actions::WhichButton>,
+}
+
+/// Where one of the profile, buttonset or wheel selections currently is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Position {
+    pub id: String,
+    pub index: usize,
+    pub last_id: Option<String>,
+    pub last_index: Option<usize>,
+    pub available: Vec<String>,
+}
+
+/// A snapshot of the `State` for the outside world.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub profile: Position,
+    pub buttonset: Position,
+    pub wheel: Position,
+    #[serde(default)]
+    pub variables: IndexMap<actions::VariableId, toml::Value>,
+}
+
+type Button = actions::ButtonCallback<actions::Callback>;
+
+/// `base`, or the button of the shift layer standing in for it.
+fn layered<'a>(shift: Option<&'a model::ShiftModel>, which: actions::WhichButton, base: &'a Button, layer: impl Fn(&'a model::ShiftModel) -> &'a Option<Button>) -> &'a Button {
+    shift.filter(|shift| shift.hold != which).and_then(|shift| layer(shift).as_ref()).unwrap_or(base)
+}
+
+/// The timings of `button`, falling back to `global`, with hold repeats
+/// only when it has something to repeat.
+fn button_timings(button: &Button, global: &events::Timings) -> events::Timings {
+    events::Timings {
+        hold_repeat: !button.on_hold_repeat.actions.is_empty(),
+        ..button.timings.or(global)
+    }
+}
+
+/// How a variable reads inside a string: strings as they are, anything else as TOML.
+fn variable_text(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
 }
 
 impl State {
@@ -45,25 +103,276 @@ impl State {
             current_wheel_index: 0,
             last_wheel_id: None,
             last_wheel_index: None,
-            model,
             buttonset_state: actions::ButtonSet::default(),
             profilebutton_state: actions::ProfileButton::default(),
             wheel_state: actions::WheelSet::default(),
+            focus_rule: None,
+            variables: model.variables.clone(),
+            chords: chords::Chords::default(),
+            shifted: false,
+            shifted_presses: Vec::new(),
+            model,
         })
     }
 
+    /// Move to a new model, staying on the same profile, buttonset and wheel
+    /// when they still exist and falling back to the first ones otherwise.
+    pub fn reload(&self, model: model::Model) -> Result<State, anyhow::Error> {
+        let mut state = State::new(model)?;
+        state.buttonset_state = self.buttonset_state.clone();
+        state.wheel_state = self.wheel_state.clone();
+        state.profilebutton_state = self.profilebutton_state.clone();
+        // Values set meanwhile win over the ones in the config
+        state.variables.extend(self.variables.clone());
+        state.chords = self.chords.clone();
+
+        let Some(profile_index) = state.model.profiles.get_index_of(&self.current_profile_id) else {
+            return Ok(state);
+        };
+        state.current_profile_id = self.current_profile_id.clone();
+        state.current_profile_index = profile_index;
+        let profile = state.get_current_profile();
+        let (buttonset_index, buttonset_id) = match profile.buttonsets.get_index_of(&self.current_buttonset_id) {
+            Some(index) => (index, self.current_buttonset_id.clone()),
+            None => (0, profile.buttonsets.first().ok_or(Error::msg("No buttonsets"))?.0.clone()),
+        };
+        let (wheel_index, wheel_id) = match profile.wheels.get_index_of(&self.current_wheel_id) {
+            Some(index) => (index, self.current_wheel_id.clone()),
+            None => (0, profile.wheels.first().ok_or(Error::msg("No wheels"))?.0.clone()),
+        };
+        state.current_buttonset_id = buttonset_id;
+        state.current_buttonset_index = buttonset_index;
+        state.current_wheel_id = wheel_id;
+        state.current_wheel_index = wheel_index;
+        // A shift still held stays on, as long as the buttonset is the same
+        if state.current_buttonset_id == self.current_buttonset_id {
+            let hold = state.get_current_buttonset().shift.as_ref().map(|shift| shift.hold.clone());
+            state.shifted = self.shifted && hold.is_some_and(|hold| state.is_held(&hold));
+            state.shifted_presses = self.shifted_presses.clone();
+        }
+        Ok(state)
+    }
+
     pub fn get_current_profile(&self) -> &model::ProfileModel {
         self.model.profiles.get(&self.current_profile_id).unwrap()
     }
 
-    pub fn get_current_buttonset(&self) -> &actions::ButtonSetCallback<actions::ButtonCallback<Vec<actions::Action>>,Vec<actions::Action>> {
+    pub fn get_current_buttonset(&self) -> &model::ButtonSetModel {
         self.get_current_profile().buttonsets.get(&self.current_buttonset_id).unwrap()
     }
 
-    pub fn get_current_wheel(&self) -> &actions::WheelSetCallback<Vec<actions::Action>> {
+    pub fn get_current_wheel(&self) -> &actions::WheelSetCallback<actions::Callback> {
         self.get_current_profile().wheels.get(&self.current_wheel_id).unwrap()
     }
 
+    /// The shift layer of the buttonset, while it is on.
+    pub fn get_current_shift(&self) -> Option<&model::ShiftModel> {
+        self.get_current_buttonset().shift.as_ref().filter(|_| self.shifted)
+    }
+
+    /// The buttons of the buttonset, with the shift layer over the ones `in_layer` says.
+    fn layered_buttons(&self, in_layer: impl Fn(&actions::WhichButton) -> bool) -> actions::ButtonSet<&Button> {
+        let buttonset = &self.get_current_buttonset().buttonset;
+        let shift = |which: &actions::WhichButton| self.get_current_buttonset().shift.as_ref().filter(|_| in_layer(which));
+        actions::ButtonSet {
+            button0: layered(shift(&actions::WhichButton::Button0), actions::WhichButton::Button0, &buttonset.button0, |shift| &shift.buttonset.button0),
+            button1: layered(shift(&actions::WhichButton::Button1), actions::WhichButton::Button1, &buttonset.button1, |shift| &shift.buttonset.button1),
+            button2: layered(shift(&actions::WhichButton::Button2), actions::WhichButton::Button2, &buttonset.button2, |shift| &shift.buttonset.button2),
+            button3: layered(shift(&actions::WhichButton::Button3), actions::WhichButton::Button3, &buttonset.button3, |shift| &shift.buttonset.button3),
+            button4: layered(shift(&actions::WhichButton::Button4), actions::WhichButton::Button4, &buttonset.button4, |shift| &shift.buttonset.button4),
+            button5: layered(shift(&actions::WhichButton::Button5), actions::WhichButton::Button5, &buttonset.button5, |shift| &shift.buttonset.button5),
+            button6: layered(shift(&actions::WhichButton::Button6), actions::WhichButton::Button6, &buttonset.button6, |shift| &shift.buttonset.button6),
+            button7: layered(shift(&actions::WhichButton::Button7), actions::WhichButton::Button7, &buttonset.button7, |shift| &shift.buttonset.button7),
+        }
+    }
+
+    /// What the buttons of the buttonset show now, with the shift layer over them while it is on.
+    pub fn get_active_buttons(&self) -> actions::ButtonSet<&Button> {
+        self.layered_buttons(|_| self.shifted)
+    }
+
+    /// Where the events of the buttons go: each to the layer its last press went to.
+    pub fn get_pressed_buttons(&self) -> actions::ButtonSet<&Button> {
+        self.layered_buttons(|which| self.shifted_presses.contains(which))
+    }
+
+    /// What the wheel does now, the one of the shift layer while it is on.
+    pub fn get_active_wheel(&self) -> &actions::WheelSetCallback<actions::Callback> {
+        self.get_current_shift().and_then(|shift| shift.wheel.as_ref()).unwrap_or_else(|| self.get_current_wheel())
+    }
+
+    /// What the wheel button shows now.  Holding the shift it stays what it was.
+    pub fn get_active_wheel_button(&self) -> &Button {
+        let wheel = match self.get_current_shift() {
+            Some(shift) if shift.hold == actions::WhichButton::WheelButton => self.get_current_wheel(),
+            _ => self.get_active_wheel(),
+        };
+        &wheel.wheel.button
+    }
+
+    /// Where the events of the wheel button go, like `get_pressed_buttons`.
+    pub fn get_pressed_wheel_button(&self) -> &Button {
+        let layer = self.get_current_buttonset().shift.as_ref()
+            .filter(|shift| shift.hold != actions::WhichButton::WheelButton && self.shifted_presses.contains(&actions::WhichButton::WheelButton))
+            .and_then(|shift| shift.wheel.as_ref());
+        &layer.unwrap_or_else(|| self.get_current_wheel()).wheel.button
+    }
+
+    /// Send the events of `which` to the shift layer from now on if `events`
+    /// has a press while it is on, or back to the buttonset if off.
+    pub fn route_presses(&mut self, which: actions::WhichButton, events: &[events::ButtonEvent]) {
+        if !events.iter().any(|event| matches!(event, events::ButtonEvent::OnPress)) {
+            return;
+        }
+        self.shifted_presses.retain(|pressed| *pressed != which);
+        if self.shifted {
+            self.shifted_presses.push(which);
+        }
+    }
+
+    /// Whether `button` is down right now.
+    pub fn is_held(&self, button: &actions::WhichButton) -> bool {
+        let machine = match button {
+            actions::WhichButton::Button0 => &self.buttonset_state.button0,
+            actions::WhichButton::Button1 => &self.buttonset_state.button1,
+            actions::WhichButton::Button2 => &self.buttonset_state.button2,
+            actions::WhichButton::Button3 => &self.buttonset_state.button3,
+            actions::WhichButton::Button4 => &self.buttonset_state.button4,
+            actions::WhichButton::Button5 => &self.buttonset_state.button5,
+            actions::WhichButton::Button6 => &self.buttonset_state.button6,
+            actions::WhichButton::Button7 => &self.buttonset_state.button7,
+            actions::WhichButton::WheelButton => &self.wheel_state.wheel_button,
+            actions::WhichButton::ButtonExtra => &self.profilebutton_state.button,
+            actions::WhichButton::ThisButton => return false,
+        };
+        machine.is_pressed()
+    }
+
+    /// The gesture timings of the buttons in the current buttonset.
+    pub fn buttonset_timings(&self) -> actions::ButtonSet<events::Timings> {
+        let buttonset = self.get_pressed_buttons();
+        let global = &self.model.timings;
+        actions::ButtonSet {
+            button0: button_timings(buttonset.button0, global),
+            button1: button_timings(buttonset.button1, global),
+            button2: button_timings(buttonset.button2, global),
+            button3: button_timings(buttonset.button3, global),
+            button4: button_timings(buttonset.button4, global),
+            button5: button_timings(buttonset.button5, global),
+            button6: button_timings(buttonset.button6, global),
+            button7: button_timings(buttonset.button7, global),
+        }
+    }
+
+    /// The gesture timings of the current wheel and its button.
+    pub fn wheel_timings(&self) -> events::Timings {
+        events::Timings {
+            hold_repeat: !self.get_pressed_wheel_button().on_hold_repeat.actions.is_empty(),
+            ..self.get_active_wheel().wheel.button.timings.or(&self.model.timings)
+        }
+    }
+
+    /// The gesture timings of the profile button.
+    pub fn profilebutton_timings(&self) -> events::Timings {
+        button_timings(&self.get_current_profile().button, &self.model.timings)
+    }
+
+    /// When a button or the wheel next has something happen without moving:
+    /// a long press, a hold repeat, the end of a click or of a turn.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let profilebutton = self.profilebutton_state.button.deadline(&self.profilebutton_timings());
+        [
+            self.buttonset_state.deadline(&self.buttonset_timings()),
+            self.wheel_state.deadline(&self.wheel_timings()),
+            profilebutton,
+            self.chords.deadline(),
+        ].into_iter().flatten().min()
+    }
+
+    pub fn status(&self) -> Status {
+        let profile = self.get_current_profile();
+        Status {
+            profile: Position {
+                id: self.current_profile_id.clone(),
+                index: self.current_profile_index,
+                last_id: self.last_profile_id.clone(),
+                last_index: self.last_profile_index,
+                available: self.model.profiles.keys().cloned().collect(),
+            },
+            buttonset: Position {
+                id: self.current_buttonset_id.clone(),
+                index: self.current_buttonset_index,
+                last_id: self.last_buttonset_id.clone(),
+                last_index: self.last_buttonset_index,
+                available: profile.buttonsets.keys().cloned().collect(),
+            },
+            wheel: Position {
+                id: self.current_wheel_id.clone(),
+                index: self.current_wheel_index,
+                last_id: self.last_wheel_id.clone(),
+                last_index: self.last_wheel_index,
+                available: profile.wheels.keys().cloned().collect(),
+            },
+            variables: self.variables.clone(),
+        }
+    }
+
+    /// `text` with every `{{name}}` replaced by the value of the variable,
+    /// or nothing when it is not set.
+    pub fn interpolate(&self, text: &str) -> String {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else { break };
+            result.push_str(&rest[..start]);
+            let name = rest[start + 2..start + 2 + len].trim();
+            result.push_str(&self.variables.get(name).map(variable_text).unwrap_or_default());
+            rest = &rest[start + 2 + len + 2..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: &toml::Value) {
+        let value = match value {
+            toml::Value::String(text) => toml::Value::String(self.interpolate(text)),
+            other => other.clone(),
+        };
+        self.variables.insert(name.to_string(), value);
+    }
+
+    pub fn increment_variable(&mut self, name: &str, by: i64) -> anyhow::Result<()> {
+        let value = match self.variables.get(name) {
+            None => 0,
+            Some(toml::Value::Integer(value)) => *value,
+            Some(other) => anyhow::bail!("Variable {} is {}, not a number", name, other),
+        };
+        self.variables.insert(name.to_string(), toml::Value::Integer(value.saturating_add(by)));
+        Ok(())
+    }
+
+    pub fn toggle_variable(&mut self, name: &str) -> anyhow::Result<()> {
+        let value = match self.variables.get(name) {
+            None => false,
+            Some(toml::Value::Boolean(value)) => *value,
+            Some(other) => anyhow::bail!("Variable {} is {}, not a boolean", name, other),
+        };
+        self.variables.insert(name.to_string(), toml::Value::Boolean(!value));
+        Ok(())
+    }
+
+    /// Whether the variable is set to true, a number other than 0 or a
+    /// string that is not empty.
+    pub fn is_set(&self, name: &str) -> bool {
+        match self.variables.get(name) {
+            Some(toml::Value::Boolean(value)) => *value,
+            Some(toml::Value::Integer(value)) => *value != 0,
+            Some(toml::Value::String(value)) => !value.is_empty(),
+            Some(_) => true,
+            None => false,
+        }
+    }
+
     pub fn process_goto(&self, goto: actions::GoTo) -> Result<Self, anyhow::Error> {
         let mut state = self.clone();
 
@@ -191,6 +500,11 @@ impl State {
                 state.current_wheel_index = state.last_wheel_index.unwrap_or(current_wheel_index);
             },
         }
+        // The shift layer belongs to the buttonset it was turned on in
+        if state.current_profile_id != current_profile_id || state.current_buttonset_id != current_buttonset_id {
+            state.shifted = false;
+            state.shifted_presses.clear();
+        }
         state.last_profile_id = Some(current_profile_id);
         state.last_profile_index = Some(current_profile_index);
         state.last_buttonset_id = Some(current_buttonset_id);
@@ -201,3 +515,187 @@ impl State {
         Ok(state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [buttonsets.main]
+        [wheels.main]
+        [profiles.main.buttonsets]
+        main = "main"
+        [profiles.main.wheels]
+        main = "main"
+
+        [variables]
+        name = "world"
+        count = 1
+    "#;
+
+    fn state(config: &str) -> State {
+        State::new(model::from_config(toml::from_str(config).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn interpolates_variables() {
+        let mut state = state(CONFIG);
+        state.set_variable("muted", &toml::Value::Boolean(true));
+        assert_eq!(state.interpolate("hello {{name}}, {{ count }} {{muted}}"), "hello world, 1 true");
+        assert_eq!(state.interpolate("[{{missing}}]"), "[]");
+        assert_eq!(state.interpolate("{{name}} and {{name"), "world and {{name");
+
+        // Strings are interpolated as they are set
+        state.set_variable("greeting", &toml::Value::String("hello {{name}}".to_string()));
+        state.set_variable("name", &toml::Value::String("there".to_string()));
+        assert_eq!(state.interpolate("{{greeting}}"), "hello world");
+    }
+
+    #[test]
+    fn increments_and_toggles_variables() {
+        let mut state = state(CONFIG);
+        state.increment_variable("count", 2).unwrap();
+        state.increment_variable("unset", -1).unwrap();
+        assert_eq!(state.variables["count"], toml::Value::Integer(3));
+        assert_eq!(state.variables["unset"], toml::Value::Integer(-1));
+        state.set_variable("big", &toml::Value::Integer(i64::MAX - 1));
+        state.increment_variable("big", 5).unwrap();
+        assert_eq!(state.variables["big"], toml::Value::Integer(i64::MAX));
+        state.increment_variable("unset", i64::MIN).unwrap();
+        assert_eq!(state.variables["unset"], toml::Value::Integer(i64::MIN));
+        assert_eq!(state.increment_variable("name", 1).unwrap_err().to_string(), r#"Variable name is "world", not a number"#);
+
+        state.toggle_variable("muted").unwrap();
+        assert!(state.is_set("muted"));
+        state.toggle_variable("muted").unwrap();
+        assert!(!state.is_set("muted"));
+        assert_eq!(state.toggle_variable("count").unwrap_err().to_string(), "Variable count is 3, not a boolean");
+    }
+
+    #[test]
+    fn tells_which_variables_are_set() {
+        let mut state = state(CONFIG);
+        assert!(state.is_set("name") && state.is_set("count"));
+        state.set_variable("name", &toml::Value::String(String::new()));
+        state.set_variable("count", &toml::Value::Integer(0));
+        assert!(!state.is_set("name") && !state.is_set("count") && !state.is_set("missing"));
+    }
+
+    #[test]
+    fn keeps_variables_across_reloads() {
+        let mut state = state(CONFIG);
+        state.set_variable("count", &toml::Value::Integer(5));
+        state.set_variable("runtime", &toml::Value::Boolean(true));
+        let reloaded = state.reload(model::from_config(toml::from_str(&CONFIG.replace("count = 1", "count = 0\nadded = 2")).unwrap()).unwrap()).unwrap();
+        let variables: Vec<_> = reloaded.variables.iter().map(|(name, value)| format!("{name}={value}")).collect();
+        assert_eq!(variables, ["name=\"world\"", "count=5", "added=2", "runtime=true"]);
+    }
+
+    const SHIFT: &str = r#"
+        [buttons.base]
+        on_press = [{ Debug = "base" }]
+        [buttons.layer]
+        on_press = [{ Debug = "layer" }]
+        [buttons.shift]
+
+        [buttonsets.main]
+        button0 = "base"
+        button7 = "shift"
+        shift = { hold = "Button7", button0 = "layer" }
+        [buttonsets.other]
+        button0 = "base"
+
+        [wheels.main]
+        [wheels.other]
+
+        [profiles.main.buttonsets]
+        main = "main"
+        other = "other"
+        [profiles.main.wheels]
+        main = "main"
+        other = "other"
+        [profiles.second.buttonsets]
+        main = "main"
+        [profiles.second.wheels]
+        main = "main"
+    "#;
+
+    /// What `button` writes out when pressed.
+    fn debug_text(button: &Button) -> &str {
+        match button.on_press.actions.first() {
+            Some(actions::Action::NonEnigo(actions::NonEnigoAction::Debug(text))) => text,
+            _ => "",
+        }
+    }
+
+    fn press() -> Vec<events::ButtonEvent> {
+        vec![events::ButtonEvent::OnPress, events::ButtonEvent::OnClickPress(1)]
+    }
+
+    fn release() -> Vec<events::ButtonEvent> {
+        vec![events::ButtonEvent::OnRelease, events::ButtonEvent::OnClickRelease(1)]
+    }
+
+    /// `state` with the shift held down and on.
+    fn shifted(mut state: State) -> State {
+        state.buttonset_state.button7 = events::ButtonStateMachine::Pressed(Instant::now(), 0);
+        state.shifted = true;
+        state
+    }
+
+    #[test]
+    fn a_release_goes_to_the_layer_of_the_press() {
+        let mut state = shifted(state(SHIFT));
+        state.route_presses(actions::WhichButton::Button0, &press());
+        state.shifted = false;
+        // The shift is let go with the button still down
+        assert_eq!(debug_text(state.get_active_buttons().button0), "base");
+        assert_eq!(debug_text(state.get_pressed_buttons().button0), "layer");
+        state.route_presses(actions::WhichButton::Button0, &release());
+        assert_eq!(debug_text(state.get_pressed_buttons().button0), "layer");
+
+        // The next press is the buttonset's again, and the other way round
+        state.route_presses(actions::WhichButton::Button0, &press());
+        assert_eq!(debug_text(state.get_pressed_buttons().button0), "base");
+        state.shifted = true;
+        state.route_presses(actions::WhichButton::Button0, &release());
+        assert_eq!(debug_text(state.get_pressed_buttons().button0), "base");
+        assert_eq!(debug_text(state.get_active_buttons().button0), "layer");
+    }
+
+    #[test]
+    fn a_held_shift_stays_on_across_reloads() {
+        let mut state = shifted(state(SHIFT));
+        state.route_presses(actions::WhichButton::Button0, &press());
+        let model = model::from_config(toml::from_str(SHIFT).unwrap()).unwrap();
+
+        let reloaded = state.reload(model.clone()).unwrap();
+        assert!(reloaded.shifted);
+        assert_eq!(reloaded.shifted_presses, [actions::WhichButton::Button0]);
+        assert_eq!(debug_text(reloaded.get_active_buttons().button0), "layer");
+
+        // Not once the shift is let go
+        state.buttonset_state.button7 = events::ButtonStateMachine::Idle;
+        assert!(!state.reload(model).unwrap().shifted);
+    }
+
+    #[test]
+    fn the_shift_is_left_with_its_buttonset() {
+        let mut state = shifted(state(SHIFT));
+        state.route_presses(actions::WhichButton::Button0, &press());
+        // Exiting the state while shifted leaves the layer's buttons
+        assert_eq!(debug_text(state.get_active_buttons().button0), "layer");
+
+        let goto = |buttonset: actions::ChangeRef, wheel: actions::ChangeRef| actions::GoTo::Switch(actions::ChangeRef::This, buttonset, wheel);
+        let moved = state.process_goto(goto(actions::ChangeRef::This, actions::ChangeRef::Next)).unwrap();
+        assert!(moved.shifted);
+        let moved = state.process_goto(goto(actions::ChangeRef::Next, actions::ChangeRef::This)).unwrap();
+        assert!(!moved.shifted);
+        assert!(moved.shifted_presses.is_empty());
+        // Even to a buttonset of the same name in another profile
+        let moved = state.process_goto(actions::GoTo::Switch(actions::ChangeRef::Name("second".to_string()), actions::ChangeRef::This, actions::ChangeRef::This)).unwrap();
+        assert_eq!(moved.current_buttonset_id, "main");
+        assert!(!moved.shifted);
+        assert!(moved.shifted_presses.is_empty());
+    }
+}
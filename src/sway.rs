@@ -0,0 +1,199 @@
+//! The focused window on sway, from its IPC socket in `$SWAYSOCK`.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+use serde_json::Value;
+
+use crate::focus::{Window, WindowSource};
+
+const MAGIC: &[u8] = b"i3-ipc";
+
+// Message types
+const SUBSCRIBE: u32 = 2;
+const GET_TREE: u32 = 4;
+const WINDOW_EVENT: u32 = 0x8000_0003;
+
+/// The focused window of the sway session in `$SWAYSOCK`.
+pub struct SwayWindows {
+    stream: UnixStream,
+    /// Where it was when connecting, answered by the first `next`
+    initial: Option<Window>,
+    last: Option<Window>,
+}
+
+/// What sway says about a container, as a `Window`.  Wayland windows only
+/// have an app id, which is taken as the class.
+fn window(container: &Value) -> Window {
+    let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+    let properties = &container["window_properties"];
+    let class = match container["app_id"].as_str() {
+        Some(app_id) => app_id.to_string(),
+        None => text(&properties["class"]),
+    };
+    Window { instance: text(&properties["instance"]), class, title: text(&container["name"]) }
+}
+
+/// The focused window in a tree from `GET_TREE`.
+fn focused(node: &Value) -> Option<Window> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(window(node));
+    }
+    ["nodes", "floating_nodes"].iter()
+        .filter_map(|key| node[*key].as_array())
+        .flatten()
+        .find_map(focused)
+}
+
+impl SwayWindows {
+    pub fn connect() -> anyhow::Result<Self> {
+        let path = std::env::var("SWAYSOCK").map_err(|_| anyhow::anyhow!("SWAYSOCK is not set"))?;
+        let stream = UnixStream::connect(&path).map_err(|e| anyhow::anyhow!("Cannot connect to sway at {}: {}", path, e))?;
+        let mut windows = SwayWindows { stream, initial: None, last: None };
+
+        windows.send(GET_TREE, "")?;
+        let (_, tree) = windows.receive()?;
+        // A workspace is focused when it has no window
+        windows.initial = Some(focused(&tree).unwrap_or_default());
+
+        windows.send(SUBSCRIBE, r#"["window"]"#)?;
+        let (_, reply) = windows.receive()?;
+        if reply["success"].as_bool() != Some(true) {
+            anyhow::bail!("sway refused the subscription: {}", reply);
+        }
+        Ok(windows)
+    }
+
+    fn send(&mut self, kind: u32, payload: &str) -> anyhow::Result<()> {
+        let mut message = MAGIC.to_vec();
+        message.extend((payload.len() as u32).to_ne_bytes());
+        message.extend(kind.to_ne_bytes());
+        message.extend(payload.as_bytes());
+        self.stream.write_all(&message)?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> anyhow::Result<(u32, Value)> {
+        let mut header = [0u8; 14];
+        self.stream.read_exact(&mut header)?;
+        if &header[..6] != MAGIC {
+            anyhow::bail!("Not a sway IPC message");
+        }
+        let len = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+        let kind = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+        let mut payload = vec![0u8; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        Ok((kind, serde_json::from_slice(&payload)?))
+    }
+}
+
+impl WindowSource for SwayWindows {
+    fn next(&mut self) -> anyhow::Result<Window> {
+        if let Some(window) = self.initial.take() {
+            self.last = Some(window.clone());
+            return Ok(window);
+        }
+        loop {
+            let (kind, event) = self.receive()?;
+            if kind != WINDOW_EVENT || !matches!(event["change"].as_str(), Some("focus" | "title")) {
+                continue;
+            }
+            let container = &event["container"];
+            // A title changing somewhere in the background
+            if container["focused"].as_bool() != Some(true) {
+                continue;
+            }
+            let window = window(container);
+            if self.last.as_ref() != Some(&window) {
+                self.last = Some(window.clone());
+                return Ok(window);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use serde_json::json;
+
+    fn message(kind: u32, payload: &Value) -> Vec<u8> {
+        let payload = payload.to_string();
+        let mut message = MAGIC.to_vec();
+        message.extend((payload.len() as u32).to_ne_bytes());
+        message.extend(kind.to_ne_bytes());
+        message.extend(payload.as_bytes());
+        message
+    }
+
+    /// The type of the next message from the client, after checking its payload.
+    fn request(stream: &mut UnixStream, payload: &str) -> u32 {
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(&header[..6], MAGIC);
+        let len = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+        let mut body = vec![0u8; len as usize];
+        stream.read_exact(&mut body).unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), payload);
+        u32::from_ne_bytes([header[10], header[11], header[12], header[13]])
+    }
+
+    fn event(change: &str, container: Value) -> Vec<u8> {
+        message(WINDOW_EVENT, &json!({ "change": change, "container": container }))
+    }
+
+    fn window(instance: &str, class: &str, title: &str) -> Window {
+        Window { instance: instance.to_string(), class: class.to_string(), title: title.to_string() }
+    }
+
+    #[test]
+    fn follows_the_focused_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sway-ipc.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        std::env::set_var("SWAYSOCK", &path);
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(request(&mut stream, ""), GET_TREE);
+            let tree = json!({
+                "type": "root",
+                "nodes": [{
+                    "type": "workspace",
+                    "nodes": [{ "name": "zsh", "app_id": "foot", "focused": false }],
+                    "floating_nodes": [{
+                        "name": "Mozilla Firefox",
+                        "app_id": null,
+                        "focused": true,
+                        "window_properties": { "class": "firefox", "instance": "Navigator" },
+                    }],
+                }],
+            });
+            stream.write_all(&message(GET_TREE, &tree)).unwrap();
+            assert_eq!(request(&mut stream, r#"["window"]"#), SUBSCRIBE);
+            stream.write_all(&message(SUBSCRIBE, &json!({ "success": true }))).unwrap();
+
+            let events = [
+                event("focus", json!({ "name": "zsh", "app_id": "foot", "focused": true })),
+                // Not about the focus, in the background, or nothing new
+                event("new", json!({ "name": "htop", "app_id": "foot", "focused": true })),
+                event("title", json!({ "name": "make", "app_id": "foot", "focused": false })),
+                message(0x8000_0000, &json!({ "change": "focus", "current": {} })),
+                event("title", json!({ "name": "zsh", "app_id": "foot", "focused": true })),
+                event("title", json!({ "name": "nvim", "app_id": "foot", "focused": true })),
+            ];
+            for event in events {
+                stream.write_all(&event).unwrap();
+            }
+        });
+
+        let mut windows = SwayWindows::connect().unwrap();
+        assert_eq!(windows.next().unwrap(), window("Navigator", "firefox", "Mozilla Firefox"));
+        assert_eq!(windows.next().unwrap(), window("", "foot", "zsh"));
+        assert_eq!(windows.next().unwrap(), window("", "foot", "nvim"));
+        server.join().unwrap();
+        // Sway going away ends the watch
+        assert!(windows.next().is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! The focused window on X11, asked straight over the display socket.  Only
+//! the few requests needed for `_NET_ACTIVE_WINDOW`, `WM_CLASS` and
+//! `_NET_WM_NAME` are spoken, so no X library is needed.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use crate::focus::{Window, WindowSource};
+
+// Predefined atoms
+const WM_NAME: u32 = 39;
+const WM_CLASS: u32 = 67;
+
+// Opcodes
+const CHANGE_WINDOW_ATTRIBUTES: u8 = 2;
+const INTERN_ATOM: u8 = 16;
+const GET_PROPERTY: u8 = 20;
+
+const PROPERTY_NOTIFY: u8 = 28;
+const CW_EVENT_MASK: u32 = 0x800;
+const PROPERTY_CHANGE_MASK: u32 = 0x0040_0000;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn pad(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// The display number in `$DISPLAY`, for local displays only.
+fn display_number(display: &str) -> anyhow::Result<String> {
+    let (host, rest) = display.rsplit_once(':').ok_or_else(|| anyhow::anyhow!("Bad DISPLAY {}", display))?;
+    if !host.is_empty() && host != "unix" {
+        anyhow::bail!("Only local displays are supported, not {}", display);
+    }
+    Ok(rest.split('.').next().unwrap_or(rest).to_string())
+}
+
+/// The `MIT-MAGIC-COOKIE-1` for `display` in the Xauthority file, if any.
+fn cookie(display: &str) -> Option<Vec<u8>> {
+    let path = std::env::var_os("XAUTHORITY").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".Xauthority")))?;
+    let data = std::fs::read(path).ok()?;
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    find_cookie(&data, display, hostname.trim())
+}
+
+/// The `MIT-MAGIC-COOKIE-1` for `display` on `hostname` in the contents of
+/// an Xauthority file.
+fn find_cookie(data: &[u8], display: &str, hostname: &str) -> Option<Vec<u8>> {
+    // Each entry: family, then address, display number, name and data, all length prefixed
+    let mut offset = 0;
+    let field = |offset: &mut usize| -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*data.get(*offset)?, *data.get(*offset + 1)?]) as usize;
+        let value = data.get(*offset + 2..*offset + 2 + len)?.to_vec();
+        *offset += 2 + len;
+        Some(value)
+    };
+    let mut fallback = None;
+    while offset + 2 <= data.len() {
+        let family = u16::from_be_bytes([data[offset], data[offset + 1]]);
+        offset += 2;
+        let address = field(&mut offset)?;
+        let number = field(&mut offset)?;
+        let name = field(&mut offset)?;
+        let value = field(&mut offset)?;
+        if name != b"MIT-MAGIC-COOKIE-1" || (!number.is_empty() && number != display.as_bytes()) {
+            continue;
+        }
+        // Local (256) for this host, or Wild (65535) for any
+        if family == 65535 || (family == 256 && address == hostname.as_bytes()) {
+            return Some(value);
+        }
+        fallback.get_or_insert(value);
+    }
+    fallback
+}
+
+/// The focused window of the X display in `$DISPLAY`.
+pub struct X11Windows {
+    stream: UnixStream,
+    sequence: u16,
+    root: u32,
+    net_active_window: u32,
+    net_wm_name: u32,
+    /// The window whose property changes are listened to
+    active: u32,
+    events: VecDeque<Vec<u8>>,
+    changed: bool,
+    last: Option<Window>,
+}
+
+impl X11Windows {
+    pub fn connect() -> anyhow::Result<Self> {
+        let display = std::env::var("DISPLAY").map_err(|_| anyhow::anyhow!("DISPLAY is not set"))?;
+        let number = display_number(&display)?;
+        let stream = UnixStream::connect(format!("/tmp/.X11-unix/X{}", number))
+            .map_err(|e| anyhow::anyhow!("Cannot connect to display {}: {}", display, e))?;
+        Self::handshake(stream, &display, cookie(&number))
+    }
+
+    /// Set up the connection on `stream` and start listening for focus changes.
+    fn handshake(mut stream: UnixStream, display: &str, cookie: Option<Vec<u8>>) -> anyhow::Result<Self> {
+        let (auth_name, auth_data): (&[u8], Vec<u8>) = match cookie {
+            Some(cookie) => (b"MIT-MAGIC-COOKIE-1", cookie),
+            None => (b"", Vec::new()),
+        };
+        let mut setup = vec![b'l', 0];
+        setup.extend(11u16.to_le_bytes());
+        setup.extend(0u16.to_le_bytes());
+        setup.extend((auth_name.len() as u16).to_le_bytes());
+        setup.extend((auth_data.len() as u16).to_le_bytes());
+        setup.extend([0, 0]);
+        setup.extend(auth_name);
+        setup.extend(vec![0; pad(auth_name.len())]);
+        setup.extend(&auth_data);
+        setup.extend(vec![0; pad(auth_data.len())]);
+        stream.write_all(&setup)?;
+
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header)?;
+        let mut reply = vec![0u8; u16_at(&header, 6) as usize * 4];
+        stream.read_exact(&mut reply)?;
+        if header[0] != 1 {
+            let reason = String::from_utf8_lossy(&reply[..(header[1] as usize).min(reply.len())]).into_owned();
+            anyhow::bail!("Display {} refused the connection: {}", display, reason);
+        }
+        // The first screen comes after the vendor name and the pixmap formats
+        let vendor_len = u16_at(&reply, 16) as usize;
+        let formats = reply[21] as usize;
+        let root = u32_at(&reply, 32 + vendor_len + pad(vendor_len) + 8 * formats);
+
+        let mut windows = X11Windows {
+            stream,
+            sequence: 0,
+            root,
+            net_active_window: 0,
+            net_wm_name: 0,
+            active: 0,
+            events: VecDeque::new(),
+            changed: true,
+            last: None,
+        };
+        windows.net_active_window = windows.intern_atom("_NET_ACTIVE_WINDOW")?;
+        windows.net_wm_name = windows.intern_atom("_NET_WM_NAME")?;
+        windows.listen(root, PROPERTY_CHANGE_MASK)?;
+        Ok(windows)
+    }
+
+    fn send(&mut self, mut request: Vec<u8>) -> anyhow::Result<u16> {
+        request.extend(vec![0; pad(request.len())]);
+        let len = (request.len() / 4) as u16;
+        request[2..4].copy_from_slice(&len.to_le_bytes());
+        self.stream.write_all(&request)?;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(self.sequence)
+    }
+
+    /// The next reply, error or event.
+    fn read_packet(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut packet = vec![0u8; 32];
+        self.stream.read_exact(&mut packet)?;
+        if packet[0] == 1 {
+            let mut extra = vec![0u8; u32_at(&packet, 4) as usize * 4];
+            self.stream.read_exact(&mut extra)?;
+            packet.extend(extra);
+        }
+        Ok(packet)
+    }
+
+    /// The reply to request `sequence`, or `None` when it failed.  Events
+    /// read meanwhile are kept for later.
+    fn reply(&mut self, sequence: u16) -> anyhow::Result<Option<Vec<u8>>> {
+        loop {
+            let packet = self.read_packet()?;
+            match packet[0] {
+                0 | 1 if u16_at(&packet, 2) != sequence => {},
+                0 => return Ok(None),
+                1 => return Ok(Some(packet)),
+                _ => self.events.push_back(packet),
+            }
+        }
+    }
+
+    fn intern_atom(&mut self, name: &str) -> anyhow::Result<u32> {
+        let mut request = vec![INTERN_ATOM, 0, 0, 0];
+        request.extend((name.len() as u16).to_le_bytes());
+        request.extend([0, 0]);
+        request.extend(name.as_bytes());
+        let sequence = self.send(request)?;
+        let reply = self.reply(sequence)?.ok_or_else(|| anyhow::anyhow!("Cannot intern atom {}", name))?;
+        Ok(u32_at(&reply, 8))
+    }
+
+    /// Set which events of `window` this connection gets.  A window that is
+    /// already gone only makes an error nobody waits for.
+    fn listen(&mut self, window: u32, mask: u32) -> anyhow::Result<()> {
+        let mut request = vec![CHANGE_WINDOW_ATTRIBUTES, 0, 0, 0];
+        request.extend(window.to_le_bytes());
+        request.extend(CW_EVENT_MASK.to_le_bytes());
+        request.extend(mask.to_le_bytes());
+        self.send(request)?;
+        Ok(())
+    }
+
+    fn property(&mut self, window: u32, property: u32) -> anyhow::Result<Vec<u8>> {
+        let mut request = vec![GET_PROPERTY, 0, 0, 0];
+        request.extend(window.to_le_bytes());
+        request.extend(property.to_le_bytes());
+        // Any type, from the start, up to 4 KiB
+        request.extend(0u32.to_le_bytes());
+        request.extend(0u32.to_le_bytes());
+        request.extend(1024u32.to_le_bytes());
+        let sequence = self.send(request)?;
+        let Some(reply) = self.reply(sequence)? else {
+            return Ok(Vec::new());
+        };
+        let len = u32_at(&reply, 16) as usize * (reply[1] as usize / 8);
+        Ok(reply[32..(32 + len).min(reply.len())].to_vec())
+    }
+
+    fn active_window(&mut self) -> anyhow::Result<Window> {
+        let value = self.property(self.root, self.net_active_window)?;
+        let active = if value.len() >= 4 { u32_at(&value, 0) } else { 0 };
+        if active != self.active {
+            if self.active != 0 {
+                self.listen(self.active, 0)?;
+            }
+            if active != 0 {
+                self.listen(active, PROPERTY_CHANGE_MASK)?;
+            }
+            self.active = active;
+        }
+        if active == 0 {
+            return Ok(Window::default());
+        }
+
+        let class = self.property(active, WM_CLASS)?;
+        let mut parts = class.split(|byte| *byte == 0).map(|part| String::from_utf8_lossy(part).into_owned());
+        let instance = parts.next().unwrap_or_default();
+        let class = parts.next().unwrap_or_default();
+        let mut title = self.property(active, self.net_wm_name)?;
+        if title.is_empty() {
+            title = self.property(active, WM_NAME)?;
+        }
+        Ok(Window { instance, class, title: String::from_utf8_lossy(&title).into_owned() })
+    }
+}
+
+impl WindowSource for X11Windows {
+    fn next(&mut self) -> anyhow::Result<Window> {
+        loop {
+            if self.changed {
+                self.changed = false;
+                let window = self.active_window()?;
+                if self.last.as_ref() != Some(&window) {
+                    self.last = Some(window.clone());
+                    return Ok(window);
+                }
+            }
+            let event = match self.events.pop_front() {
+                Some(event) => event,
+                None => self.read_packet()?,
+            };
+            // The top bit only tells the event was sent by another client
+            if event[0] & 0x7f != PROPERTY_NOTIFY {
+                continue;
+            }
+            let (window, atom) = (u32_at(&event, 4), u32_at(&event, 8));
+            self.changed = (window == self.root && atom == self.net_active_window)
+                || (window == self.active && (atom == self.net_wm_name || atom == WM_NAME || atom == WM_CLASS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: u32 = 0x100;
+    const ACTIVE: u32 = 0x200;
+    const NET_ACTIVE_WINDOW: u32 = 300;
+    const NET_WM_NAME: u32 = 301;
+
+    fn entry(family: u16, address: &[u8], number: &[u8], name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut entry = family.to_be_bytes().to_vec();
+        for field in [address, number, name, data] {
+            entry.extend((field.len() as u16).to_be_bytes());
+            entry.extend(field);
+        }
+        entry
+    }
+
+    #[test]
+    fn display_numbers() {
+        assert_eq!(display_number(":0").unwrap(), "0");
+        assert_eq!(display_number(":1.0").unwrap(), "1");
+        assert_eq!(display_number("unix:2").unwrap(), "2");
+        assert!(display_number("remote:0").is_err());
+        assert!(display_number("0").is_err());
+    }
+
+    #[test]
+    fn cookie_for_this_host_and_display() {
+        let mut data = entry(256, b"box", b"0", b"XDM-AUTHORIZATION-1", b"xdm");
+        data.extend(entry(256, b"box", b"1", b"MIT-MAGIC-COOKIE-1", b"other display"));
+        data.extend(entry(256, b"elsewhere", b"0", b"MIT-MAGIC-COOKIE-1", b"other host"));
+        data.extend(entry(256, b"box", b"0", b"MIT-MAGIC-COOKIE-1", b"this one"));
+        assert_eq!(find_cookie(&data, "0", "box").as_deref(), Some(&b"this one"[..]));
+        // Another host's cookie is better than none
+        assert_eq!(find_cookie(&data, "0", "laptop").as_deref(), Some(&b"other host"[..]));
+        assert_eq!(find_cookie(&data, "2", "box"), None);
+    }
+
+    #[test]
+    fn wildcard_cookie() {
+        let mut data = entry(0, b"\x7f\x00\x00\x01", b"0", b"MIT-MAGIC-COOKIE-1", b"tcp");
+        data.extend(entry(65535, b"", b"", b"MIT-MAGIC-COOKIE-1", b"any"));
+        assert_eq!(find_cookie(&data, "3", "box").as_deref(), Some(&b"any"[..]));
+        // A truncated file has nothing more to give
+        assert_eq!(find_cookie(&data[..data.len() - 1], "3", "box"), None);
+    }
+
+    /// A successful setup reply with a single screen whose root is `ROOT`.
+    fn setup_reply() -> Vec<u8> {
+        let vendor = b"Fake X";
+        let mut data = vec![0u8; 32];
+        data[16..18].copy_from_slice(&(vendor.len() as u16).to_le_bytes());
+        data[21] = 2;
+        data.extend(vendor);
+        data.extend(vec![0; pad(vendor.len())]);
+        data.extend([0; 16]);
+        data.extend(ROOT.to_le_bytes());
+        data.extend([0; 36]);
+        let mut reply = vec![1, 0];
+        reply.extend(11u16.to_le_bytes());
+        reply.extend(0u16.to_le_bytes());
+        reply.extend(((data.len() / 4) as u16).to_le_bytes());
+        reply.extend(data);
+        reply
+    }
+
+    fn intern_reply(sequence: u16, atom: u32) -> Vec<u8> {
+        let mut reply = vec![0u8; 32];
+        reply[0] = 1;
+        reply[2..4].copy_from_slice(&sequence.to_le_bytes());
+        reply[8..12].copy_from_slice(&atom.to_le_bytes());
+        reply
+    }
+
+    fn property_reply(sequence: u16, format: u8, value: &[u8]) -> Vec<u8> {
+        let mut reply = vec![0u8; 32];
+        reply[0] = 1;
+        reply[1] = format;
+        reply[2..4].copy_from_slice(&sequence.to_le_bytes());
+        reply[4..8].copy_from_slice((((value.len() + pad(value.len())) / 4) as u32).to_le_bytes().as_slice());
+        let items = if format == 0 { 0 } else { value.len() / (format as usize / 8) };
+        reply[16..20].copy_from_slice(&(items as u32).to_le_bytes());
+        reply.extend(value);
+        reply.extend(vec![0; pad(value.len())]);
+        reply
+    }
+
+    fn property_notify(window: u32, atom: u32) -> Vec<u8> {
+        let mut event = vec![0u8; 32];
+        event[0] = PROPERTY_NOTIFY;
+        event[4..8].copy_from_slice(&window.to_le_bytes());
+        event[8..12].copy_from_slice(&atom.to_le_bytes());
+        event
+    }
+
+    fn sent(mut event: Vec<u8>) -> Vec<u8> {
+        event[0] |= 0x80;
+        event
+    }
+
+    fn error(sequence: u16) -> Vec<u8> {
+        let mut error = vec![0u8; 32];
+        error[1] = 3;
+        error[2..4].copy_from_slice(&sequence.to_le_bytes());
+        error
+    }
+
+    fn window(instance: &str, class: &str, title: &str) -> Window {
+        Window { instance: instance.to_string(), class: class.to_string(), title: title.to_string() }
+    }
+
+    #[test]
+    fn follows_the_active_window() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        // Every answer is written up front, in the order the requests are made
+        let answers = [
+            setup_reply(),
+            intern_reply(1, NET_ACTIVE_WINDOW),
+            intern_reply(2, NET_WM_NAME),
+            // 3 listens on the root window
+            property_reply(4, 32, &ACTIVE.to_le_bytes()),
+            // 5 listens on the active window, which is already gone
+            error(5),
+            property_reply(6, 8, b"kitty\0kitty\0"),
+            property_reply(7, 8, b"zsh"),
+            // Unrelated changes are ignored, the title is not, even when sent
+            property_notify(ROOT, WM_NAME),
+            sent(property_notify(ACTIVE, NET_WM_NAME)),
+            property_reply(8, 32, &ACTIVE.to_le_bytes()),
+            property_reply(9, 8, b"kitty\0kitty\0"),
+            // Read while waiting for the title, and kept for later
+            property_notify(ROOT, NET_ACTIVE_WINDOW),
+            property_reply(10, 0, b""),
+            property_reply(11, 8, b"nvim"),
+            property_reply(12, 0, b""),
+            // 13 stops listening on the window that lost the focus
+        ];
+        for answer in answers {
+            server.write_all(&answer).unwrap();
+        }
+
+        let mut windows = X11Windows::handshake(client, ":0", Some(b"secret".to_vec())).unwrap();
+        assert_eq!(windows.root, ROOT);
+        assert_eq!(windows.next().unwrap(), window("kitty", "kitty", "zsh"));
+        assert_eq!(windows.next().unwrap(), window("kitty", "kitty", "nvim"));
+        assert_eq!(windows.next().unwrap(), Window::default());
+        assert_eq!(windows.active, 0);
+    }
+
+    #[test]
+    fn refused_connection() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let reason = b"No protocol specified\n";
+        let mut reply = vec![0, reason.len() as u8];
+        reply.extend(11u16.to_le_bytes());
+        reply.extend(0u16.to_le_bytes());
+        reply.extend((((reason.len() + pad(reason.len())) / 4) as u16).to_le_bytes());
+        reply.extend(reason);
+        reply.extend(vec![0; pad(reason.len())]);
+        server.write_all(&reply).unwrap();
+
+        let err = X11Windows::handshake(client, ":0", None).err().unwrap();
+        assert_eq!(err.to_string(), "Display :0 refused the connection: No protocol specified\n");
+    }
+}